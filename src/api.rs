@@ -0,0 +1,124 @@
+use std::path::{Path, PathBuf};
+use std::io::{Seek, Write};
+
+use types::*;
+use renderer;
+use tomlparser;
+use loudness;
+
+impl Settings {
+    /// Reads a render settings file with its includes. Fails if the file or its includes cannot be read or are invalid.
+    pub fn from_file<P: AsRef<Path>>(file: P) -> Result<Settings, Error> {
+        ensure!(file.as_ref().to_str().is_some(), "The settings file name must be UTF-8");
+        tomlparser::read_settings_file(file.as_ref())
+    }
+
+    /// Reads settings in the format of render settings files. Included files, the input_file and the output_file are
+    /// relative to `directory`.
+    pub fn from_toml_str<P: AsRef<Path>>(settings: &str, directory: P) -> Result<Settings, Error> {
+        tomlparser::read_settings_str(settings, Path::new("<settings>"), directory.as_ref())
+    }
+
+    /// Sets a dotted key like `synth.piano.gain` to a TOML value, like the --set option of the command line tool.
+    /// Bare words are taken as strings.
+    pub fn set(mut self, key: &str, value: &str) -> Result<Settings, Error> {
+        tomlparser::set_setting(&mut self, key, value)?;
+        Ok(self)
+    }
+}
+
+impl Render {
+    /// Starts a render of a render settings file. Fails if the file or its includes cannot be read or are invalid.
+    pub fn from_file<P: AsRef<Path>>(file: P) -> Result<Render, Error> {
+        Render::from_settings(&Settings::from_file(file)?)
+    }
+
+    /// Starts a render of settings in the format of render settings files. Included files and the input_file are
+    /// relative to `directory`. Fails if the settings or the included files are invalid.
+    pub fn from_toml_str<P: AsRef<Path>>(settings: &str, directory: P) -> Result<Render, Error> {
+        Render::from_settings(&Settings::from_toml_str(settings, directory)?)
+    }
+
+    /// Starts a render of settings, their variables are expanded now. Fails if the settings are invalid.
+    pub fn from_settings(settings: &Settings) -> Result<Render, Error> {
+        Ok(Render {
+            settings: tomlparser::render_settings_of(settings)?,
+            resource_directories: Vec::new(),
+            midi_data: None,
+        })
+    }
+
+    /// Adds a directory resource files are searched in. The resource_path of the settings is searched first,
+    /// then the directories in the order they were added. Without any, the directory of the settings is searched.
+    pub fn resource_directory<P: AsRef<Path>>(mut self, directory: P) -> Render {
+        self.resource_directories.push(directory.as_ref().to_path_buf());
+        self
+    }
+
+    /// Renders the contents of a Standard MIDI File instead of the input_file of the settings.
+    pub fn midi_data(mut self, data: Vec<u8>) -> Render {
+        self.midi_data = Some(data);
+        self
+    }
+
+    /// Renders into memory. The output_file of the settings is not written. Fails with the message of the command
+    /// line tool if the settings, the MIDI data or a resource file is invalid.
+    pub fn render(&self) -> Result<RenderedAudio, Error> {
+        let directories = if self.resource_directories.is_empty() {
+            vec![self.settings.input_path.clone()]
+        } else {
+            self.resource_directories.clone()
+        };
+        let resources = ResourcePaths { directories };
+        let mut progress = ProgressReporter::new(ReportMode::Log, &self.settings.output_file);
+        renderer::render_audio(&self.settings, &resources.for_settings(&self.settings), self.midi_data.as_ref().map(|d| &d[..]), None, &mut Vec::new(), &mut progress)
+    }
+
+    /// Renders and writes the output as a 16 bit WAV file to `writer`.
+    pub fn render_to<W: Write + Seek>(&self, writer: W) -> Result<RenderedAudio, Error> {
+        let audio = self.render()?;
+        audio.write_wav(writer)?;
+        Ok(audio)
+    }
+}
+
+impl RenderedAudio {
+    pub fn sample_rate(&self) -> u64 {
+        self.sample_rate
+    }
+
+    /// Interleaved left and right samples, not limited to -1.0 to 1.0.
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    /// Length in seconds, including the tails of releases and effects.
+    pub fn duration(&self) -> f64 {
+        (self.samples.len() / 2) as f64 / self.sample_rate as f64
+    }
+
+    /// Peaks of the left and right channel, 1.0 is full scale.
+    pub fn channel_peaks(&self) -> (f32, f32) {
+        self.samples.chunks(2).fold((0.0f32, 0.0f32), |(l, r), s| (l.max(s[0].abs()), r.max(s[1].abs())))
+    }
+
+    pub fn peak(&self) -> f32 {
+        let (left, right) = self.channel_peaks();
+        left.max(right)
+    }
+
+    /// Integrated loudness in LUFS after ITU-R BS.1770, None for silence.
+    pub fn loudness(&self) -> Option<f64> {
+        loudness::integrated_loudness(&self.samples, self.sample_rate)
+    }
+
+    /// Writes a 16 bit WAV file, samples outside of -1.0 to 1.0 are clipped.
+    pub fn write_wav<W: Write + Seek>(&self, writer: W) -> Result<(), Error> {
+        renderer::write_wav(writer, self.sample_rate, &self.samples)
+    }
+
+    /// Writes a 16 bit WAV file like the command line tool.
+    pub fn write_wav_file<P: AsRef<Path>>(&self, file: P) -> Result<(), Error> {
+        renderer::write_output(&PathBuf::from(file.as_ref()), self.sample_rate, &self.samples)
+    }
+}
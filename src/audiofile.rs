@@ -0,0 +1,50 @@
+extern crate claxon;
+extern crate hound;
+
+use std::path::PathBuf;
+
+use types::*;
+
+fn load_wav(file: &PathBuf) -> Result<AudioSample, Error> {
+    let invalid = |_| Error::new(format!("Could not read WAV file '{}'", file.to_str().unwrap()));
+    let mut reader = hound::WavReader::open(file).map_err(invalid)?;
+    let spec = reader.spec();
+    let data = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<f32>, _>>().map_err(invalid)?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>().map(|s| s.map(|s| s as f32 / scale)).collect::<Result<Vec<f32>, _>>().map_err(invalid)?
+        }
+    };
+    Ok(AudioSample {
+        channels: spec.channels as usize,
+        sample_rate: f64::from(spec.sample_rate),
+        data,
+    })
+}
+
+fn load_flac(file: &PathBuf) -> Result<AudioSample, Error> {
+    let mut reader = claxon::FlacReader::open(file)
+        .map_err(|_| Error::new(format!("Could not read FLAC file '{}'", file.to_str().unwrap())))?;
+    let info = reader.streaminfo();
+    let scale = (1i64 << (info.bits_per_sample - 1)) as f32;
+    let data = reader.samples().map(|s| s.map(|s| s as f32 / scale)).collect::<Result<Vec<f32>, _>>()
+        .map_err(|_| Error::new("Invalid FLAC data"))?;
+    Ok(AudioSample {
+        channels: info.channels as usize,
+        sample_rate: f64::from(info.sample_rate),
+        data,
+    })
+}
+
+/// Reads a mono or stereo WAV or FLAC file into interleaved frames, selected by the file extension.
+pub fn load_audio(file: &PathBuf) -> Result<AudioSample, Error> {
+    let extension = file.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+    let sample = match extension.as_ref().map(|e| e.as_str()) {
+        Some("wav") => load_wav(file)?,
+        Some("flac") => load_flac(file)?,
+        _ => fail!("Not a supported audio file: '{}'. Use WAV or FLAC files.", file.to_str().unwrap()),
+    };
+    ensure!(sample.channels == 1 || sample.channels == 2, "Only mono and stereo audio files are supported");
+    Ok(sample)
+}
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use types::*;
+use audiofile;
+use mapping;
+use sampler;
+use sampler::Sampler;
+use synthesizer::Synthesizer;
+
+// Channel volume at which clips play at their recorded level
+const CLIP_UNITY_VOLUME: f64 = 100.0;
+
+// Clips have no state SysEx messages could change
+const IGNORED_SYSEX: SynthesizerSysEx = SynthesizerSysEx {
+    reset: SysExHandling::Ignore,
+    master_volume: SysExHandling::Ignore,
+    drum_part: SysExHandling::Ignore,
+    other: SysExHandling::Ignore,
+};
+
+impl AudioSynthesizer {
+    pub fn new() -> AudioSynthesizer {
+        let mut core = SamplerCore::new("the audio synthesizer");
+        core.sysex = IGNORED_SYSEX;
+        AudioSynthesizer {
+            core,
+            clips: Vec::new(),
+            channel_clips: Vec::new(),
+            controllers: Vec::new(),
+            triggers: Vec::new(),
+            voices: Vec::new(),
+            end_time: 0.0,
+        }
+    }
+
+    /// Loads a clip relative to `directory` in the resource directories, files used several times are loaded once.
+    fn load_clip(&mut self, resources: &ResourcePaths, directory: &PathBuf, file: &str, loaded: &mut HashMap<PathBuf, usize>) -> Result<usize, Error> {
+        let path = resources.find(directory.join(file));
+        if let Some(index) = loaded.get(&path) {
+            return Ok(*index);
+        }
+        info!("Loading audio clip '{}'", path.to_str().unwrap());
+        self.clips.push(audiofile::load_audio(&path)?);
+        loaded.insert(path, self.clips.len() - 1);
+        Ok(self.clips.len() - 1)
+    }
+
+    fn restore_channels(&mut self) {
+        let mut controllers = vec![[0; 128]; self.core.router.used_channels];
+        for mapping in &self.core.router.mapping {
+            for destination in &mapping.destinations {
+                for (control, value) in mapping::initial_controllers(destination, &mapping.controller_rules) {
+                    controllers[destination.channel as usize][control as usize] = value;
+                }
+            }
+        }
+        self.controllers = controllers;
+    }
+
+    /// Extends the end time to the end of a clip started at `time` (in microseconds).
+    fn extend_end_time(&mut self, time: f64, clip: usize) {
+        let clip = &self.clips[clip];
+        let length = (clip.data.len() / clip.channels) as f64 / clip.sample_rate * 1_000_000.0;
+        self.end_time = self.end_time.max(time + length);
+    }
+
+    fn start_clip(&mut self, clip: usize, gain: f64, pan: f64) {
+        // Linear pan which keeps both sides at full level in the center
+        self.voices.push(AudioVoice {
+            clip,
+            position: 0.0,
+            left: (1.0 - pan).min(1.0) * gain,
+            right: (1.0 + pan).min(1.0) * gain,
+        });
+    }
+}
+
+impl Sampler for AudioSynthesizer {
+    fn core(&mut self) -> &mut SamplerCore {
+        &mut self.core
+    }
+
+    fn interprets_sysex(&self, _kind: SysExKind) -> bool {
+        false
+    }
+
+    fn process_event(&mut self, event: SamplerEvent) {
+        match event {
+            // Clips are one shots, note offs do not stop them
+            SamplerEvent::Message(MIDIMessage::NoteOn { channel, velocity, .. }) => {
+                let (gain, pan) = {
+                    let controllers = &self.controllers[channel as usize];
+                    let volume = f64::from(controllers[mapping::CC_VOLUME as usize]) / CLIP_UNITY_VOLUME;
+                    let pan = (f64::from(controllers[mapping::CC_PAN as usize]) - 64.0) / 63.0;
+                    (volume * f64::from(velocity) / 127.0, pan.max(-1.0).min(1.0))
+                };
+                let clip = self.channel_clips[channel as usize];
+                self.start_clip(clip, gain, pan);
+            }
+            SamplerEvent::Message(MIDIMessage::ControlChange { channel, control, value }) => {
+                self.controllers[channel as usize][control as usize] = value;
+            }
+            SamplerEvent::Message(MIDIMessage::Marker { text }) => {
+                let started: Vec<(usize, f64)> = self.triggers.iter()
+                    .filter(|t| t.marker.as_ref() == Some(&text))
+                    .map(|t| (t.clip, t.gain))
+                    .collect();
+                for (clip, gain) in started {
+                    debug!("Starting clip {} at marker '{}'", clip, text);
+                    self.start_clip(clip, gain, 0.0);
+                }
+            }
+            SamplerEvent::Clip(trigger) => {
+                let (clip, gain) = (self.triggers[trigger].clip, self.triggers[trigger].gain);
+                self.start_clip(clip, gain, 0.0);
+            }
+            _ => {}
+        }
+    }
+
+    fn render_voices(&mut self, buffer: &mut [f32]) {
+        let sample_rate = self.core.sample_rate as f64;
+        let clips = &self.clips;
+        self.voices.retain(|v| (v.position as usize) < clips[v.clip].data.len() / clips[v.clip].channels);
+        for voice in &mut self.voices {
+            let clip = &clips[voice.clip];
+            let frames = clip.data.len() / clip.channels;
+            let step = clip.sample_rate / sample_rate;
+            for frame in buffer.chunks_mut(2) {
+                let index = voice.position as usize;
+                if index >= frames {
+                    break;
+                }
+                let fraction = voice.position - index as f64;
+                let value = |frame: usize, channel: usize| if frame < frames { f64::from(clip.data[frame * clip.channels + channel]) } else { 0.0 };
+                let interpolate = |channel: usize| value(index, channel) + (value(index + 1, channel) - value(index, channel)) * fraction;
+                let (l, r) = if clip.channels == 2 { (interpolate(0), interpolate(1)) } else { (interpolate(0), interpolate(0)) };
+                frame[0] += (l * voice.left) as f32;
+                frame[1] += (r * voice.right) as f32;
+                voice.position += step;
+            }
+        }
+    }
+
+    fn reset_voices(&mut self) {
+        self.voices.clear();
+        self.restore_channels();
+    }
+}
+
+impl Synthesizer for AudioSynthesizer {
+    fn configure(&mut self, settings: &TOMLSynth, sample_rate: u64) -> Result<(), Error> {
+        self.core.sample_rate = sample_rate;
+        if settings.reverb.is_some() || settings.chorus.is_some() || settings.setting.is_some() || settings.soundfont.is_some() || settings.tuning.is_some()
+            || settings.sysex.is_some() {
+            warn!("The audio synthesizer has no soundfonts, tunings, reverb, chorus, SysEx or FluidSynth settings, ignoring them");
+        }
+        Ok(())
+    }
+
+    fn load_resources(&mut self, settings: &TOMLSynth, resources: &ResourcePaths) -> Result<(), Error> {
+        let directory = settings.directory.as_ref().map_or_else(PathBuf::new, PathBuf::from);
+        let mut loaded = HashMap::new();
+
+        self.core.router = mapping::generate_router(settings, resources)?;
+        let files = self.core.router.mapping.iter()
+            .flat_map(|m| m.destinations.iter())
+            .map(|d| d.instrument.clone().map(|i| (d.channel, i)).ok_or_else(|| Error::new("Destinations of synthtype 'audio' must contain instrument")))
+            .collect::<Result<Vec<(u8, String)>, Error>>()?;
+        self.channel_clips = vec![0; self.core.router.used_channels];
+        for (channel, file) in files {
+            self.channel_clips[channel as usize] = self.load_clip(resources, &directory, &file, &mut loaded)?;
+        }
+
+        if let Some(ref clips) = settings.clip {
+            for clip in clips {
+                let position = match (&clip.marker, clip.bar) {
+                    (&Some(_), None) => {
+                        ensure!(clip.beat.is_none(), "Clip beat requires bar");
+                        None
+                    }
+                    (&None, Some(bar)) => {
+                        let beat = clip.beat.unwrap_or(1.0);
+                        ensure!(bar >= 1 && beat >= 1.0, "Clip bar and beat must be at least 1");
+                        Some((bar, beat))
+                    }
+                    _ => fail!("Clip '{}' must contain either marker or bar", clip.file),
+                };
+                let gain = f64::from(clip.gain.unwrap_or(1.0));
+                ensure!(gain >= 0.0, "Clip gain must not be negative");
+                let index = self.load_clip(resources, &directory, &clip.file, &mut loaded)?;
+                self.triggers.push(AudioClipTrigger {
+                    clip: index,
+                    marker: clip.marker.clone(),
+                    position,
+                    gain,
+                });
+            }
+        }
+        self.restore_channels();
+        Ok(())
+    }
+
+    /// Queues the clips placed at a bar and beat, the events of the MIDI file are sorted in between.
+    fn set_tempo_map(&mut self, tempo_map: &TempoMap) {
+        for index in 0..self.triggers.len() {
+            if let Some((bar, beat)) = self.triggers[index].position {
+                let time = tempo_map.position_to_time(bar, beat);
+                let sample_time = self.core.sample_time(time);
+                self.core.add_event(sample_time, SamplerEvent::Clip(index));
+                let clip = self.triggers[index].clip;
+                self.extend_end_time(time, clip);
+            }
+        }
+    }
+
+    fn schedule_event(&mut self, time: f64, message: &MIDIMessage) -> Result<(), Error> {
+        // Clips play to their end, also after the last event of the MIDI file
+        let started: Vec<usize> = match *message {
+            MIDIMessage::NoteOn { channel, .. } => self.core.router.matching_destinations(channel).iter()
+                .map(|d| self.channel_clips[d.channel as usize])
+                .collect(),
+            MIDIMessage::Marker { ref text } => self.triggers.iter()
+                .filter(|t| t.marker.as_ref() == Some(text))
+                .map(|t| t.clip)
+                .collect(),
+            _ => Vec::new(),
+        };
+        for clip in started {
+            self.extend_end_time(time, clip);
+        }
+        sampler::schedule_event(self, time, message);
+        Ok(())
+    }
+
+    fn end_time(&self) -> f64 {
+        self.end_time
+    }
+
+    fn render_block(&mut self, buffer: &mut [f32]) -> Result<(), Error> {
+        sampler::render_block(self, buffer);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        sampler::reset(self);
+        // Recomputed when the events are scheduled again
+        self.end_time = 0.0;
+        Ok(())
+    }
+}
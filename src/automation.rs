@@ -0,0 +1,238 @@
+use types::*;
+
+fn to_samples(time: f64, sample_rate: u64) -> u64 {
+    (time * sample_rate as f64 / 1_000_000.0).round() as u64
+}
+
+/// Converts a position like "5:2:240" in bars, beats and ticks to microseconds. Ticks are pulses of the MIDI file.
+fn parse_position(position: &str, tempo_map: &TempoMap) -> Result<f64, Error> {
+    let parts = position.split(':')
+        .map(|p| p.trim().parse().map_err(|_| Error::new(format!("Not a valid automation position: '{}'. Use 'bar:beat:tick'.", position))))
+        .collect::<Result<Vec<u32>, Error>>()?;
+    ensure!(parts.len() == 3, "Not a valid automation position: '{}'. Use 'bar:beat:tick'.", position);
+    ensure!(parts[0] >= 1 && parts[1] >= 1, "Automation bar and beat must be at least 1");
+    let pulse = tempo_map.position_to_pulse(parts[0], f64::from(parts[1])).round() as u64 + u64::from(parts[2]);
+    Ok(tempo_map.pulse_to_time(pulse))
+}
+
+fn effect_parameters(effect: &Effect) -> &'static [&'static str] {
+    match *effect {
+        Effect::EQ { .. } => &["frequency", "gain", "q"],
+        Effect::Compressor { .. } => &["threshold", "ratio", "attack", "release", "makeup"],
+        Effect::Delay { .. } => &["feedback", "mix"],
+        Effect::Reverb { .. } => &["mix"],
+    }
+}
+
+fn generate_target(automation: &TOMLAutomation, effects: &[Effect]) -> Result<AutomationTarget, Error> {
+    let index = match automation.effect {
+        Some(index) => index,
+        None => {
+            ensure!(automation.band.is_none(), "Automation band requires effect");
+            return Ok(match automation.parameter.as_str() {
+                "gain" => AutomationTarget::Gain,
+                "pan" => AutomationTarget::Pan,
+                parameter => fail!("Not a valid automation parameter: '{}'. Use 'gain', 'pan' or set effect.", parameter),
+            });
+        }
+    };
+    ensure!(index >= 1 && index <= effects.len(), "Automated effect must be between 1 and the number of effects of the synth");
+    let effect = &effects[index - 1];
+    let parameters = effect_parameters(effect);
+    if !parameters.contains(&automation.parameter.as_str()) {
+        let names: Vec<String> = parameters.iter().map(|p| format!("'{}'", p)).collect();
+        fail!("Not a valid parameter of effect {}: '{}'. Use {}.", index, automation.parameter, names.join(", "));
+    }
+    let band = match *effect {
+        Effect::EQ { ref bands } => {
+            let band = automation.band.ok_or_else(|| Error::new("Automation of an EQ requires band"))?;
+            ensure!(band >= 1 && band <= bands.len(), "Automated band must be between 1 and the number of bands of the EQ");
+            band - 1
+        }
+        _ => {
+            ensure!(automation.band.is_none(), "Automation band is only valid for EQ effects");
+            0
+        }
+    };
+    Ok(AutomationTarget::Effect {
+        effect: index - 1,
+        band,
+        parameter: automation.parameter.clone(),
+    })
+}
+
+fn check_value(target: &AutomationTarget, value: f64, sample_rate: u64) -> Result<(), Error> {
+    match *target {
+        AutomationTarget::Gain => ensure!(value >= 0.0, "Automated gain must not be negative"),
+        AutomationTarget::Pan => ensure!(value >= -1.0 && value <= 1.0, "Automated pan must be between -1 and 1"),
+        AutomationTarget::Effect { ref parameter, .. } => match parameter.as_str() {
+            "mix" => ensure!(value >= 0.0 && value <= 1.0, "Automated mix must be between 0 and 1"),
+            "feedback" => ensure!(value >= 0.0 && value < 1.0, "Automated feedback must be at least 0 and below 1"),
+            "ratio" => ensure!(value >= 1.0, "Automated ratio must be at least 1"),
+            "attack" | "release" => ensure!(value >= 0.0, "Automated attack and release must not be negative"),
+            "q" => ensure!(value > 0.0, "Automated q must be positive"),
+            "frequency" => ensure!(value > 0.0 && value < sample_rate as f64 / 2.0, "Automated frequency must be between 0 and half the sample rate"),
+            // Levels in dB
+            _ => {}
+        },
+    }
+    Ok(())
+}
+
+fn controller_points(automation: &TOMLAutomation, controller: u8, tempo_map: &TempoMap, events: &[MIDIEvent], sample_rate: u64) -> Result<Vec<(u64, f64)>, Error> {
+    let channel = automation.channel.ok_or_else(|| Error::new("Controller automation requires channel"))?;
+    let min = automation.min.unwrap_or(0.0);
+    let max = automation.max.unwrap_or(1.0);
+    Ok(events.iter()
+        .filter_map(|e| match e.message {
+            MIDIMessage::ControlChange { channel: c, control, value } if c == channel && control == controller => {
+                Some((to_samples(tempo_map.pulse_to_time(e.pulse), sample_rate), min + (max - min) * f64::from(value) / 127.0))
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+/// Generates the automation lanes of a synth. Lanes derived from a controller step by default, others ramp linearly.
+pub fn generate_lanes(automations: &[TOMLAutomation], effects: &[Effect], tempo_map: &TempoMap, events: &[MIDIEvent], sample_rate: u64) -> Result<Vec<AutomationLane>, Error> {
+    let mut lanes: Vec<AutomationLane> = Vec::new();
+    for automation in automations {
+        let target = generate_target(automation, effects)?;
+        ensure!(lanes.iter().all(|l| l.target != target), "Parameter '{}' is automated more than once", automation.parameter);
+
+        let curve = match automation.curve.as_ref().map(|c| c.as_str()) {
+            None if automation.controller.is_some() => AutomationCurve::Step,
+            None | Some("linear") => AutomationCurve::Linear,
+            Some("step") => AutomationCurve::Step,
+            Some("exponential") => AutomationCurve::Exponential,
+            Some(curve) => fail!("Not a valid automation curve: '{}'. Use 'step', 'linear' or 'exponential'.", curve),
+        };
+
+        let mut points: Vec<(u64, f64)> = match (&automation.point, automation.controller) {
+            (&Some(ref points), None) => points.iter()
+                .map(|p| {
+                    let time = match (p.time, &p.position) {
+                        (Some(time), &None) => {
+                            ensure!(time >= 0.0, "Automation time must not be negative");
+                            time * 1_000_000.0
+                        }
+                        (None, &Some(ref position)) => parse_position(position, tempo_map)?,
+                        _ => fail!("Automation points must contain either time or position"),
+                    };
+                    Ok((to_samples(time, sample_rate), p.value))
+                })
+                .collect::<Result<Vec<(u64, f64)>, Error>>()?,
+            (&None, Some(controller)) => controller_points(automation, controller, tempo_map, events, sample_rate)?,
+            _ => fail!("Automation of '{}' must contain either point or controller", automation.parameter),
+        };
+        // Stable, points at the same time keep their order and form a jump
+        points.sort_by_key(|p| p.0);
+        if points.is_empty() {
+            warn!("Ignoring automation of '{}' without points", automation.parameter);
+            continue;
+        }
+        for &(_, value) in &points {
+            check_value(&target, value, sample_rate)?;
+            ensure!(curve != AutomationCurve::Exponential || value > 0.0, "Exponential automation curves require positive values");
+        }
+        debug!("Automating {:?} with {} points", target, points.len());
+        lanes.push(AutomationLane { target, curve, points });
+    }
+    Ok(lanes)
+}
+
+pub fn find_lane<'a>(lanes: &'a [AutomationLane], target: &AutomationTarget) -> Option<&'a AutomationLane> {
+    lanes.iter().find(|l| l.target == *target)
+}
+
+impl AutomationLane {
+    /// Returns the value at a time in samples. The first and last points hold before and after the lane.
+    pub fn value_at(&self, time: u64) -> f64 {
+        // Find the first point after time
+        let (mut low, mut high) = (0, self.points.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.points[middle].0 <= time {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        if low == 0 {
+            return self.points[0].1;
+        }
+        if low == self.points.len() {
+            return self.points[low - 1].1;
+        }
+
+        let (start, from) = self.points[low - 1];
+        let (end, to) = self.points[low];
+        let position = (time - start) as f64 / (end - start) as f64;
+        match self.curve {
+            AutomationCurve::Step => from,
+            AutomationCurve::Linear => from + (to - from) * position,
+            AutomationCurve::Exponential => from * (to / from).powf(position),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 120 BPM at 480 pulses per quarter note in 4/4.
+    fn tempo_map() -> TempoMap {
+        TempoMap {
+            pulses_per_quarter_note: 480,
+            tempo_changes: vec![MIDITempoChange { pulse: 0, us_per_pulse: 500_000.0 / 480.0 }],
+            time_signatures: vec![MIDITimeSignature { pulse: 0, numerator: 4, denominator: 4 }],
+        }
+    }
+
+    fn lane(curve: AutomationCurve, points: Vec<(u64, f64)>) -> AutomationLane {
+        AutomationLane { target: AutomationTarget::Gain, curve, points }
+    }
+
+    #[test]
+    fn parse_position_counts_bars_beats_and_ticks() {
+        let tempo_map = tempo_map();
+        assert_eq!(parse_position("1:1:0", &tempo_map).unwrap(), 0.0);
+        assert!((parse_position("1:2:240", &tempo_map).unwrap() - 750_000.0).abs() < 1e-6);
+        assert!((parse_position(" 3 : 1 : 0 ", &tempo_map).unwrap() - 4_000_000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn parse_position_requires_ticks() {
+        let error = parse_position("2:1", &tempo_map()).unwrap_err();
+        assert!(error.message().starts_with("Not a valid automation position"), "{}", error);
+    }
+
+    #[test]
+    fn parse_position_rejects_bar_zero() {
+        assert_eq!(parse_position("0:1:0", &tempo_map()).unwrap_err().message(), "Automation bar and beat must be at least 1");
+    }
+
+    #[test]
+    fn value_at_holds_outside_of_the_points() {
+        let lane = lane(AutomationCurve::Linear, vec![(100, 1.0), (200, 3.0)]);
+        assert_eq!(lane.value_at(0), 1.0);
+        assert_eq!(lane.value_at(100), 1.0);
+        assert_eq!(lane.value_at(200), 3.0);
+        assert_eq!(lane.value_at(1000), 3.0);
+    }
+
+    #[test]
+    fn value_at_follows_the_curve() {
+        assert_eq!(lane(AutomationCurve::Step, vec![(100, 1.0), (200, 3.0)]).value_at(199), 1.0);
+        assert_eq!(lane(AutomationCurve::Linear, vec![(100, 1.0), (200, 3.0)]).value_at(150), 2.0);
+        assert!((lane(AutomationCurve::Exponential, vec![(100, 1.0), (200, 4.0)]).value_at(150) - 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn value_at_jumps_at_points_with_the_same_time() {
+        let lane = lane(AutomationCurve::Linear, vec![(0, 0.0), (100, 1.0), (100, 5.0), (200, 5.0)]);
+        assert_eq!(lane.value_at(50), 0.5);
+        assert_eq!(lane.value_at(100), 5.0);
+        assert_eq!(lane.value_at(150), 5.0);
+    }
+}
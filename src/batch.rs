@@ -0,0 +1,94 @@
+extern crate num_cpus;
+extern crate time;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use types::*;
+use renderer;
+use progress;
+
+struct JobOutcome {
+    job: usize,
+    output_file: String,
+    // Render time in seconds
+    render_time: f64,
+    // None if the job was skipped because its inputs have not changed
+    result: Result<Option<RenderResult>, String>,
+}
+
+fn format_length(length: f64) -> String {
+    let seconds = length / 1_000_000.0;
+    format!("{}:{:06.3}", (seconds / 60.0).floor(), seconds % 60.0)
+}
+
+fn print_summary(outcomes: &[JobOutcome]) {
+    println!("{:<4} {:<32} {:>10} {:>11} {:>10} {:>9}  {}", "Job", "Output", "Length", "Peak", "Loudness", "Time", "Status");
+    for outcome in outcomes {
+        match outcome.result {
+            Ok(None) => println!("{:<4} {:<32} {:>10} {:>11} {:>10} {:>9}  skipped, unchanged", outcome.job, outcome.output_file, "-", "-", "-", "-"),
+            Ok(Some(ref result)) => println!("{:<4} {:<32} {:>10} {:>6.1} dBFS {:>10} {:>7.1} s  ok",
+                                       outcome.job, outcome.output_file, format_length(result.length),
+                                       20.0 * result.peak.max(1e-9).log10(),
+                                       result.loudness.map_or("-".to_string(), |l| format!("{:.1} LUFS", l)), outcome.render_time),
+            Err(ref message) => println!("{:<4} {:<32} {:>10} {:>11} {:>10} {:>7.1} s  failed: {}",
+                                         outcome.job, outcome.output_file, "-", "-", "-", outcome.render_time, message),
+        }
+    }
+    let failed = outcomes.iter().filter(|o| o.result.is_err()).count();
+    let skipped = outcomes.iter().filter(|o| o.result.as_ref().ok().map_or(false, |r| r.is_none())).count();
+    println!("{} of {} jobs rendered, {} skipped, {} failed", outcomes.len() - failed - skipped, outcomes.len(), skipped, failed);
+}
+
+/// Renders all jobs on `threads` worker threads, every job with its own synthesizers. A failing job does not stop the others,
+/// unchanged jobs are skipped unless forced. Returns false if any job failed. In JSON mode every job reports its events
+/// instead of the summary table, progress bars are not shown for parallel jobs.
+pub fn process_jobs(jobs: Vec<TOMLRenderSettings>, resources: &ResourcePaths, threads: Option<usize>, force: bool, report: ReportMode) -> bool {
+    let report = if report == ReportMode::Json { ReportMode::Json } else { ReportMode::Log };
+    let threads = threads.unwrap_or_else(num_cpus::get).max(1).min(jobs.len().max(1));
+    info!("Rendering {} jobs on {} threads", jobs.len(), threads);
+
+    let queue: Arc<Mutex<VecDeque<(usize, TOMLRenderSettings)>>> = Arc::new(Mutex::new(jobs.into_iter().enumerate().map(|(i, j)| (i + 1, j)).collect()));
+    let outcomes: Arc<Mutex<Vec<JobOutcome>>> = Arc::new(Mutex::new(Vec::new()));
+    let workers: Vec<thread::JoinHandle<()>> = (0..threads)
+        .map(|_| {
+            let queue = queue.clone();
+            let outcomes = outcomes.clone();
+            let resources = resources.clone();
+            thread::spawn(move || loop {
+                let next = queue.lock().unwrap().pop_front();
+                let (job, render_settings) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                info!("Starting job {}: '{}'", job, render_settings.output_file);
+                let start = time::precise_time_s();
+                let result = renderer::process_if_changed(&render_settings, &resources, false, force, report)
+                    .map_err(|e| e.message().to_string());
+                if let (ReportMode::Json, &Err(ref message)) = (report, &result) {
+                    progress::emit("failed", &[
+                        ("output", progress::json_string(&render_settings.output_file)),
+                        ("message", progress::json_string(message)),
+                    ]);
+                }
+                outcomes.lock().unwrap().push(JobOutcome {
+                    job,
+                    output_file: render_settings.output_file.clone(),
+                    render_time: time::precise_time_s() - start,
+                    result,
+                });
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    let mut outcomes = Arc::try_unwrap(outcomes).ok().unwrap().into_inner().unwrap();
+    outcomes.sort_by_key(|o| o.job);
+    if report != ReportMode::Json {
+        print_summary(&outcomes);
+    }
+    outcomes.iter().all(|o| o.result.is_ok())
+}
@@ -0,0 +1,114 @@
+extern crate env_logger;
+
+use std::env;
+use std::io;
+use std::path::PathBuf;
+
+use log::{LogLevel, LogLevelFilter, LogRecord};
+use structopt::StructOpt;
+use structopt::clap::Shell;
+
+use types::*;
+use tomlparser;
+use renderer;
+use batch;
+use watch;
+use inspect;
+use gm_instruments;
+use sf2;
+use progress;
+
+/// Sets up logging to standard error. With --json log messages become JSON events on standard output, warnings and errors
+/// as 'warning' and 'error', everything else as 'log'.
+fn init_logging(options: &Options) {
+    let level = if options.quiet {
+        LogLevelFilter::Error
+    } else {
+        match options.verbose {
+            0 => LogLevelFilter::Warn,
+            1 => LogLevelFilter::Info,
+            2 => LogLevelFilter::Debug,
+            _ => LogLevelFilter::Trace,
+        }
+    };
+    let mut builder = env_logger::LogBuilder::new();
+    builder.filter(None, level);
+    if options.json {
+        builder.target(env_logger::LogTarget::Stdout);
+        builder.format(|record: &LogRecord| {
+            let event = match record.level() {
+                LogLevel::Error => "error",
+                LogLevel::Warn => "warning",
+                _ => "log",
+            };
+            progress::event_line(event, &[
+                ("level", progress::json_string(&record.level().to_string())),
+                ("message", progress::json_string(&record.args().to_string())),
+            ])
+        });
+    } else {
+        // Standard output may carry the rendered audio
+        builder.target(env_logger::LogTarget::Stderr);
+    }
+    // RUST_LOG still allows finer filters per module
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse(&filters);
+    }
+    builder.init().unwrap();
+}
+
+/// Runs the command of the command line tool. Returns false if it failed, the error is logged, or reported as a 'failed'
+/// event with --json. A batch with failed jobs fails as well.
+fn run_command(options: &Options) -> bool {
+    debug!("Options: {:?}", options);
+    let report = progress::report_mode(options.json, options.quiet);
+    match run(options, report) {
+        Ok(success) => success,
+        Err(e) => {
+            if report == ReportMode::Json {
+                progress::emit("failed", &[("message", progress::json_string(e.message()))]);
+            } else {
+                error!("{}", e);
+            }
+            false
+        }
+    }
+}
+
+fn run(options: &Options, report: ReportMode) -> Result<bool, Error> {
+    match options.command {
+        Command::Render { ref input, ref resources, debug, watch: true, ref overrides, .. } => {
+            watch::watch(input, &ResourcePaths::from_argument(resources)?, overrides, debug, report)?;
+        }
+        Command::Render { ref input, ref resources, debug, force, ref overrides, .. } => {
+            let render_settings = tomlparser::read_input_file(input, overrides)?;
+            debug!("Render settings: {:?}", render_settings);
+            renderer::process_if_changed(&render_settings, &ResourcePaths::from_argument(resources)?, debug, force, report)?;
+        }
+        Command::Validate { ref input, ref resources, ref overrides } => {
+            let render_settings = tomlparser::read_input_file(input, overrides)?;
+            debug!("Render settings: {:?}", render_settings);
+            renderer::validate_render_settings(&render_settings, &ResourcePaths::from_argument(resources)?, report)?;
+        }
+        Command::Batch { ref project, ref resources, jobs, force } => {
+            let render_settings = tomlparser::read_project_file(project)?;
+            return Ok(batch::process_jobs(render_settings, &ResourcePaths::from_argument(resources)?, jobs, force, report));
+        }
+        Command::Inspect { ref midi_file } => inspect::inspect_midi_file(&PathBuf::from(midi_file), report)?,
+        Command::ListInstruments => gm_instruments::list_instruments(report),
+        Command::ListPresets { ref soundfont } => sf2::list_presets(&PathBuf::from(soundfont), report)?,
+        Command::Completions { ref shell } => {
+            let shell: Shell = shell.parse()
+                .map_err(|_| Error::new(format!("Not a valid shell: '{}'. Use 'bash', 'fish', 'zsh', 'powershell' or 'elvish'.", shell)))?;
+            Options::clap().gen_completions_to("musicrenderer_rust", shell, &mut io::stdout());
+        }
+    }
+    Ok(true)
+}
+
+/// Entry point of the command line tool, which is not part of the library API. Returns false if the command failed.
+pub fn main() -> bool {
+    let options = Options::from_args();
+    init_logging(&options);
+    run_command(&options)
+}
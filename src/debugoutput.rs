@@ -0,0 +1,93 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+use types::*;
+use mapping;
+use stdio;
+
+/// Creates the directory for interstage products next to the output file, named like the output file with '.debug' appended.
+/// Output to standard output gets 'stdout.debug'.
+pub fn debug_directory(render_settings: &TOMLRenderSettings) -> Result<PathBuf, Error> {
+    let name = if stdio::is_standard_stream(&render_settings.output_file) { "stdout" } else { &render_settings.output_file };
+    let mut directory = render_settings.input_path.clone();
+    directory.push(format!("{}.debug", name));
+    fs::create_dir_all(&directory)
+        .map_err(|_| Error::new(format!("Could not create debug directory '{}'", directory.to_str().unwrap())))?;
+    info!("Writing interstage products to '{}'", directory.to_str().unwrap());
+    Ok(directory)
+}
+
+fn write_file(directory: &PathBuf, name: &str, contents: &str) -> Result<(), Error> {
+    let mut file = directory.clone();
+    file.push(name);
+    File::create(&file)
+        .and_then(|mut f| f.write_all(contents.as_bytes()))
+        .map_err(|_| Error::new(format!("Could not write debug file '{}'", file.to_str().unwrap())))
+}
+
+fn or_any<T: ToString>(value: Option<T>) -> String {
+    value.map_or("any".to_string(), |v| v.to_string())
+}
+
+pub fn write_settings(directory: &PathBuf, render_settings: &TOMLRenderSettings) -> Result<(), Error> {
+    write_file(directory, "settings.txt", &format!("{:#?}\n", render_settings))
+}
+
+/// Writes the mappings of all synths as their routers resolve them, with destination channels and channel strips.
+pub fn write_mapping(directory: &PathBuf, render_settings: &TOMLRenderSettings, resources: &ResourcePaths) -> Result<(), Error> {
+    let mut ids: Vec<&String> = render_settings.synth.keys().collect();
+    ids.sort();
+    let mut res = String::new();
+    for id in ids {
+        let settings = &render_settings.synth[id];
+        res.push_str(&format!("[{}] synthtype {}, gain {}\n", id, settings.synthtype, settings.gain));
+        let router = mapping::generate_router(settings, resources)?;
+        for mapping in &router.mapping {
+            res.push_str(&format!("  channel {}, program {}\n", or_any(mapping.condition.channel), or_any(mapping.condition.program)));
+            for d in &mapping.destinations {
+                res.push_str(&format!("    -> channel {}: soundfont {}, bank {}, program {}, instrument {}, volume {}, pan {}, transpose {}, detune {}, velocity {:?}, tuning {}\n",
+                                      d.channel, d.soundfont, d.bank, d.program, d.instrument.as_ref().map_or("none", |i| i.as_str()),
+                                      d.volume, d.pan, d.transpose, d.detune, d.velocity_curve, d.tuning.map_or("none".to_string(), |t| t.to_string())));
+            }
+            for rule in &mapping.controller_rules {
+                res.push_str(&format!("    controller {}: {:?}\n", rule.control, rule.action));
+            }
+        }
+    }
+    write_file(directory, "mapping.txt", &res)
+}
+
+pub fn write_tempo_map(directory: &PathBuf, tempo_map: &TempoMap) -> Result<(), Error> {
+    let mut res = format!("Pulses per quarter note: {}\n\nTempo changes:\n", tempo_map.pulses_per_quarter_note);
+    for change in &tempo_map.tempo_changes {
+        let bpm = 60_000_000.0 / (change.us_per_pulse * f64::from(tempo_map.pulses_per_quarter_note));
+        res.push_str(&format!("  pulse {}, {:.6} s: {} us per pulse, {:.3} BPM\n", change.pulse, tempo_map.pulse_to_time(change.pulse) / 1_000_000.0, change.us_per_pulse, bpm));
+    }
+    res.push_str("\nTime signatures:\n");
+    for signature in &tempo_map.time_signatures {
+        let (bar, _) = tempo_map.pulse_to_position(signature.pulse);
+        res.push_str(&format!("  pulse {}, bar {}: {}/{}\n", signature.pulse, bar, signature.numerator, signature.denominator));
+    }
+    write_file(directory, "tempo_map.txt", &res)
+}
+
+/// Writes the events of all tracks in the order they are scheduled, with their position and time.
+pub fn write_events(directory: &PathBuf, handler_data: &MIDIHandlerData) -> Result<(), Error> {
+    let tempo_map = &handler_data.tempo_map;
+    let mut res = String::from("pulse\tbar:beat\tseconds\tmessage\n");
+    for event in &handler_data.events {
+        let (bar, beat) = tempo_map.pulse_to_position(event.pulse);
+        res.push_str(&format!("{}\t{}:{:.3}\t{:.6}\t{:?}\n", event.pulse, bar, beat, tempo_map.pulse_to_time(event.pulse) / 1_000_000.0, event.message));
+    }
+    write_file(directory, "events.tsv", &res)
+}
+
+pub fn write_timings(directory: &PathBuf, timings: &[(String, f64)]) -> Result<(), Error> {
+    let mut res = String::new();
+    for &(ref stage, seconds) in timings {
+        res.push_str(&format!("{:.3} s\t{}\n", seconds, stage));
+    }
+    res.push_str(&format!("{:.3} s\ttotal\n", timings.iter().map(|t| t.1).sum::<f64>()));
+    write_file(directory, "timing.txt", &res)
+}
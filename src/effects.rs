@@ -0,0 +1,488 @@
+use std::f64::consts::PI;
+
+use types::*;
+use audiofile;
+
+// Level the compressor envelope starts at, in dB
+const COMPRESSOR_FLOOR: f64 = -120.0;
+
+fn check_mix(mix: f64) -> Result<f64, Error> {
+    ensure!(mix >= 0.0 && mix <= 1.0, "Effect mix must be between 0 and 1");
+    Ok(mix)
+}
+
+fn generate_band(band: &TOMLEQBand, sample_rate: u64) -> Result<EQBand, Error> {
+    let shape = match band.shape.as_str() {
+        "peak" => EQShape::Peak,
+        "lowshelf" => EQShape::LowShelf,
+        "highshelf" => EQShape::HighShelf,
+        "lowpass" => EQShape::LowPass,
+        "highpass" => EQShape::HighPass,
+        shape => fail!("Not a valid EQ band shape: '{}'. Use 'peak', 'lowshelf', 'highshelf', 'lowpass' or 'highpass'.", shape),
+    };
+    ensure!(band.frequency > 0.0 && band.frequency < sample_rate as f64 / 2.0, "EQ band frequency must be between 0 and half the sample rate");
+    let q = band.q.unwrap_or(0.5f64.sqrt());
+    ensure!(q > 0.0, "EQ band q must be positive");
+    Ok(EQBand {
+        shape,
+        frequency: band.frequency,
+        gain: band.gain.unwrap_or(0.0),
+        q,
+    })
+}
+
+/// Converts an impulse response to the render sample rate with linear interpolation.
+fn resample(sample: AudioSample, sample_rate: u64) -> AudioSample {
+    if sample.sample_rate == sample_rate as f64 {
+        return sample;
+    }
+    let frames = sample.data.len() / sample.channels;
+    let step = sample.sample_rate / sample_rate as f64;
+    let length = (frames as f64 / step).floor() as usize;
+    let mut data = Vec::with_capacity(length * sample.channels);
+    for frame in 0..length {
+        let position = frame as f64 * step;
+        let index = position as usize;
+        let fraction = (position - index as f64) as f32;
+        for channel in 0..sample.channels {
+            let current = sample.data[index * sample.channels + channel];
+            let next = if index + 1 < frames { sample.data[(index + 1) * sample.channels + channel] } else { 0.0 };
+            data.push(current + (next - current) * fraction);
+        }
+    }
+    AudioSample {
+        channels: sample.channels,
+        sample_rate: sample_rate as f64,
+        data,
+    }
+}
+
+fn generate_effect(effect: &TOMLEffect, resources: &ResourcePaths, sample_rate: u64) -> Result<Effect, Error> {
+    Ok(match effect.effecttype.as_str() {
+        "eq" => Effect::EQ {
+            bands: effect.band.as_ref().ok_or_else(|| Error::new("EQ effect requires band"))?.iter()
+                .map(|b| generate_band(b, sample_rate))
+                .collect::<Result<Vec<EQBand>, Error>>()?,
+        },
+        "compressor" => {
+            let ratio = effect.ratio.unwrap_or(4.0);
+            ensure!(ratio >= 1.0, "Compressor ratio must be at least 1");
+            let attack = effect.attack.unwrap_or(0.01);
+            let release = effect.release.unwrap_or(0.1);
+            ensure!(attack >= 0.0 && release >= 0.0, "Compressor attack and release must not be negative");
+            Effect::Compressor {
+                threshold: effect.threshold.unwrap_or(-20.0),
+                ratio,
+                attack,
+                release,
+                makeup: effect.makeup.unwrap_or(0.0),
+            }
+        }
+        "delay" => {
+            let time_left = effect.time_left.or(effect.time).ok_or_else(|| Error::new("Delay effect requires time or time_left"))?;
+            let time_right = effect.time_right.or(effect.time).ok_or_else(|| Error::new("Delay effect requires time or time_right"))?;
+            ensure!(time_left > 0.0 && time_right > 0.0, "Delay time must be positive");
+            let feedback = effect.feedback.unwrap_or(0.3);
+            ensure!(feedback >= 0.0 && feedback < 1.0, "Delay feedback must be at least 0 and below 1");
+            Effect::Delay {
+                time_left,
+                time_right,
+                feedback,
+                mix: check_mix(effect.mix.unwrap_or(0.3))?,
+            }
+        }
+        "reverb" => {
+            let file = resources.find(effect.impulse.as_ref().ok_or_else(|| Error::new("Reverb effect requires impulse"))?);
+            info!("Loading impulse response '{}'", file.to_str().unwrap());
+            Effect::Reverb {
+                impulse: resample(audiofile::load_audio(&file)?, sample_rate),
+                mix: check_mix(effect.mix.unwrap_or(0.3))?,
+            }
+        }
+        effecttype => fail!("Not a valid effecttype: '{}'. Use 'eq', 'compressor', 'delay' or 'reverb'.", effecttype),
+    })
+}
+
+pub fn generate_effects(effects: &[TOMLEffect], resources: &ResourcePaths, sample_rate: u64) -> Result<Vec<Effect>, Error> {
+    effects.iter().map(|e| generate_effect(e, resources, sample_rate)).collect()
+}
+
+/// Time in microseconds the effects keep sounding after their input became silent. The effects of a chain are
+/// serial, so their tails add up.
+pub fn tail(effects: &[Effect]) -> f64 {
+    effects.iter()
+        .map(|e| match *e {
+            Effect::Delay { time_left, time_right, feedback, .. } => {
+                // The first echo, then the echoes fed back until they are 60 dB down
+                let echoes = if feedback > 0.0 { 1.0 + (0.001f64).ln() / feedback.ln() } else { 1.0 };
+                time_left.max(time_right) * echoes * 1_000_000.0
+            }
+            Effect::Reverb { ref impulse, .. } => (impulse.data.len() / impulse.channels) as f64 * 1_000_000.0 / impulse.sample_rate,
+            _ => 0.0,
+        })
+        .sum()
+}
+
+fn parameter_lane<'a>(lanes: &[&'a AutomationLane], parameter: &str, band: usize) -> Option<&'a AutomationLane> {
+    lanes.iter()
+        .find(|l| match l.target {
+            AutomationTarget::Effect { band: b, parameter: ref p, .. } => b == band && p == parameter,
+            _ => false,
+        })
+        .map(|l| *l)
+}
+
+/// Biquad coefficients b0, b1, b2, a1, a2 normalized by a0, from the Audio EQ Cookbook.
+fn band_coefficients(band: &EQBand, sample_rate: u64) -> [f64; 5] {
+    let a = 10f64.powf(band.gain / 40.0);
+    let w0 = 2.0 * PI * band.frequency / sample_rate as f64;
+    let cos = w0.cos();
+    let alpha = w0.sin() / (2.0 * band.q);
+    let shelf = 2.0 * a.sqrt() * alpha;
+    let c = match band.shape {
+        EQShape::Peak => [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a, 1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+        EQShape::LowShelf => [
+            a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+            2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+            a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+            (a + 1.0) + (a - 1.0) * cos + shelf,
+            -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+            (a + 1.0) + (a - 1.0) * cos - shelf,
+        ],
+        EQShape::HighShelf => [
+            a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+            -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+            a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+            (a + 1.0) - (a - 1.0) * cos + shelf,
+            2.0 * ((a - 1.0) - (a + 1.0) * cos),
+            (a + 1.0) - (a - 1.0) * cos - shelf,
+        ],
+        EQShape::LowPass => [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        EQShape::HighPass => [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+    };
+    [c[0] / c[3], c[1] / c[3], c[2] / c[3], c[4] / c[3], c[5] / c[3]]
+}
+
+fn process_eq(bands: &[EQBand], lanes: &[&AutomationLane], buffer: &mut [f32], sample_rate: u64) {
+    for (index, band) in bands.iter().enumerate() {
+        let automated_lanes = (parameter_lane(lanes, "frequency", index), parameter_lane(lanes, "gain", index), parameter_lane(lanes, "q", index));
+        let automated = automated_lanes.0.is_some() || automated_lanes.1.is_some() || automated_lanes.2.is_some();
+        let mut c = band_coefficients(band, sample_rate);
+        // x1, x2, y1, y2 for both channels
+        let mut state = [[0.0f64; 4]; 2];
+        for (frame, samples) in buffer.chunks_mut(2).enumerate() {
+            if automated {
+                let frame = frame as u64;
+                c = band_coefficients(&EQBand {
+                    shape: band.shape,
+                    frequency: automated_lanes.0.map_or(band.frequency, |l| l.value_at(frame)),
+                    gain: automated_lanes.1.map_or(band.gain, |l| l.value_at(frame)),
+                    q: automated_lanes.2.map_or(band.q, |l| l.value_at(frame)),
+                }, sample_rate);
+            }
+            for (sample, s) in samples.iter_mut().zip(state.iter_mut()) {
+                let x = f64::from(*sample);
+                let y = c[0] * x + c[1] * s[0] + c[2] * s[1] - c[3] * s[2] - c[4] * s[3];
+                *s = [x, s[0], y, s[2]];
+                *sample = y as f32;
+            }
+        }
+    }
+}
+
+fn process_compressor(lanes: &[&AutomationLane], buffer: &mut [f32], sample_rate: u64, threshold: f64, ratio: f64, attack: f64, release: f64, makeup: f64) {
+    let coefficient = |time: f64| if time > 0.0 { (-1.0 / (time * sample_rate as f64)).exp() } else { 0.0 };
+    let automated_lanes = [parameter_lane(lanes, "threshold", 0), parameter_lane(lanes, "ratio", 0), parameter_lane(lanes, "attack", 0), parameter_lane(lanes, "release", 0), parameter_lane(lanes, "makeup", 0)];
+    let (mut attack_coefficient, mut release_coefficient) = (coefficient(attack), coefficient(release));
+    // Both channels share the detector so the stereo image does not shift
+    let mut envelope = COMPRESSOR_FLOOR;
+    for (frame, samples) in buffer.chunks_mut(2).enumerate() {
+        let value = |lane: Option<&AutomationLane>, value: f64| lane.map_or(value, |l| l.value_at(frame as u64));
+        if automated_lanes[2].is_some() {
+            attack_coefficient = coefficient(value(automated_lanes[2], attack));
+        }
+        if automated_lanes[3].is_some() {
+            release_coefficient = coefficient(value(automated_lanes[3], release));
+        }
+
+        let peak = samples.iter().fold(0.0f64, |m, s| m.max(f64::from(s.abs())));
+        let level = (20.0 * peak.log10()).max(COMPRESSOR_FLOOR);
+        let c = if level > envelope { attack_coefficient } else { release_coefficient };
+        envelope = c * envelope + (1.0 - c) * level;
+        let reduction = (envelope - value(automated_lanes[0], threshold)).max(0.0) * (1.0 - 1.0 / value(automated_lanes[1], ratio));
+        let gain = 10f64.powf((value(automated_lanes[4], makeup) - reduction) / 20.0);
+        for sample in samples.iter_mut() {
+            *sample = (f64::from(*sample) * gain) as f32;
+        }
+    }
+}
+
+fn process_delay(lanes: &[&AutomationLane], buffer: &mut [f32], sample_rate: u64, time_left: f64, time_right: f64, feedback: f64, mix: f64) {
+    let length = |time: f64| ((time * sample_rate as f64).round() as usize).max(1);
+    let (feedback_lane, mix_lane) = (parameter_lane(lanes, "feedback", 0), parameter_lane(lanes, "mix", 0));
+    let mut lines = [vec![0.0f64; length(time_left)], vec![0.0f64; length(time_right)]];
+    for (frame, samples) in buffer.chunks_mut(2).enumerate() {
+        let feedback = feedback_lane.map_or(feedback, |l| l.value_at(frame as u64));
+        let mix = mix_lane.map_or(mix, |l| l.value_at(frame as u64));
+        for (sample, line) in samples.iter_mut().zip(lines.iter_mut()) {
+            let index = frame % line.len();
+            let x = f64::from(*sample);
+            let delayed = line[index];
+            line[index] = x + delayed * feedback;
+            *sample = (x * (1.0 - mix) + delayed * mix) as f32;
+        }
+    }
+}
+
+/// In place radix 2 FFT, the length must be a power of two. The inverse transform is scaled by 1 / length.
+fn fft(re: &mut [f64], im: &mut [f64], inverse: bool) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut length = 2;
+    while length <= n {
+        let half = length / 2;
+        let angle = sign * 2.0 * PI / length as f64;
+        let twiddles: Vec<(f64, f64)> = (0..half).map(|k| ((angle * k as f64).cos(), (angle * k as f64).sin())).collect();
+        let mut start = 0;
+        while start < n {
+            for (k, &(wr, wi)) in twiddles.iter().enumerate() {
+                let (a, b) = (start + k, start + k + half);
+                let tr = re[b] * wr - im[b] * wi;
+                let ti = re[b] * wi + im[b] * wr;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+            start += length;
+        }
+        length <<= 1;
+    }
+
+    if inverse {
+        for v in re.iter_mut().chain(im.iter_mut()) {
+            *v /= n as f64;
+        }
+    }
+}
+
+/// Convolves `input` with `impulse` by FFT overlap-add, the result is cut to the length of the input.
+fn convolve(input: &[f64], impulse: &[f64]) -> Vec<f64> {
+    let block = impulse.len().next_power_of_two();
+    let size = block * 2;
+    let mut impulse_re = impulse.to_vec();
+    impulse_re.resize(size, 0.0);
+    let mut impulse_im = vec![0.0; size];
+    fft(&mut impulse_re, &mut impulse_im, false);
+
+    let mut output = vec![0.0; input.len()];
+    let mut start = 0;
+    while start < input.len() {
+        let end = (start + block).min(input.len());
+        let mut re = input[start..end].to_vec();
+        re.resize(size, 0.0);
+        let mut im = vec![0.0; size];
+        fft(&mut re, &mut im, false);
+        for i in 0..size {
+            let (r, m) = (re[i], im[i]);
+            re[i] = r * impulse_re[i] - m * impulse_im[i];
+            im[i] = r * impulse_im[i] + m * impulse_re[i];
+        }
+        fft(&mut re, &mut im, true);
+        for (o, v) in output[start..].iter_mut().zip(re.iter()) {
+            *o += *v;
+        }
+        start += block;
+    }
+    output
+}
+
+fn process_reverb(lanes: &[&AutomationLane], buffer: &mut [f32], impulse: &AudioSample, mix: f64) {
+    if impulse.data.is_empty() {
+        return;
+    }
+    for channel in 0..2 {
+        // Mono impulse responses are used for both channels
+        let impulse_channel = channel.min(impulse.channels - 1);
+        let impulse: Vec<f64> = impulse.data.chunks(impulse.channels).map(|f| f64::from(f[impulse_channel])).collect();
+        let dry: Vec<f64> = buffer.chunks(2).map(|f| f64::from(f[channel])).collect();
+        let wet = convolve(&dry, &impulse);
+        let mix_lane = parameter_lane(lanes, "mix", 0);
+        for (frame, (samples, (d, w))) in buffer.chunks_mut(2).zip(dry.iter().zip(wet.iter())).enumerate() {
+            let mix = mix_lane.map_or(mix, |l| l.value_at(frame as u64));
+            samples[channel] = (d * (1.0 - mix) + w * mix) as f32;
+        }
+    }
+}
+
+/// Applies an effect chain in order to a complete interleaved stereo render, following the automation lanes of its effects.
+pub fn process_effects(effects: &[Effect], lanes: &[AutomationLane], buffer: &mut [f32], sample_rate: u64) {
+    for (index, effect) in effects.iter().enumerate() {
+        let effect_lanes: Vec<&AutomationLane> = lanes.iter()
+            .filter(|l| match l.target {
+                AutomationTarget::Effect { effect, .. } => effect == index,
+                _ => false,
+            })
+            .collect();
+        match *effect {
+            Effect::EQ { ref bands } => process_eq(bands, &effect_lanes, buffer, sample_rate),
+            Effect::Compressor { threshold, ratio, attack, release, makeup } => process_compressor(&effect_lanes, buffer, sample_rate, threshold, ratio, attack, release, makeup),
+            Effect::Delay { time_left, time_right, feedback, mix } => process_delay(&effect_lanes, buffer, sample_rate, time_left, time_right, feedback, mix),
+            Effect::Reverb { ref impulse, mix } => process_reverb(&effect_lanes, buffer, impulse, mix),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tomlparser::toml;
+
+    const SAMPLE_RATE: u64 = 1000;
+
+    fn effect(settings: &str) -> Result<Effect, Error> {
+        let effect: TOMLEffect = toml::from_str(settings).unwrap();
+        generate_effect(&effect, &ResourcePaths { directories: Vec::new() }, SAMPLE_RATE)
+    }
+
+    /// An interleaved stereo buffer with a single click at the first frame.
+    fn click(frames: usize) -> Vec<f32> {
+        let mut buffer = vec![0.0; frames * 2];
+        buffer[0] = 1.0;
+        buffer[1] = 1.0;
+        buffer
+    }
+
+    #[test]
+    fn generate_effect_applies_defaults() {
+        match effect("effecttype = 'delay'\ntime = 0.25").unwrap() {
+            Effect::Delay { time_left, time_right, feedback, mix } => assert_eq!((time_left, time_right, feedback, mix), (0.25, 0.25, 0.3, 0.3)),
+            _ => panic!("Not a delay"),
+        }
+        match effect("effecttype = 'eq'\n[[band]]\nshape = 'lowpass'\nfrequency = 100.0").unwrap() {
+            Effect::EQ { ref bands } => {
+                assert_eq!(bands.len(), 1);
+                assert_eq!(bands[0].shape, EQShape::LowPass);
+                assert_eq!((bands[0].gain, bands[0].q), (0.0, 0.5f64.sqrt()));
+            }
+            _ => panic!("Not an EQ"),
+        }
+    }
+
+    #[test]
+    fn generate_effect_rejects_invalid_settings() {
+        let error = |settings: &str| effect(settings).err().unwrap().message().to_string();
+        assert!(error("effecttype = 'flanger'").starts_with("Not a valid effecttype: 'flanger'"));
+        assert_eq!(error("effecttype = 'eq'"), "EQ effect requires band");
+        assert!(error("effecttype = 'eq'\n[[band]]\nshape = 'notch'\nfrequency = 100.0").starts_with("Not a valid EQ band shape: 'notch'"));
+        assert_eq!(error("effecttype = 'eq'\n[[band]]\nshape = 'peak'\nfrequency = 500.0"), "EQ band frequency must be between 0 and half the sample rate");
+        assert_eq!(error("effecttype = 'compressor'\nratio = 0.5"), "Compressor ratio must be at least 1");
+        assert_eq!(error("effecttype = 'delay'\ntime_left = 0.1"), "Delay effect requires time or time_right");
+        assert_eq!(error("effecttype = 'delay'\ntime = 0.1\nfeedback = 1.0"), "Delay feedback must be at least 0 and below 1");
+        assert_eq!(error("effecttype = 'delay'\ntime = 0.1\nmix = 1.5"), "Effect mix must be between 0 and 1");
+        assert_eq!(error("effecttype = 'reverb'"), "Reverb effect requires impulse");
+    }
+
+    #[test]
+    fn tail_adds_up_the_effects() {
+        let delay = Effect::Delay { time_left: 0.1, time_right: 0.2, feedback: 0.0, mix: 0.5 };
+        let impulse = AudioSample { channels: 2, sample_rate: 1000.0, data: vec![0.0; 100] };
+        let reverb = Effect::Reverb { impulse, mix: 0.5 };
+        assert_eq!(tail(&[delay, reverb]), 250_000.0);
+
+        // Echoes fed back at half the level are 60 dB down after about 10 repetitions
+        let delay = Effect::Delay { time_left: 0.1, time_right: 0.1, feedback: 0.5, mix: 0.5 };
+        assert!((tail(&[delay]) - 1_096_578.4).abs() < 1.0);
+    }
+
+    #[test]
+    fn delay_repeats_the_input() {
+        let mut buffer = click(8);
+        process_effects(&[Effect::Delay { time_left: 0.002, time_right: 0.003, feedback: 0.5, mix: 0.5 }], &[], &mut buffer, SAMPLE_RATE);
+        let left: Vec<f32> = buffer.iter().step_by(2).cloned().collect();
+        let right: Vec<f32> = buffer.iter().skip(1).step_by(2).cloned().collect();
+        assert_eq!(left, vec![0.5, 0.0, 0.5, 0.0, 0.25, 0.0, 0.125, 0.0]);
+        assert_eq!(right, vec![0.5, 0.0, 0.0, 0.5, 0.0, 0.0, 0.25, 0.0]);
+    }
+
+    #[test]
+    fn delay_follows_its_mix_lane() {
+        let lane = AutomationLane {
+            target: AutomationTarget::Effect { effect: 0, band: 0, parameter: "mix".to_string() },
+            curve: AutomationCurve::Step,
+            points: vec![(0, 0.0)],
+        };
+        let mut buffer = click(4);
+        process_effects(&[Effect::Delay { time_left: 0.002, time_right: 0.002, feedback: 0.0, mix: 0.5 }], &[lane], &mut buffer, SAMPLE_RATE);
+        assert_eq!(buffer, click(4));
+    }
+
+    #[test]
+    fn flat_peak_band_keeps_the_input() {
+        let band = EQBand { shape: EQShape::Peak, frequency: 100.0, gain: 0.0, q: 1.0 };
+        let mut buffer: Vec<f32> = (0..64).map(|i| (i as f32 * 0.3).sin()).collect();
+        let input = buffer.clone();
+        process_effects(&[Effect::EQ { bands: vec![band] }], &[], &mut buffer, SAMPLE_RATE);
+        for (output, input) in buffer.iter().zip(input.iter()) {
+            assert!((output - input).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn lowpass_passes_constant_signals() {
+        let band = EQBand { shape: EQShape::LowPass, frequency: 50.0, gain: 0.0, q: 0.5f64.sqrt() };
+        let mut buffer = vec![0.5; 2000];
+        process_effects(&[Effect::EQ { bands: vec![band] }], &[], &mut buffer, SAMPLE_RATE);
+        assert!((buffer[1998] - 0.5).abs() < 1e-4);
+        assert!((buffer[1999] - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn compressor_reduces_loud_signals() {
+        let mut buffer = vec![1.0; 2000];
+        let compressor = Effect::Compressor { threshold: -20.0, ratio: 4.0, attack: 0.0, release: 0.1, makeup: 0.0 };
+        process_effects(&[compressor], &[], &mut buffer, SAMPLE_RATE);
+        // 20 dB above the threshold are reduced to 5 dB
+        let expected = 10f32.powf(-15.0 / 20.0);
+        assert!((buffer[1999] - expected).abs() < 1e-6, "{}", buffer[1999]);
+
+        let mut buffer = vec![0.01; 2000];
+        let compressor = Effect::Compressor { threshold: -20.0, ratio: 4.0, attack: 0.0, release: 0.1, makeup: 6.0 };
+        process_effects(&[compressor], &[], &mut buffer, SAMPLE_RATE);
+        assert!((buffer[1999] - 0.01 * 10f32.powf(6.0 / 20.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn convolve_matches_direct_convolution() {
+        let input: Vec<f64> = (0..37).map(|i| ((i * 7) % 11) as f64 - 5.0).collect();
+        let impulse = vec![0.5, -0.25, 0.125, 1.0, 0.0];
+        let output = convolve(&input, &impulse);
+        assert_eq!(output.len(), input.len());
+        for (n, value) in output.iter().enumerate() {
+            let expected: f64 = impulse.iter().enumerate().filter(|&(k, _)| k <= n).map(|(k, h)| h * input[n - k]).sum();
+            assert!((value - expected).abs() < 1e-9, "{} != {} at {}", value, expected, n);
+        }
+    }
+
+    #[test]
+    fn resample_interpolates_linearly() {
+        let sample = AudioSample { channels: 1, sample_rate: 2000.0, data: vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0] };
+        let resampled = resample(sample, 4000);
+        assert_eq!(resampled.sample_rate, 4000.0);
+        assert_eq!(resampled.data, vec![0.0, 0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 3.5, 4.0, 4.5, 5.0, 2.5]);
+    }
+}
@@ -0,0 +1,44 @@
+use std::error;
+use std::fmt;
+
+use types::*;
+
+/// Returns an Error with a message formatted like `format!` from the current function.
+macro_rules! fail {
+    ($($arg:tt)+) => {
+        return Err(::types::Error::new(format!($($arg)+)))
+    };
+}
+
+/// Like `assert!`, but returns the Error from the current function instead of panicking.
+macro_rules! ensure {
+    ($condition:expr, $($arg:tt)+) => {
+        if !$condition {
+            fail!($($arg)+);
+        }
+    };
+}
+
+impl Error {
+    pub fn new<S: Into<String>>(message: S) -> Error {
+        Error { message: message.into() }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<FluidError> for Error {
+    fn from(error: FluidError) -> Error {
+        Error::new(error.to_string())
+    }
+}
@@ -0,0 +1,281 @@
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::ptr;
+
+use std::os::raw::c_int;
+use std::os::raw::c_double;
+use std::os::raw::c_void;
+use std::os::raw::c_char;
+
+use fluidsynth_bindgen::*;
+
+use types::*;
+
+impl fmt::Display for FluidError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FluidError::Allocation(object) => write!(f, "Could not create FluidSynth {}", object),
+            FluidError::Setting(ref name) => write!(f, "Could not set FluidSynth setting '{}'", name),
+            FluidError::SoundFont(ref file) => write!(f, "Could not load SoundFont '{}'", file),
+            FluidError::Call(function) => write!(f, "FluidSynth function {} failed", function),
+        }
+    }
+}
+
+impl Error for FluidError {
+    fn description(&self) -> &str {
+        "FluidSynth error"
+    }
+}
+
+fn check(result: c_int, function: &'static str) -> FluidResult<()> {
+    if result == FLUID_OK { Ok(()) } else { Err(FluidError::Call(function)) }
+}
+
+// FluidSynth objects may move to another thread, but must not be used by several threads at once, so they are not Sync
+unsafe impl Send for FluidSettings {}
+unsafe impl Send for FluidSynth {}
+unsafe impl Send for FluidSequencer {}
+
+impl FluidSettings {
+    pub fn new() -> FluidResult<FluidSettings> {
+        let settings = unsafe { new_fluid_settings() };
+        if settings.is_null() {
+            return Err(FluidError::Allocation("settings"));
+        }
+        Ok(FluidSettings { settings })
+    }
+
+    /// Sets an integer setting. FluidSynth clamps some values, so the value is read back.
+    pub fn set_int(&self, name: &str, value: c_int) -> FluidResult<()> {
+        let c_name = CString::new(name).unwrap();
+        let mut set_value: c_int = 0;
+        unsafe {
+            if fluid_settings_setint(self.settings, c_name.as_ptr(), value) != 1 {
+                return Err(FluidError::Setting(name.to_string()));
+            }
+            fluid_settings_getint(self.settings, c_name.as_ptr(), &mut set_value as *mut c_int);
+        }
+        if set_value != value {
+            return Err(FluidError::Setting(name.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn set_num(&self, name: &str, value: c_double) -> FluidResult<()> {
+        let c_name = CString::new(name).unwrap();
+        let mut set_value: c_double = 0.0;
+        unsafe {
+            if fluid_settings_setnum(self.settings, c_name.as_ptr(), value) != 1 {
+                return Err(FluidError::Setting(name.to_string()));
+            }
+            fluid_settings_getnum(self.settings, c_name.as_ptr(), &mut set_value as *mut c_double);
+        }
+        if set_value != value {
+            return Err(FluidError::Setting(name.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn set_str(&self, name: &str, value: &str) -> FluidResult<()> {
+        let c_name = CString::new(name).unwrap();
+        let c_value = CString::new(value).unwrap();
+        if unsafe { fluid_settings_setstr(self.settings, c_name.as_ptr(), c_value.as_ptr()) } != 1 {
+            return Err(FluidError::Setting(name.to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FluidSettings {
+    fn drop(&mut self) {
+        trace!("Dropping FluidSynth settings");
+        unsafe { delete_fluid_settings(self.settings); }
+    }
+}
+
+impl FluidSynth {
+    /// Creates a synth, most settings are only read now.
+    pub fn new(settings: FluidSettings) -> FluidResult<FluidSynth> {
+        let synth = unsafe { new_fluid_synth(settings.settings) };
+        if synth.is_null() {
+            return Err(FluidError::Allocation("synth"));
+        }
+        Ok(FluidSynth { synth, _settings: settings })
+    }
+
+    pub fn gain(&self) -> f32 {
+        unsafe { fluid_synth_get_gain(self.synth) }
+    }
+
+    pub fn set_gain(&self, gain: f32) {
+        unsafe { fluid_synth_set_gain(self.synth, gain); }
+    }
+
+    pub fn set_reverb(&self, reverb: &FluidSynthesizerReverb) {
+        unsafe {
+            fluid_synth_set_reverb_on(self.synth, reverb.enabled as c_int);
+            fluid_synth_set_reverb(self.synth, reverb.room_size, reverb.damping, reverb.width, reverb.level);
+        }
+    }
+
+    pub fn set_chorus(&self, chorus: &FluidSynthesizerChorus) {
+        unsafe {
+            fluid_synth_set_chorus_on(self.synth, chorus.enabled as c_int);
+            fluid_synth_set_chorus(self.synth, chorus.voices, chorus.level, chorus.speed, chorus.depth, chorus.modulation);
+        }
+    }
+
+    pub fn system_reset(&self) -> FluidResult<()> {
+        check(unsafe { fluid_synth_system_reset(self.synth) }, "fluid_synth_system_reset")
+    }
+
+    /// Loads a SoundFont with its banks shifted by `offset` and returns its ID.
+    pub fn load_soundfont(&self, file: &str, offset: i32) -> FluidResult<u32> {
+        let c_file = CString::new(file).unwrap();
+        let id = unsafe { fluid_synth_sfload(self.synth, c_file.as_ptr(), 0) };
+        if id == FLUID_FAILED {
+            return Err(FluidError::SoundFont(file.to_string()));
+        }
+        check(unsafe { fluid_synth_set_bank_offset(self.synth, id, offset) }, "fluid_synth_set_bank_offset")?;
+        Ok(id as u32)
+    }
+
+    pub fn cc(&self, channel: u8, control: u8, value: u8) -> FluidResult<()> {
+        check(unsafe { fluid_synth_cc(self.synth, c_int::from(channel), c_int::from(control), c_int::from(value)) }, "fluid_synth_cc")
+    }
+
+    pub fn program_select(&self, channel: u8, soundfont: u32, bank: u32, program: u8) -> FluidResult<()> {
+        check(unsafe { fluid_synth_program_select(self.synth, c_int::from(channel), soundfont, bank, u32::from(program)) }, "fluid_synth_program_select")
+    }
+
+    /// Returns the SoundFont ID, bank and program of a channel.
+    pub fn program(&self, channel: u8) -> (u32, u32, u32) {
+        let mut sfont_id: u32 = 0;
+        let mut bank_num: u32 = 0;
+        let mut preset_num: u32 = 0;
+        unsafe { fluid_synth_get_program(self.synth, c_int::from(channel), &mut sfont_id as *mut u32, &mut bank_num as *mut u32, &mut preset_num as *mut u32); }
+        (sfont_id, bank_num, preset_num)
+    }
+
+    pub fn set_drum_channel(&self, channel: u8, drum: bool) -> FluidResult<()> {
+        // CHANNEL_TYPE_DRUM and CHANNEL_TYPE_MELODIC
+        let channel_type = if drum { 1 } else { 0 };
+        check(unsafe { fluid_synth_set_channel_type(self.synth, c_int::from(channel), channel_type) }, "fluid_synth_set_channel_type")
+    }
+
+    /// Creates a key tuning from 128 pitches in cents as `program` in tuning bank 0.
+    pub fn create_key_tuning(&self, program: usize, pitches: &[f64]) -> FluidResult<()> {
+        assert_eq!(pitches.len(), 128);
+        let name = CString::new(format!("tuning{}", program)).unwrap();
+        check(unsafe { fluid_synth_activate_key_tuning(self.synth, 0, program as c_int, name.as_ptr(), pitches.as_ptr(), 0) }, "fluid_synth_activate_key_tuning")
+    }
+
+    pub fn activate_tuning(&self, channel: u8, program: usize) -> FluidResult<()> {
+        check(unsafe { fluid_synth_activate_tuning(self.synth, c_int::from(channel), 0, program as c_int, 1) }, "fluid_synth_activate_tuning")
+    }
+
+    /// Sends a SysEx message without F0 and F7, returns whether FluidSynth handled it.
+    pub fn sysex(&self, data: &[u8]) -> bool {
+        let mut handled: c_int = 0;
+        let result = unsafe {
+            fluid_synth_sysex(self.synth, data.as_ptr() as *const c_char, data.len() as c_int,
+                              ptr::null_mut(), ptr::null_mut(), &mut handled as *mut c_int, 0)
+        };
+        result == FLUID_OK && handled != 0
+    }
+
+    /// Renders interleaved stereo frames into `buffer`.
+    pub fn write_interleaved(&self, buffer: &mut [f32]) -> FluidResult<()> {
+        let frames = buffer.len() / 2;
+        let out = buffer.as_mut_ptr() as *mut c_void;
+        check(unsafe { fluid_synth_write_float(self.synth, frames as c_int, out, 0, 2, out, 1, 2) }, "fluid_synth_write_float")
+    }
+}
+
+impl Drop for FluidSynth {
+    fn drop(&mut self) {
+        // The settings are dropped after this
+        trace!("Dropping FluidSynth synth");
+        unsafe { delete_fluid_synth(self.synth); }
+    }
+}
+
+impl FluidSequencer {
+    /// Creates a sequencer with one tick per millisecond, sending to `synth`.
+    pub fn new(synth: &FluidSynth) -> FluidResult<FluidSequencer> {
+        let sequencer = unsafe { new_fluid_sequencer2(0) };
+        if sequencer.is_null() {
+            return Err(FluidError::Allocation("sequencer"));
+        }
+        let mut res = FluidSequencer { sequencer, destination: 0 };
+        res.destination = unsafe { fluid_sequencer_register_fluidsynth(sequencer, synth.synth) };
+        if res.destination < 0 {
+            return Err(FluidError::Call("fluid_sequencer_register_fluidsynth"));
+        }
+        debug!("Sequencer seq ID {}", res.destination);
+        unsafe {
+            if fluid_sequencer_get_use_system_timer(sequencer) != 0 {
+                return Err(FluidError::Call("new_fluid_sequencer2"));
+            }
+            // Unfortunately, sequencer precision is limited to 1ms/tick by FluidSynth
+            fluid_sequencer_set_time_scale(sequencer, 1000.0);
+            if fluid_sequencer_get_time_scale(sequencer) != 1000.0 {
+                return Err(FluidError::Call("fluid_sequencer_set_time_scale"));
+            }
+        }
+        Ok(res)
+    }
+
+    /// Sends all events up to `time_ms` to the synth.
+    pub fn process(&self, time_ms: u32) {
+        unsafe { fluid_sequencer_process(self.sequencer, time_ms); }
+    }
+
+    /// Schedules a channel message at `time_ms`. Only notes, controllers, channel pressure and pitch bends are supported
+    /// by the sequencer, other messages are not sent.
+    pub fn send_at(&self, time_ms: u32, message: &MIDIMessage) -> FluidResult<()> {
+        unsafe {
+            let event = new_fluid_event();
+            if event.is_null() {
+                return Err(FluidError::Allocation("event"));
+            }
+            fluid_event_set_source(event, -1);
+            fluid_event_set_dest(event, self.destination);
+            let supported = match *message {
+                MIDIMessage::NoteOn { channel, key, velocity } => {
+                    fluid_event_noteon(event, c_int::from(channel), i16::from(key), i16::from(velocity));
+                    true
+                }
+                MIDIMessage::NoteOff { channel, key } => {
+                    fluid_event_noteoff(event, c_int::from(channel), i16::from(key));
+                    true
+                }
+                MIDIMessage::ControlChange { channel, control, value } => {
+                    fluid_event_control_change(event, c_int::from(channel), i16::from(control), i16::from(value));
+                    true
+                }
+                MIDIMessage::ChannelPressure { channel, pressure } => {
+                    fluid_event_channel_pressure(event, c_int::from(channel), i16::from(pressure));
+                    true
+                }
+                MIDIMessage::PitchBend { channel, value } => {
+                    fluid_event_pitch_bend(event, c_int::from(channel), c_int::from(value));
+                    true
+                }
+                _ => false,
+            };
+            let result = if supported { fluid_sequencer_send_at(self.sequencer, event, time_ms, 1) } else { FLUID_OK };
+            delete_fluid_event(event);
+            check(result, "fluid_sequencer_send_at")
+        }
+    }
+}
+
+impl Drop for FluidSequencer {
+    fn drop(&mut self) {
+        trace!("Dropping FluidSynth sequencer");
+        unsafe { delete_fluid_sequencer(self.sequencer); }
+    }
+}
@@ -1,233 +1,467 @@
-use std::ffi::CString;
-use std::path::PathBuf;
-
 use std::os::raw::c_int;
-use std::os::raw::c_double;
-
-use fluidsynth_bindgen::*;
 
 use types::*;
-use gm_instruments;
-
-impl FluidSynthesizer {
-    pub fn new() -> FluidSynthesizer {
-        unsafe {
-            FluidSynthesizer {
-                settings: new_fluid_settings(),
-                synthesizer: None,
-                sequencer: None,
-                synthesizer_seq_id: 0,
-                gain: 1.0,
-                last_event: 0,
-                used_channels: 0,
-                mapping: Vec::new(),
+use mapping;
+use midiparser;
+use synthesizer;
+use synthesizer::Synthesizer;
+
+// FluidSynth's built-in defaults, used for everything not given in the TOML
+const DEFAULT_REVERB: FluidSynthesizerReverb = FluidSynthesizerReverb {
+    enabled: true,
+    room_size: 0.2,
+    damping: 0.0,
+    width: 0.5,
+    level: 0.9,
+};
+
+const DEFAULT_CHORUS: FluidSynthesizerChorus = FluidSynthesizerChorus {
+    enabled: true,
+    voices: 3,
+    level: 2.0,
+    speed: 0.3,
+    depth: 8.0,
+    modulation: 0,
+};
+
+fn apply_settings(fluid_settings: &FluidSettings, settings: &TOMLSynth) -> Result<(), Error> {
+    if let Some(ref synth_settings) = settings.setting {
+        for setting in synth_settings {
+            check_one_value_in_synth_setting(setting)?;
+            if let Some(set) = setting.value_i {
+                debug!("Setting '{}' to {}", setting.name, set);
+                fluid_settings.set_int(&setting.name, set)?;
+            }
+            if let Some(set) = setting.value_f {
+                debug!("Setting '{}' to {}", setting.name, set);
+                fluid_settings.set_num(&setting.name, set)?;
+            }
+            if let Some(ref set) = setting.value_s {
+                debug!("Setting '{}' to '{}'", setting.name, set);
+                fluid_settings.set_str(&setting.name, set)?;
             }
         }
     }
+    Ok(())
+}
 
-    pub fn settings_setint(&self, name: &str, value: c_int) {
-        let name: CString = CString::new(name).unwrap();
-        unsafe { assert_eq!(fluid_settings_setint(self.settings, name.as_ptr(), value), 1); }
-
-        // Verify
-        let mut set_value: i32 = 0;
-        unsafe { fluid_settings_getint(self.settings, name.as_ptr(), &mut set_value as *mut i32); }
-        assert_eq!(set_value, value);
+impl FluidSynthesizer {
+    /// Creates the FluidSynth settings, synth and sequencer. The settings of the TOML are applied first, as FluidSynth
+    /// reads most of them only when the synth is created.
+    pub fn new(settings: &TOMLSynth, sample_rate: u64) -> Result<FluidSynthesizer, Error> {
+        let fluid_settings = FluidSettings::new()?;
+        fluid_settings.set_num("synth.sample-rate", sample_rate as f64)?;
+
+        // Every destination gets its own channel, FluidSynth allocates them in blocks of 16
+        let destinations: usize = settings.mapping.values().map(|m| m.condition.len() * m.destination.len()).sum();
+        ensure!(destinations <= mapping::MAX_DESTINATIONS, "A synth can have at most {} destinations", mapping::MAX_DESTINATIONS);
+        if destinations > 16 {
+            fluid_settings.set_int("synth.midi-channels", ((destinations + 15) / 16 * 16) as c_int)?;
+        }
+        apply_settings(&fluid_settings, settings)?;
+
+        let synth = FluidSynth::new(fluid_settings)?;
+        let sequencer = FluidSequencer::new(&synth)?;
+        Ok(FluidSynthesizer {
+            initial_synth_gain: synth.gain(),
+            sequencer,
+            synth,
+            sample_rate,
+            rendered_samples: 0,
+            last_event: 0,
+            router: ChannelRouter::new(),
+            reverb: None,
+            chorus: None,
+            configured_changes: Vec::new(),
+            timed_changes: Vec::new(),
+            next_timed_change: 0,
+            sysex: synthesizer::DEFAULT_SYSEX,
+            unhandled_sysex: Vec::new(),
+        })
     }
 
-    pub fn settings_setfloat(&self, name: &str, value: c_double) {
-        let name: CString = CString::new(name).unwrap();
-        unsafe { assert_eq!(fluid_settings_setnum(self.settings, name.as_ptr(), value), 1); }
+    pub fn set_router(&mut self, router: ChannelRouter) {
+        self.router = router;
+    }
 
-        // Verify
-        let mut set_value: f64 = 0.0;
-        unsafe { fluid_settings_getnum(self.settings, name.as_ptr(), &mut set_value as *mut f64); }
-        assert_eq!(set_value, value);
+    pub fn set_timed_changes(&mut self, mut timed_changes: Vec<FluidSynthesizerTimedChange>) {
+        timed_changes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        self.configured_changes = timed_changes.clone();
+        self.timed_changes = timed_changes;
+        self.next_timed_change = 0;
     }
 
-    pub fn settings_setstring(&self, name: &str, value: String) {
-        let name: CString = CString::new(name).unwrap();
-        let value: CString = CString::new(value).unwrap();
-        unsafe { assert_eq!(fluid_settings_setstr(self.settings, name.as_ptr(), value.as_ptr()), 1); }
+    pub fn set_reverb(&self, reverb: &FluidSynthesizerReverb) {
+        debug!("Setting reverb: {:?}", reverb);
+        self.synth.set_reverb(reverb);
+    }
 
-        // Verify TODO
+    pub fn set_chorus(&self, chorus: &FluidSynthesizerChorus) {
+        debug!("Setting chorus: {:?}", chorus);
+        self.synth.set_chorus(chorus);
     }
 
-    pub fn set_gain(&mut self, gain: f32) {
-        self.gain = gain;
+    pub fn add_timed_change(&mut self, timed_change: FluidSynthesizerTimedChange) {
+        // Keep the order of changes with equal times
+        let index = self.timed_changes.iter().position(|c| c.time > timed_change.time).unwrap_or(self.timed_changes.len());
+        self.timed_changes.insert(index, timed_change);
     }
 
-    pub fn set_mapping(&mut self, mapping: Vec<FluidSynthesizerMapping>) {
-        self.mapping = mapping;
+    fn apply_timed_changes(&mut self, time: f64) -> FluidResult<()> {
+        while self.next_timed_change < self.timed_changes.len() && self.timed_changes[self.next_timed_change].time <= time {
+            let mut unhandled = None;
+            match self.timed_changes[self.next_timed_change].change {
+                FluidSynthesizerParameterChange::Reverb(ref reverb) => self.set_reverb(reverb),
+                FluidSynthesizerParameterChange::Chorus(ref chorus) => self.set_chorus(chorus),
+                FluidSynthesizerParameterChange::SysEx { ref data, reset } => {
+                    if !self.send_sysex(data) {
+                        unhandled = Some(data.clone());
+                    }
+                    if reset {
+                        self.restore_destinations()?;
+                    }
+                }
+                FluidSynthesizerParameterChange::SystemReset => {
+                    debug!("Resetting synthesizer");
+                    self.synth.system_reset()?;
+                    self.restore_destinations()?;
+                }
+                FluidSynthesizerParameterChange::MasterVolume(volume) => {
+                    debug!("Setting master volume to {}", volume);
+                    self.synth.set_gain(self.initial_synth_gain * volume);
+                }
+                FluidSynthesizerParameterChange::DrumChannels { ref channels, drum } => {
+                    for channel in channels {
+                        self.set_drum_channel(*channel, drum)?;
+                    }
+                }
+            }
+            if let Some(data) = unhandled {
+                self.unhandled_sysex.push(data);
+            }
+            self.next_timed_change += 1;
+        }
+        Ok(())
     }
 
-    pub fn build(&mut self) {
-        unsafe {
-            self.synthesizer = Some(new_fluid_synth(self.settings));
-            self.sequencer = Some(new_fluid_sequencer2(0));
-            self.synthesizer_seq_id = fluid_sequencer_register_fluidsynth(self.sequencer.unwrap(), self.synthesizer.unwrap());
-            debug!("Sequencer seq ID {}", self.synthesizer_seq_id);
-            assert_eq!(fluid_sequencer_get_use_system_timer(self.sequencer.unwrap()), 0);
+    /// Forwards a MIDI message at `time` (in microseconds) to all mapped destination channels.
+    fn forward_event(&mut self, time: f64, message: &MIDIMessage) -> FluidResult<()> {
+        let time_ms = (time / 1000.0) as u32;
+        self.last_event = time_ms as i32;
+        if let MIDIMessage::SysEx { ref data } = *message {
+            self.schedule_sysex(time, data);
+            return Ok(());
+        }
 
-            // Unfortunately, sequencer precision is limited to 1ms/tick by FluidSynth
-            fluid_sequencer_set_time_scale(self.sequencer.unwrap(), 1000.0);
-            assert_eq!(fluid_sequencer_get_time_scale(self.sequencer.unwrap()), 1000.0);
+        for routed in self.router.route(message) {
+            match routed {
+                MIDIMessage::NoteOn { .. } | MIDIMessage::NoteOff { .. } | MIDIMessage::ControlChange { .. } |
+                MIDIMessage::ChannelPressure { .. } | MIDIMessage::PitchBend { .. } => {
+                    self.sequencer.send_at(time_ms, &routed)?;
+                }
+                MIDIMessage::KeyPressure { .. } => {
+                    trace!("Ignoring polyphonic key pressure, not supported by the FluidSynth sequencer");
+                }
+                MIDIMessage::ProgramChange { .. } | MIDIMessage::SysEx { .. } | MIDIMessage::Marker { .. } => {}
+            }
         }
+        Ok(())
     }
 
-    pub fn load_soundfont(&self, file: &str, offset: i32) {
-        assert!(self.synthesizer.is_some());
-        let file: CString = CString::new(file).unwrap();
+    pub fn load_soundfont(&self, file: &str, offset: i32) -> FluidResult<()> {
         info!("Loading SoundFont...");
-        let result = unsafe { fluid_synth_sfload(self.synthesizer.unwrap(), file.as_ptr(), 0) };
-        assert_ne!(result, FLUID_FAILED, "Could not load SoundFont");
-        info!("SoundFont loaded. Got ID {}", result);
-        debug!("Setting bank offset");
-        unsafe { fluid_synth_set_bank_offset(self.synthesizer.unwrap(), result, offset); }
+        let id = self.synth.load_soundfont(file, offset)?;
+        info!("SoundFont loaded. Got ID {}", id);
+        Ok(())
     }
 
-    pub fn debug_programs(&self) {
-        for channel in 0..self.used_channels {
-            let mut sfont_id: u32 = 0;
-            let mut bank_num: u32 = 0;
-            let mut preset_num: u32 = 0;
-            unsafe { fluid_synth_get_program(self.synthesizer.unwrap(), channel as i32, &mut sfont_id as *mut u32, &mut bank_num as *mut u32, &mut preset_num as *mut u32); }
-            debug!("Channel {}: {} - {}:{}", channel, sfont_id, bank_num, preset_num);
+    pub fn synth_cc(&self, channel: u8, control: u8, value: u8) -> FluidResult<()> {
+        self.synth.cc(channel, control, value)
+    }
+
+    /// Sets the channel fine tuning (RPN 1) to `cents`, which must be within +-100.
+    pub fn set_fine_tuning(&self, channel: u8, cents: f64) -> FluidResult<()> {
+        // +100 cents is one step above the largest 14 bit value
+        let value = (8192.0 + cents * 8192.0 / 100.0).round().min(16383.0) as u16;
+        self.synth_cc(channel, 101, 0)?;
+        self.synth_cc(channel, 100, 1)?;
+        self.synth_cc(channel, 6, (value >> 7) as u8)?;
+        self.synth_cc(channel, 38, (value & 0x7F) as u8)?;
+        // Reset to the null RPN, so later data entry from the MIDI file does not change the tuning
+        self.synth_cc(channel, 101, 127)?;
+        self.synth_cc(channel, 100, 127)
+    }
+
+    /// Selects the destination's program and applies its channel strip and constant controllers.
+    pub fn setup_destination(&self, destination: &SynthesizerDestination, controller_rules: &[ControllerRule]) -> FluidResult<()> {
+        let channel = destination.channel;
+        self.synth.program_select(channel, destination.soundfont, destination.bank, destination.program)?;
+
+        for (control, value) in mapping::initial_controllers(destination, controller_rules) {
+            self.synth_cc(channel, control, value)?;
+        }
+        if destination.detune != 0.0 {
+            self.set_fine_tuning(channel, destination.detune)?;
+        }
+        if let Some(tuning) = destination.tuning {
+            self.synth.activate_tuning(channel, tuning)?;
         }
+        Ok(())
     }
-}
 
-impl Drop for FluidSynthesizer {
-    fn drop(&mut self) {
-        unsafe {
-            trace!("Dropping FluidSynthesizer");
+    /// Creates a key tuning from 128 pitches in cents as `program` in tuning bank 0.
+    pub fn create_tuning(&self, program: usize, pitches: &[f64]) -> Result<(), Error> {
+        ensure!(program < 128, "A synth can have at most 128 tunings");
+        Ok(self.synth.create_key_tuning(program, pitches)?)
+    }
 
-            if let Some(sequencer) = self.sequencer {
-                trace!(" => Dropping sequencer");
-                delete_fluid_sequencer(sequencer);
+    /// Restores all destinations, e.g. after a system reset cleared their programs.
+    fn restore_destinations(&self) -> FluidResult<()> {
+        for mapping in &self.router.mapping {
+            for destination in &mapping.destinations {
+                self.setup_destination(destination, &mapping.controller_rules)?;
             }
+        }
+        Ok(())
+    }
+
+    /// Switches a destination channel between its own preset and the drum kit with the same number.
+    fn set_drum_channel(&self, channel: u8, drum: bool) -> FluidResult<()> {
+        let destination = self.router.destination(channel);
+        let bank = if drum { 128 } else { destination.bank };
+        self.synth.set_drum_channel(channel, drum)?;
+        self.synth.program_select(channel, destination.soundfont, bank, destination.program)
+    }
 
-            if let Some(synthesizer) = self.synthesizer {
-                trace!(" => Dropping synthesizer");
-                delete_fluid_synth(synthesizer);
+    /// Sends a SysEx message without F0 and F7 to FluidSynth, returns whether it was handled.
+    fn send_sysex(&self, data: &[u8]) -> bool {
+        self.synth.sysex(data)
+    }
+
+    /// Channel-addressed messages refer to channels of the MIDI file, not to the destination channels of FluidSynth.
+    /// Drum parts are therefore always translated through the router, other part parameters cannot be passed on.
+    fn schedule_sysex(&mut self, time: f64, data: &[u8]) {
+        let kind = midiparser::classify_sysex(data);
+        let change = match (self.sysex.handling_of(kind), kind) {
+            (SysExHandling::Ignore, _) => {
+                debug!("Ignoring SysEx {:?}", kind);
+                return;
+            }
+            (_, SysExKind::DrumPart { channel, drum }) => FluidSynthesizerParameterChange::DrumChannels {
+                channels: self.router.matching_destinations(channel).iter().map(|d| d.channel).collect(),
+                drum,
+            },
+            (_, SysExKind::Part { .. }) => {
+                self.unhandled_sysex.push(data.to_vec());
+                return;
             }
+            (SysExHandling::Honour, _) => FluidSynthesizerParameterChange::SysEx {
+                data: data.to_vec(),
+                reset: kind.is_reset(),
+            },
+            (SysExHandling::Translate, SysExKind::MasterVolume(volume)) => FluidSynthesizerParameterChange::MasterVolume(f32::from(volume) / 16383.0),
+            (SysExHandling::Translate, SysExKind::Other) => unreachable!(),
+            (SysExHandling::Translate, _) => FluidSynthesizerParameterChange::SystemReset,
+        };
+        self.add_timed_change(FluidSynthesizerTimedChange { time, change });
+    }
 
-            trace!(" => Dropping settings");
-            delete_fluid_settings(self.settings);
+    pub fn debug_programs(&self) {
+        for channel in 0..self.router.used_channels {
+            let (sfont_id, bank_num, preset_num) = self.synth.program(channel as u8);
+            debug!("Channel {}: {} - {}:{}", channel, sfont_id, bank_num, preset_num);
         }
     }
 }
 
-fn assert_one_value_in_synth_setting(setting: &TOMLSynthSetting) {
+fn check_one_value_in_synth_setting(setting: &TOMLSynthSetting) -> Result<(), Error> {
     let mut i = 0;
     if setting.value_i.is_some() { i += 1 };
     if setting.value_f.is_some() { i += 1 };
     if setting.value_s.is_some() { i += 1 };
-    assert_eq!(i, 1, "Expecting exactly one value");
+    ensure!(i == 1, "Expecting exactly one value");
+    Ok(())
 }
 
-fn assert_one_value_in_condition(condition: &TOMLCondition) {
-    let mut i = 0;
-    if condition.channel.is_some() { i += 1 };
-    if condition.program.is_some() { i += 1 };
-    assert_eq!(i, 1, "Expecting exactly one value");
+fn validate_reverb(reverb: &FluidSynthesizerReverb) -> Result<(), Error> {
+    ensure!(reverb.room_size >= 0.0 && reverb.room_size <= 1.2, "Reverb room_size must be between 0.0 and 1.2");
+    ensure!(reverb.damping >= 0.0 && reverb.damping <= 1.0, "Reverb damping must be between 0.0 and 1.0");
+    ensure!(reverb.width >= 0.0 && reverb.width <= 100.0, "Reverb width must be between 0.0 and 100.0");
+    ensure!(reverb.level >= 0.0 && reverb.level <= 1.0, "Reverb level must be between 0.0 and 1.0");
+    Ok(())
 }
 
-fn generate_single_mapping(synthsettings: &TOMLSynth, synth: &mut FluidSynthesizer, condition: &TOMLCondition, destinations: &Vec<TOMLDestination>) -> FluidSynthesizerMapping {
-    assert_one_value_in_condition(condition);
-    let channel = condition.channel;
-    let program = if condition.program.is_some() {
-        Some(gm_instruments::program_nr_of(condition.program.as_ref().unwrap()))
-    } else {
-        None
-    };
-
-    let mut fluid_destinations = Vec::new();
-    for destination in destinations {
-        let destination_bank = if destination.bank.is_some() {
-            destination.bank.unwrap()
-        } else {
-            0
-        };
-        let destination_program = if destination.program.is_some() {
-            gm_instruments::program_nr_of(destination.program.as_ref().unwrap())
-        } else {
-            destination.program_nr.expect("Destination must contain program or program_nr") as u8
-        };
-        unsafe { fluid_synth_program_select(synth.synthesizer.unwrap(), synth.used_channels as i32, destination.soundfont, destination_bank, destination_program as u32); }
+fn validate_chorus(chorus: &FluidSynthesizerChorus) -> Result<(), Error> {
+    ensure!(chorus.voices >= 0 && chorus.voices <= 99, "Chorus voices must be between 0 and 99");
+    ensure!(chorus.level >= 0.0 && chorus.level <= 10.0, "Chorus level must be between 0.0 and 10.0");
+    ensure!(chorus.speed >= 0.29 && chorus.speed <= 5.0, "Chorus speed must be between 0.29 and 5.0");
+    ensure!(chorus.depth >= 0.0 && chorus.depth <= 21.0, "Chorus depth must be between 0.0 and 21.0");
+    Ok(())
+}
 
-        fluid_destinations.push(synth.used_channels);
-        synth.used_channels += 1;
+fn chorus_modulation_of(name: &str) -> Result<i32, Error> {
+    match name {
+        // FLUID_CHORUS_MOD_SINE and FLUID_CHORUS_MOD_TRIANGLE
+        "sine" => Ok(0),
+        "triangle" => Ok(1),
+        _ => fail!("Not a valid chorus type: '{}'. Use 'sine' or 'triangle'.", name),
     }
+}
 
-    let res = FluidSynthesizerMapping {
-        condition: FluidSynthesizerCondition {
-            channel,
-            program,
-        },
-        destinations: fluid_destinations,
+fn generate_reverb(settings: &TOMLSynthReverb, timed_changes: &mut Vec<FluidSynthesizerTimedChange>) -> Result<FluidSynthesizerReverb, Error> {
+    let initial = FluidSynthesizerReverb {
+        enabled: settings.enabled.unwrap_or(DEFAULT_REVERB.enabled),
+        room_size: settings.room_size.unwrap_or(DEFAULT_REVERB.room_size),
+        damping: settings.damping.unwrap_or(DEFAULT_REVERB.damping),
+        width: settings.width.unwrap_or(DEFAULT_REVERB.width),
+        level: settings.level.unwrap_or(DEFAULT_REVERB.level),
     };
-    res
+    validate_reverb(&initial)?;
+
+    if let Some(ref changes) = settings.change {
+        let mut changes: Vec<&TOMLSynthReverbChange> = changes.iter().collect();
+        changes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+
+        // Changes only list the parameters they touch, everything else is kept
+        let mut current = initial;
+        for change in changes {
+            ensure!(change.time >= 0.0, "Reverb change time must not be negative");
+            current = FluidSynthesizerReverb {
+                enabled: change.enabled.unwrap_or(current.enabled),
+                room_size: change.room_size.unwrap_or(current.room_size),
+                damping: change.damping.unwrap_or(current.damping),
+                width: change.width.unwrap_or(current.width),
+                level: change.level.unwrap_or(current.level),
+            };
+            validate_reverb(&current)?;
+            timed_changes.push(FluidSynthesizerTimedChange {
+                time: change.time * 1_000_000.0,
+                change: FluidSynthesizerParameterChange::Reverb(current),
+            });
+        }
+    }
+    Ok(initial)
 }
 
-fn generate_mapping(synthsettings: &TOMLSynth, synth: &mut FluidSynthesizer) -> Vec<FluidSynthesizerMapping> {
-    let mut res = Vec::new();
-    for (_id, mapping) in &synthsettings.mapping {
-        for condition in &mapping.condition {
-            res.push(generate_single_mapping(synthsettings, synth, &condition, &mapping.destination));
+fn generate_chorus(settings: &TOMLSynthChorus, timed_changes: &mut Vec<FluidSynthesizerTimedChange>) -> Result<FluidSynthesizerChorus, Error> {
+    let initial = FluidSynthesizerChorus {
+        enabled: settings.enabled.unwrap_or(DEFAULT_CHORUS.enabled),
+        voices: settings.voices.unwrap_or(DEFAULT_CHORUS.voices),
+        level: settings.level.unwrap_or(DEFAULT_CHORUS.level),
+        speed: settings.speed.unwrap_or(DEFAULT_CHORUS.speed),
+        depth: settings.depth.unwrap_or(DEFAULT_CHORUS.depth),
+        modulation: settings.modulation.as_ref().map_or(Ok(DEFAULT_CHORUS.modulation), |m| chorus_modulation_of(m))?,
+    };
+    validate_chorus(&initial)?;
+
+    if let Some(ref changes) = settings.change {
+        let mut changes: Vec<&TOMLSynthChorusChange> = changes.iter().collect();
+        changes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+
+        let mut current = initial;
+        for change in changes {
+            ensure!(change.time >= 0.0, "Chorus change time must not be negative");
+            current = FluidSynthesizerChorus {
+                enabled: change.enabled.unwrap_or(current.enabled),
+                voices: change.voices.unwrap_or(current.voices),
+                level: change.level.unwrap_or(current.level),
+                speed: change.speed.unwrap_or(current.speed),
+                depth: change.depth.unwrap_or(current.depth),
+                modulation: change.modulation.as_ref().map_or(Ok(current.modulation), |m| chorus_modulation_of(m))?,
+            };
+            validate_chorus(&current)?;
+            timed_changes.push(FluidSynthesizerTimedChange {
+                time: change.time * 1_000_000.0,
+                change: FluidSynthesizerParameterChange::Chorus(current),
+            });
         }
     }
-    res
+    Ok(initial)
 }
 
-pub fn generate_fluid_synthesizers(settings: &TOMLRenderSettings, resources: &PathBuf) -> Vec<FluidSynthesizer> {
-    let mut res = Vec::new();
-    for (id, synthsettings) in &settings.synth {
-        if synthsettings.synthtype != "fluidsynth" {
-            continue;
-        }
-        info!("Building fluid synthesizer '{}'", id);
-        let mut synth = FluidSynthesizer::new();
-        synth.set_gain(synthsettings.gain);
-        if synthsettings.setting.is_some() {
-            for setting in synthsettings.setting.as_ref().unwrap() {
-                assert_one_value_in_synth_setting(setting);
-                if setting.value_i.is_some() {
-                    let set = *setting.value_i.as_ref().unwrap();
-                    debug!("Setting '{}' to {}", setting.name, set);
-                    synth.settings_setint(&setting.name, set);
-                }
-
-                if setting.value_f.is_some() {
-                    let set = *setting.value_f.as_ref().unwrap();
-                    debug!("Setting '{}' to {}", setting.name, set);
-                    synth.settings_setfloat(&setting.name, set);
-                }
-
-                if setting.value_s.is_some() {
-                    let set = setting.value_s.as_ref().unwrap().clone();
-                    debug!("Setting '{}' to '{}'", setting.name, set);
-                    synth.settings_setstring(&setting.name, set);
-                }
-            }
+impl Synthesizer for FluidSynthesizer {
+    fn configure(&mut self, settings: &TOMLSynth, _sample_rate: u64) -> Result<(), Error> {
+        // Sample rate and FluidSynth settings were applied by new
+        let mut timed_changes = Vec::new();
+        if let Some(ref reverb) = settings.reverb {
+            let reverb = generate_reverb(reverb, &mut timed_changes)?;
+            self.set_reverb(&reverb);
+            self.reverb = Some(reverb);
+        }
+        if let Some(ref chorus) = settings.chorus {
+            let chorus = generate_chorus(chorus, &mut timed_changes)?;
+            self.set_chorus(&chorus);
+            self.chorus = Some(chorus);
         }
+        self.set_timed_changes(timed_changes);
+        if let Some(ref sysex) = settings.sysex {
+            self.sysex = synthesizer::generate_sysex(sysex)?;
+        }
+        Ok(())
+    }
 
-        synth.build();
-        if synthsettings.soundfont.is_some() {
-            for soundfont in synthsettings.soundfont.as_ref().unwrap() {
-                let mut soundfont_file = resources.clone();
-                soundfont_file.push(&soundfont.file);
+    fn load_resources(&mut self, settings: &TOMLSynth, resources: &ResourcePaths) -> Result<(), Error> {
+        if let Some(ref soundfonts) = settings.soundfont {
+            for soundfont in soundfonts {
+                let soundfont_file = resources.find(&soundfont.file);
                 let soundfont_file = soundfont_file.to_str().unwrap();
                 info!("Loading soundfont '{}' with offset {}", soundfont_file, soundfont.offset);
-                synth.load_soundfont(&soundfont_file, soundfont.offset);
+                self.load_soundfont(&soundfont_file, soundfont.offset)?;
             }
         }
 
-        let mapping = generate_mapping(synthsettings, &mut synth);
-        synth.set_mapping(mapping);
-        synth.debug_programs();
+        let router = mapping::generate_router(settings, resources)?;
+        for destination in router.mapping.iter().flat_map(|m| m.destinations.iter()) {
+            ensure!(destination.instrument.is_none(), "Destination instrument requires synthtype 'sfz' or 'audio'");
+            ensure!(destination.soundfont != 0, "Destinations of synthtype 'fluidsynth' must contain soundfont");
+        }
+        for (program, pitches) in router.tunings.iter().enumerate() {
+            self.create_tuning(program, pitches)?;
+        }
+        self.set_router(router);
+        self.restore_destinations()?;
+        self.debug_programs();
+        Ok(())
+    }
+
+    fn schedule_event(&mut self, time: f64, message: &MIDIMessage) -> Result<(), Error> {
+        Ok(self.forward_event(time, message)?)
+    }
+
+    fn render_block(&mut self, buffer: &mut [f32]) -> Result<(), Error> {
+        let frames = buffer.len() / 2;
+        let time = self.rendered_samples as f64 * 1_000_000.0 / self.sample_rate as f64;
+        self.sequencer.process((time / 1000.0) as u32);
+        self.apply_timed_changes(time)?;
+
+        self.synth.write_interleaved(buffer)?;
+        self.rendered_samples += frames as u64;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        debug!("Resetting FluidSynth synthesizer");
+        // The sequencer cannot go back in time, so scheduled events are dropped with it
+        self.sequencer = FluidSequencer::new(&self.synth)?;
+        self.synth.system_reset()?;
+        if let Some(reverb) = self.reverb {
+            self.set_reverb(&reverb);
+        }
+        if let Some(chorus) = self.chorus {
+            self.set_chorus(&chorus);
+        }
+        self.restore_destinations()?;
+
+        self.timed_changes = self.configured_changes.clone();
+        self.next_timed_change = 0;
+        self.rendered_samples = 0;
+        self.last_event = 0;
+        self.router.reset();
+        self.unhandled_sysex.clear();
+        Ok(())
+    }
 
-        res.push(synth);
+    fn finish(&mut self) {
+        synthesizer::report_unhandled_sysex("FluidSynth", &self.unhandled_sysex);
     }
-    res
 }
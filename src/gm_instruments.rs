@@ -1,15 +1,22 @@
-pub fn program_nr_of(name: &str) -> u8 {
+use types::*;
+use progress;
+
+pub fn program_nr_of(name: &str) -> Result<u8, Error> {
     let index = GM_INSTRUMENTS.iter().position(|&r| r == name);
     if index.is_some() {
-        index.unwrap() as u8
+        Ok(index.unwrap() as u8)
     } else {
-        panic!("Not a valid MIDI instrument: '{}'. Use --list-instruments to get a complete list of supported names.", name);
+        fail!("Not a valid MIDI instrument: '{}'. Use --list-instruments to get a complete list of supported names.", name);
     }
 }
 
-pub fn list_instruments() {
+pub fn list_instruments(report: ReportMode) {
     for (id, instrument) in GM_INSTRUMENTS.iter().enumerate() {
-        println!("{}: {}", id, instrument);
+        if report == ReportMode::Json {
+            progress::emit("instrument", &[("program", id.to_string()), ("name", progress::json_string(instrument))]);
+        } else {
+            println!("{}: {}", id, instrument);
+        }
     }
 }
 
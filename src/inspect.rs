@@ -0,0 +1,142 @@
+extern crate time;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use types::*;
+use midiparser;
+use gm_instruments;
+use progress;
+
+fn format_time(time: f64) -> String {
+    let seconds = time / 1_000_000.0;
+    format!("{}:{:06.3}", (seconds / 60.0).floor(), seconds % 60.0)
+}
+
+fn format_position(tempo_map: &TempoMap, pulse: u64) -> String {
+    let (bar, beat) = tempo_map.pulse_to_position(pulse);
+    format!("{}:{:.2}", bar, beat)
+}
+
+/// A position as a JSON object with bar, beat and time in seconds.
+fn json_position(tempo_map: &TempoMap, pulse: u64) -> String {
+    let (bar, beat) = tempo_map.pulse_to_position(pulse);
+    progress::json_object(&[
+        ("bar", bar.to_string()),
+        ("beat", beat.to_string()),
+        ("time", (tempo_map.pulse_to_time(pulse) / 1_000_000.0).to_string()),
+    ])
+}
+
+fn json_numbers(numbers: &BTreeSet<u8>) -> String {
+    progress::json_array(&numbers.iter().map(|n| n.to_string()).collect::<Vec<String>>())
+}
+
+/// Prints the tempo map, the markers and which notes, programs and controllers every channel uses. With JSON reports
+/// all of it is emitted as one 'midi_file' event.
+pub fn inspect_midi_file(file: &PathBuf, report: ReportMode) -> Result<(), Error> {
+    let mut handler_data = midiparser::read_midi_file(file, Vec::new(), false)?;
+    handler_data.schedule_events()?;
+    let tempo_map = &handler_data.tempo_map;
+
+    let mut markers: Vec<(u64, &str)> = Vec::new();
+    let mut notes: BTreeMap<u8, (u32, u8, u8)> = BTreeMap::new();
+    let mut programs: BTreeMap<u8, BTreeSet<u8>> = BTreeMap::new();
+    let mut controllers: BTreeMap<u8, BTreeSet<u8>> = BTreeMap::new();
+    let mut sysex = 0;
+    for event in &handler_data.events {
+        match event.message {
+            MIDIMessage::Marker { ref text } => markers.push((event.pulse, text)),
+            MIDIMessage::NoteOn { channel, key, .. } => {
+                let entry = notes.entry(channel).or_insert((0, key, key));
+                *entry = (entry.0 + 1, entry.1.min(key), entry.2.max(key));
+            }
+            MIDIMessage::ProgramChange { channel, program } => {
+                programs.entry(channel).or_insert_with(BTreeSet::new).insert(program);
+            }
+            MIDIMessage::ControlChange { channel, control, .. } => {
+                controllers.entry(channel).or_insert_with(BTreeSet::new).insert(control);
+            }
+            MIDIMessage::SysEx { .. } => sysex += 1,
+            _ => {}
+        }
+    }
+    let channels: BTreeSet<u8> = notes.keys().chain(programs.keys()).chain(controllers.keys()).cloned().collect();
+    let bpm = |change: &MIDITempoChange| 60_000_000.0 / (change.us_per_pulse * f64::from(tempo_map.pulses_per_quarter_note));
+
+    if report == ReportMode::Json {
+        let tempo_changes: Vec<String> = tempo_map.tempo_changes.iter()
+            .map(|c| progress::json_object(&[("position", json_position(tempo_map, c.pulse)), ("bpm", bpm(c).to_string())]))
+            .collect();
+        let time_signatures: Vec<String> = tempo_map.time_signatures.iter()
+            .map(|s| progress::json_object(&[
+                ("position", json_position(tempo_map, s.pulse)),
+                ("numerator", s.numerator.to_string()),
+                ("denominator", s.denominator.to_string()),
+            ]))
+            .collect();
+        let markers: Vec<String> = markers.iter()
+            .map(|&(pulse, text)| progress::json_object(&[("position", json_position(tempo_map, pulse)), ("text", progress::json_string(text))]))
+            .collect();
+        let channels: Vec<String> = channels.iter()
+            .map(|channel| progress::json_object(&[
+                ("channel", channel.to_string()),
+                ("notes", notes.get(channel).map_or("null".to_string(), |&(count, lowest, highest)| progress::json_object(&[
+                    ("count", count.to_string()),
+                    ("lowest", lowest.to_string()),
+                    ("highest", highest.to_string()),
+                ]))),
+                ("programs", programs.get(channel).map_or("[]".to_string(), json_numbers)),
+                ("controllers", controllers.get(channel).map_or("[]".to_string(), json_numbers)),
+            ]))
+            .collect();
+        progress::emit("midi_file", &[
+            ("file", progress::json_string(file.to_str().unwrap())),
+            ("pulses_per_quarter_note", tempo_map.pulses_per_quarter_note.to_string()),
+            ("length", (handler_data.max_time() / 1_000_000.0).to_string()),
+            ("bars", tempo_map.pulse_to_position(handler_data.max_pulse).0.to_string()),
+            ("tempo_changes", progress::json_array(&tempo_changes)),
+            ("time_signatures", progress::json_array(&time_signatures)),
+            ("markers", progress::json_array(&markers)),
+            ("channels", progress::json_array(&channels)),
+            ("sysex", sysex.to_string()),
+        ]);
+        return Ok(());
+    }
+
+    println!("File: {}", file.to_str().unwrap());
+    println!("Pulses per quarter note: {}", tempo_map.pulses_per_quarter_note);
+    println!("Length: {} ({} bars)", time::Duration::microseconds(handler_data.max_time() as i64), tempo_map.pulse_to_position(handler_data.max_pulse).0);
+
+    println!("Tempo changes:");
+    for change in &tempo_map.tempo_changes {
+        println!("  {} ({}): {:.2} BPM", format_position(tempo_map, change.pulse), format_time(tempo_map.pulse_to_time(change.pulse)), bpm(change));
+    }
+    println!("Time signatures:");
+    for signature in &tempo_map.time_signatures {
+        println!("  {} ({}): {}/{}", format_position(tempo_map, signature.pulse), format_time(tempo_map.pulse_to_time(signature.pulse)), signature.numerator, signature.denominator);
+    }
+
+    println!("Markers:");
+    for &(pulse, text) in &markers {
+        println!("  {} ({}): {}", format_position(tempo_map, pulse), format_time(tempo_map.pulse_to_time(pulse)), text);
+    }
+
+    println!("Channels:");
+    for channel in channels {
+        println!("  Channel {}:", channel);
+        if let Some(&(count, lowest, highest)) = notes.get(&channel) {
+            println!("    Notes: {} from key {} to {}", count, lowest, highest);
+        }
+        if let Some(programs) = programs.get(&channel) {
+            let names: Vec<String> = programs.iter().map(|p| format!("{} ({})", p, gm_instruments::GM_INSTRUMENTS[*p as usize])).collect();
+            println!("    Programs: {}", names.join(", "));
+        }
+        if let Some(controllers) = controllers.get(&channel) {
+            let numbers: Vec<String> = controllers.iter().map(|c| c.to_string()).collect();
+            println!("    Controllers: {}", numbers.join(", "));
+        }
+    }
+    println!("SysEx messages: {}", sysex);
+    Ok(())
+}
@@ -0,0 +1,76 @@
+//! Renders MIDI files to WAV with FluidSynth, the built in synthesizers and effects, configured by TOML render settings.
+//!
+//! ```no_run
+//! extern crate musicrenderer_rust;
+//!
+//! use musicrenderer_rust::Render;
+//!
+//! fn main() -> Result<(), musicrenderer_rust::Error> {
+//!     let audio = Render::from_file("song.toml")?
+//!         .resource_directory("resources")
+//!         .render()?;
+//!     println!("{:.1} s, peak {:.2}", audio.duration(), audio.peak());
+//!     audio.write_wav_file("song.wav")
+//! }
+//! ```
+//!
+//! `Settings` reads render settings without rendering them yet, so values can be set like with the --set option of the
+//! command line tool:
+//!
+//! ```no_run
+//! # extern crate musicrenderer_rust;
+//! # use musicrenderer_rust::{Render, Settings};
+//! # fn main() -> Result<(), musicrenderer_rust::Error> {
+//! let settings = Settings::from_file("song.toml")?
+//!     .set("sample_rate", "44100")?
+//!     .set("synth.piano.gain", "0.5")?;
+//! Render::from_settings(&settings)?.render()?.write_wav_file("song_quiet.wav")
+//! # }
+//! ```
+
+#[macro_use]
+extern crate log;
+extern crate fluidsynth_bindgen;
+extern crate structopt;
+extern crate ghakuf;
+#[macro_use]
+extern crate structopt_derive;
+#[macro_use]
+extern crate serde_derive;
+
+#[macro_use]
+mod error;
+mod types;
+mod tomlparser;
+mod synthesizer;
+mod mapping;
+mod fluidsynth;
+mod fluidsynthesizer;
+mod sf2;
+mod sf2synthesizer;
+mod sfz;
+mod sfzsynthesizer;
+mod sampler;
+mod testsynthesizer;
+mod audiosynthesizer;
+mod midiparser;
+mod renderer;
+mod gm_instruments;
+mod tuning;
+mod audiofile;
+mod effects;
+mod automation;
+mod inspect;
+mod debugoutput;
+mod batch;
+mod manifest;
+mod watch;
+mod resources;
+mod stdio;
+mod loudness;
+mod progress;
+mod api;
+#[doc(hidden)]
+pub mod cli;
+
+pub use types::{Render, RenderedAudio, Settings, Error};
@@ -0,0 +1,119 @@
+use std::f64::consts::PI;
+
+use types::*;
+
+// ITU-R BS.1770: 400 ms blocks overlapping by 75 %
+const BLOCK_LENGTH: f64 = 0.4;
+const BLOCK_STEP: f64 = 0.1;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Biquad {
+        Biquad { b, a, x: [0.0; 2], y: [0.0; 2] }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[1] * self.y[0] - self.a[2] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+/// The K-weighting filters, a high shelf modelling the head followed by a high pass, for any sample rate.
+/// The analog prototypes are those of libebur128, which match the coefficients of BS.1770 at 48 kHz.
+fn k_weighting(sample_rate: u64) -> (Biquad, Biquad) {
+    let rate = sample_rate as f64;
+
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10.0f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new([(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+                            [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new([1.0, -2.0, 1.0], [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+    (shelf, high_pass)
+}
+
+fn block_loudness(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// Integrated loudness of interleaved stereo samples in LUFS after ITU-R BS.1770, or None if every block is below the
+/// absolute gate, e.g. for silence or outputs shorter than one block.
+pub fn integrated_loudness(samples: &[f32], sample_rate: u64) -> Option<f64> {
+    let mut filters = [k_weighting(sample_rate), k_weighting(sample_rate)];
+    let weighted: Vec<f64> = samples.chunks(2)
+        .map(|frame| {
+            let left = filters[0].1.process(filters[0].0.process(f64::from(frame[0])));
+            let right = filters[1].1.process(filters[1].0.process(f64::from(frame[1])));
+            left * left + right * right
+        })
+        .collect();
+
+    let block = (BLOCK_LENGTH * sample_rate as f64).round() as usize;
+    let step = (BLOCK_STEP * sample_rate as f64).round() as usize;
+    if weighted.len() < block {
+        return None;
+    }
+    // Mean square of both channels summed, both weighted with 1.0
+    let mut powers = Vec::new();
+    let mut offset = 0;
+    while offset + block <= weighted.len() {
+        powers.push(weighted[offset..offset + block].iter().sum::<f64>() / block as f64);
+        offset += step;
+    }
+
+    let absolute: Vec<f64> = powers.into_iter().filter(|p| *p > 0.0 && block_loudness(*p) > ABSOLUTE_GATE).collect();
+    if absolute.is_empty() {
+        return None;
+    }
+    let relative_gate = block_loudness(absolute.iter().sum::<f64>() / absolute.len() as f64) + RELATIVE_GATE;
+    let gated: Vec<f64> = absolute.into_iter().filter(|p| block_loudness(*p) > relative_gate).collect();
+    Some(block_loudness(gated.iter().sum::<f64>() / gated.len() as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(sample_rate: u64, seconds: f64, left: f64, right: f64) -> Vec<f32> {
+        let frames = (seconds * sample_rate as f64) as usize;
+        (0..frames)
+            .flat_map(|i| {
+                let value = (2.0 * PI * 1000.0 * i as f64 / sample_rate as f64).sin();
+                vec![(value * left) as f32, (value * right) as f32]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_scale_sine_in_one_channel_is_minus_3_01_lufs() {
+        // The reference of BS.1770: a 0 dBFS 1 kHz sine in one front channel reads -3.01 LKFS
+        for &sample_rate in &[44_100, 48_000, 96_000] {
+            let loudness = integrated_loudness(&sine(sample_rate, 5.0, 1.0, 0.0), sample_rate).unwrap();
+            assert!((loudness + 3.01).abs() < 0.05, "{} LUFS at {} Hz", loudness, sample_rate);
+        }
+    }
+
+    #[test]
+    fn both_channels_add_up() {
+        let loudness = integrated_loudness(&sine(48_000, 5.0, 0.5, 0.5), 48_000).unwrap();
+        assert!((loudness + 6.02).abs() < 0.05, "{} LUFS", loudness);
+    }
+
+    #[test]
+    fn silence_and_short_outputs_have_no_loudness() {
+        assert_eq!(integrated_loudness(&vec![0.0; 48_000 * 2], 48_000), None);
+        assert_eq!(integrated_loudness(&sine(48_000, 0.3, 1.0, 1.0), 48_000), None);
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+use types::*;
+use resources;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// SoundFonts and samples can be large, files are hashed in chunks of this size
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// FNV-1a, which unlike the hasher of the standard library stays the same across Rust versions.
+fn hash_bytes(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |h, b| (h ^ u64::from(*b)).wrapping_mul(FNV_PRIME))
+}
+
+fn hash_str(hash: u64, text: &str) -> u64 {
+    // The terminator keeps ("ab", "c") and ("a", "bc") apart
+    hash_bytes(hash_bytes(hash, text.as_bytes()), &[0])
+}
+
+fn hash_file(hash: u64, file: &PathBuf) -> u64 {
+    let mut hash = hash_str(hash, file.to_str().unwrap());
+    let mut reader = match File::open(file) {
+        Ok(f) => BufReader::with_capacity(HASH_CHUNK_SIZE, f),
+        // Rendering reports the missing file
+        Err(_) => return hash,
+    };
+    loop {
+        let length = match reader.fill_buf() {
+            Ok(&[]) => return hash,
+            Ok(chunk) => {
+                hash = hash_bytes(hash, chunk);
+                chunk.len()
+            }
+            // Also reported by rendering, the hash covers what could be read
+            Err(_) => return hash,
+        };
+        reader.consume(length);
+    }
+}
+
+/// Hashes everything a render depends on: the tool version, the settings, the MIDI file and all resource files.
+pub fn input_hash(render_settings: &TOMLRenderSettings, resources: &ResourcePaths) -> u64 {
+    let mut hash = hash_str(FNV_OFFSET_BASIS, env!("CARGO_PKG_VERSION"));
+    hash = hash_str(hash, &render_settings.source);
+    hash = hash_str(hash, &render_settings.input_file);
+    hash = hash_str(hash, &render_settings.output_file);
+    hash = hash_file(hash, &render_settings.input_path.join(&render_settings.input_file));
+    for file in resources::resource_files(render_settings, resources) {
+        hash = hash_file(hash, &file);
+    }
+    hash
+}
+
+fn manifest_file(render_settings: &TOMLRenderSettings) -> PathBuf {
+    render_settings.input_path.join(format!("{}.manifest", render_settings.output_file))
+}
+
+/// Returns true if the output exists and its manifest was written for the same inputs.
+pub fn is_up_to_date(render_settings: &TOMLRenderSettings, hash: u64) -> bool {
+    if !render_settings.input_path.join(&render_settings.output_file).exists() {
+        return false;
+    }
+    let mut contents = String::new();
+    if File::open(manifest_file(render_settings)).and_then(|mut f| f.read_to_string(&mut contents)).is_err() {
+        return false;
+    }
+    contents.lines().any(|l| l == format!("hash {:016x}", hash))
+}
+
+pub fn write_manifest(render_settings: &TOMLRenderSettings, hash: u64) {
+    let file = manifest_file(render_settings);
+    let contents = format!("version {}\nhash {:016x}\n", env!("CARGO_PKG_VERSION"), hash);
+    if File::create(&file).and_then(|mut f| f.write_all(contents.as_bytes())).is_err() {
+        warn!("Could not write manifest '{}', the next render will not be skipped", file.to_str().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+    use tomlparser;
+
+    /// An empty temporary directory for one test.
+    fn directory(name: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!("musicrenderer_{}_manifest_{}", process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    /// Settings in `directory` with one synth playing the SoundFont font.sf2, `gain` changes the settings source.
+    fn render_settings(directory: &Path, gain: f64) -> TOMLRenderSettings {
+        let contents = format!("input_file = 'song.mid'\noutput_file = 'song.wav'\n\
+                                [synth.piano]\nsynthtype = 'sf2'\ngain = {:?}\n[[synth.piano.soundfont]]\nfile = 'font.sf2'\noffset = 0\n\
+                                [synth.piano.mapping.all]\ncondition = [{{ channel = 0 }}]\ndestination = [{{}}]\n", gain);
+        let settings = tomlparser::read_settings_str(&contents, Path::new("<test>"), directory).unwrap();
+        tomlparser::render_settings_of(&settings).unwrap()
+    }
+
+    #[test]
+    fn input_hash_covers_settings_midi_and_resource_files() {
+        let directory = directory("hash");
+        let resources = ResourcePaths { directories: vec![directory.clone()] };
+        fs::write(directory.join("song.mid"), b"MThd").unwrap();
+        // Larger than a chunk, so the last chunk has to be hashed too
+        let mut soundfont = vec![0u8; HASH_CHUNK_SIZE * 2 + 10];
+        fs::write(directory.join("font.sf2"), &soundfont).unwrap();
+
+        let hash = input_hash(&render_settings(&directory, 1.0), &resources);
+        assert_eq!(input_hash(&render_settings(&directory, 1.0), &resources), hash);
+        assert_ne!(input_hash(&render_settings(&directory, 0.5), &resources), hash);
+
+        fs::write(directory.join("song.mid"), b"MThd\0").unwrap();
+        let midi_hash = input_hash(&render_settings(&directory, 1.0), &resources);
+        assert_ne!(midi_hash, hash);
+
+        *soundfont.last_mut().unwrap() = 1;
+        fs::write(directory.join("font.sf2"), &soundfont).unwrap();
+        let soundfont_hash = input_hash(&render_settings(&directory, 1.0), &resources);
+        assert_ne!(soundfont_hash, midi_hash);
+
+        fs::remove_file(directory.join("font.sf2")).unwrap();
+        assert_ne!(input_hash(&render_settings(&directory, 1.0), &resources), soundfont_hash);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn is_up_to_date_requires_output_and_manifest() {
+        let directory = directory("uptodate");
+        let render_settings = render_settings(&directory, 1.0);
+        write_manifest(&render_settings, 42);
+        assert!(!is_up_to_date(&render_settings, 42));
+
+        fs::write(directory.join("song.wav"), b"RIFF").unwrap();
+        assert!(is_up_to_date(&render_settings, 42));
+        assert!(!is_up_to_date(&render_settings, 43));
+
+        fs::remove_file(directory.join("song.wav.manifest")).unwrap();
+        assert!(!is_up_to_date(&render_settings, 42));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
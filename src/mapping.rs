@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+
+use types::*;
+use gm_instruments;
+use midiparser;
+use tuning;
+
+pub const CC_VOLUME: u8 = 7;
+pub const CC_PAN: u8 = 10;
+const MIDI_DEFAULT_VOLUME: f64 = 100.0;
+const MIDI_PAN_CENTER: f64 = 64.0;
+// Destination channels are MIDI channel numbers of one byte
+pub const MAX_DESTINATIONS: usize = 256;
+
+impl ChannelRouter {
+    pub fn new() -> ChannelRouter {
+        ChannelRouter {
+            mapping: Vec::new(),
+            tunings: Vec::new(),
+            used_channels: 0,
+            channel_programs: [0; 16],
+            active_notes: HashMap::new(),
+        }
+    }
+
+    /// Returns the index of a tuning with the given key pitches, adding it unless an identical one exists.
+    /// Destinations share tunings, as FluidSynth only has 128 tuning programs.
+    pub fn add_tuning(&mut self, pitches: Vec<f64>) -> usize {
+        match self.tunings.iter().position(|t| *t == pitches) {
+            Some(index) => index,
+            None => {
+                self.tunings.push(pitches);
+                self.tunings.len() - 1
+            }
+        }
+    }
+
+    /// Returns the destination using the given destination channel.
+    pub fn destination(&self, channel: u8) -> &SynthesizerDestination {
+        self.mapping.iter()
+            .flat_map(|m| m.destinations.iter())
+            .find(|d| d.channel == channel)
+            .unwrap()
+    }
+
+    pub fn matching_mappings(&self, channel: u8) -> Vec<&SynthesizerMapping> {
+        let program = self.channel_programs[channel as usize];
+        self.mapping.iter()
+            .filter(|m| m.condition.channel == Some(channel) || m.condition.program == Some(program))
+            .collect()
+    }
+
+    pub fn matching_destinations(&self, channel: u8) -> Vec<&SynthesizerDestination> {
+        self.matching_mappings(channel).into_iter()
+            .flat_map(|m| m.destinations.iter())
+            .collect()
+    }
+
+    /// Translates a message of the MIDI file into messages for the destination channels,
+    /// applying controller rules and channel strips. SysEx messages are left to the synthesizer.
+    pub fn route(&mut self, message: &MIDIMessage) -> Vec<MIDIMessage> {
+        let mut res = Vec::new();
+        match *message {
+            MIDIMessage::ProgramChange { channel, program } => {
+                // Destinations have fixed presets, program changes only affect the mapping
+                self.channel_programs[channel as usize] = program;
+            }
+            MIDIMessage::NoteOn { channel, key, velocity } => {
+                let mut sounding = Vec::new();
+                for destination in self.matching_destinations(channel) {
+                    if let Some(key) = transpose(destination, key) {
+                        res.push(MIDIMessage::NoteOn {
+                            channel: destination.channel,
+                            key,
+                            velocity: apply_velocity_curve(&destination.velocity_curve, velocity),
+                        });
+                        sounding.push((destination.channel, key));
+                    }
+                }
+                if !sounding.is_empty() {
+                    self.active_notes.insert((channel, key), sounding);
+                }
+            }
+            MIDIMessage::NoteOff { channel, key } => {
+                if let Some(sounding) = self.active_notes.remove(&(channel, key)) {
+                    for (channel, key) in sounding {
+                        res.push(MIDIMessage::NoteOff { channel, key });
+                    }
+                }
+            }
+            MIDIMessage::KeyPressure { channel, key, pressure } => {
+                for destination in self.matching_destinations(channel) {
+                    if let Some(key) = transpose(destination, key) {
+                        res.push(MIDIMessage::KeyPressure { channel: destination.channel, key, pressure });
+                    }
+                }
+            }
+            MIDIMessage::ControlChange { channel, control, value } => {
+                for mapping in self.matching_mappings(channel) {
+                    let value = match midiparser::apply_controller_rules(&mapping.controller_rules, control, value) {
+                        Some(value) => value,
+                        None => continue,
+                    };
+                    for destination in &mapping.destinations {
+                        res.push(MIDIMessage::ControlChange {
+                            channel: destination.channel,
+                            control,
+                            value: destination_cc_value(destination, control, value),
+                        });
+                    }
+                }
+            }
+            MIDIMessage::ChannelPressure { channel, pressure } => {
+                for destination in self.matching_destinations(channel) {
+                    res.push(MIDIMessage::ChannelPressure { channel: destination.channel, pressure });
+                }
+            }
+            MIDIMessage::PitchBend { channel, value } => {
+                for destination in self.matching_destinations(channel) {
+                    res.push(MIDIMessage::PitchBend { channel: destination.channel, value });
+                }
+            }
+            MIDIMessage::SysEx { .. } | MIDIMessage::Marker { .. } => {}
+        }
+        res
+    }
+
+    /// Forgets the program and note state of the MIDI file.
+    pub fn reset(&mut self) {
+        self.channel_programs = [0; 16];
+        self.active_notes.clear();
+    }
+}
+
+fn transpose(destination: &SynthesizerDestination, key: u8) -> Option<u8> {
+    let key = i32::from(key) + destination.transpose;
+    if key < 0 || key > 127 {
+        None
+    } else {
+        Some(key as u8)
+    }
+}
+
+/// Returns the controllers a destination channel starts with: its strip's volume and pan and all constant controllers.
+pub fn initial_controllers(destination: &SynthesizerDestination, controller_rules: &[ControllerRule]) -> Vec<(u8, u8)> {
+    let mut res = vec![
+        (CC_VOLUME, volume_to_cc(MIDI_DEFAULT_VOLUME * destination.volume)),
+        (CC_PAN, pan_to_cc(MIDI_PAN_CENTER, destination.pan)),
+    ];
+    // Constant controllers hold from the start, not only once the MIDI file sets them
+    for rule in controller_rules {
+        if let ControllerAction::Constant(value) = rule.action {
+            res.push((rule.control, destination_cc_value(destination, rule.control, value)));
+        }
+    }
+    res
+}
+
+pub fn volume_to_cc(value: f64) -> u8 {
+    value.round().max(0.0).min(127.0) as u8
+}
+
+pub fn pan_to_cc(value: f64, pan: f64) -> u8 {
+    (value + pan * (MIDI_PAN_CENTER - 1.0)).round().max(0.0).min(127.0) as u8
+}
+
+pub fn destination_cc_value(destination: &SynthesizerDestination, control: u8, value: u8) -> u8 {
+    match control {
+        CC_VOLUME => volume_to_cc(f64::from(value) * destination.volume),
+        CC_PAN => pan_to_cc(f64::from(value), destination.pan),
+        _ => value,
+    }
+}
+
+fn generate_controller_rules(rules: &Option<Vec<TOMLControllerRule>>) -> Result<Vec<ControllerRule>, Error> {
+    let mut res = Vec::new();
+    if let Some(ref rules) = *rules {
+        for rule in rules {
+            ensure!(rule.control <= 127, "Controller number must be between 0 and 127");
+            let action = match rule.action.as_str() {
+                "drop" => ControllerAction::Drop,
+                "clamp" | "rescale" => {
+                    let min = rule.min.unwrap_or(0);
+                    let max = rule.max.unwrap_or(127);
+                    ensure!(min <= max && max <= 127, "Controller min and max must satisfy 0 <= min <= max <= 127");
+                    if rule.action == "clamp" {
+                        ControllerAction::Clamp(min, max)
+                    } else {
+                        ControllerAction::Rescale(min, max)
+                    }
+                }
+                "constant" => {
+                    let value = rule.value.ok_or_else(|| Error::new("Constant controller rule requires value"))?;
+                    ensure!(value <= 127, "Controller value must be between 0 and 127");
+                    ControllerAction::Constant(value)
+                }
+                _ => fail!("Not a valid controller action: '{}'. Use 'drop', 'clamp', 'rescale' or 'constant'.", rule.action),
+            };
+            res.push(ControllerRule {
+                control: rule.control,
+                action,
+            });
+        }
+    }
+    Ok(res)
+}
+
+pub fn apply_velocity_curve(curve: &VelocityCurve, velocity: u8) -> u8 {
+    let input = f64::from(velocity) / 127.0;
+    let output = match *curve {
+        VelocityCurve::Linear => input,
+        VelocityCurve::Exponential(exponent) => input.powf(exponent),
+        VelocityCurve::Table(ref table) => {
+            // The table spans the whole input range with equally spaced points
+            let position = input * (table.len() - 1) as f64;
+            let index = position.floor() as usize;
+            if index + 1 >= table.len() {
+                table[table.len() - 1] / 127.0
+            } else {
+                let fraction = position - index as f64;
+                (table[index] + (table[index + 1] - table[index]) * fraction) / 127.0
+            }
+        }
+    };
+    // A velocity of 0 would turn the note on into a note off
+    (output * 127.0).round().max(1.0).min(127.0) as u8
+}
+
+fn generate_velocity_curve(destination: &TOMLDestination) -> Result<VelocityCurve, Error> {
+    let curve = destination.velocity_curve.as_ref().map_or("linear", |c| c.as_str());
+    Ok(match curve {
+        "linear" => VelocityCurve::Linear,
+        "exponential" => {
+            let exponent = destination.velocity_exponent.ok_or_else(|| Error::new("Exponential velocity curve requires velocity_exponent"))?;
+            ensure!(exponent > 0.0, "velocity_exponent must be greater than 0.0");
+            VelocityCurve::Exponential(exponent)
+        }
+        "table" => {
+            let table = destination.velocity_table.clone().ok_or_else(|| Error::new("Table velocity curve requires velocity_table"))?;
+            ensure!(table.len() >= 2, "velocity_table must contain at least two values");
+            ensure!(table.iter().all(|v| *v >= 0.0 && *v <= 127.0), "velocity_table values must be between 0 and 127");
+            VelocityCurve::Table(table)
+        }
+        _ => fail!("Not a valid velocity curve: '{}'. Use 'linear', 'exponential' or 'table'.", curve),
+    })
+}
+
+fn check_one_value_in_condition(condition: &TOMLCondition) -> Result<(), Error> {
+    let mut i = 0;
+    if condition.channel.is_some() { i += 1 };
+    if condition.program.is_some() { i += 1 };
+    ensure!(i == 1, "Expecting exactly one value");
+    Ok(())
+}
+
+fn generate_single_mapping(router: &mut ChannelRouter, condition: &TOMLCondition, destinations: &Vec<TOMLDestination>, controller_rules: Vec<ControllerRule>, synth_tuning: Option<usize>, resources: &ResourcePaths) -> Result<SynthesizerMapping, Error> {
+    check_one_value_in_condition(condition)?;
+    let channel = condition.channel;
+    let program = if condition.program.is_some() {
+        Some(gm_instruments::program_nr_of(condition.program.as_ref().unwrap())?)
+    } else {
+        None
+    };
+
+    let mut synth_destinations = Vec::new();
+    for destination in destinations {
+        let destination_bank = if destination.bank.is_some() {
+            destination.bank.unwrap()
+        } else {
+            0
+        };
+        let destination_program = if destination.program.is_some() {
+            gm_instruments::program_nr_of(destination.program.as_ref().unwrap())?
+        } else if destination.instrument.is_some() {
+            destination.program_nr.unwrap_or(0) as u8
+        } else {
+            destination.program_nr.ok_or_else(|| Error::new("Destination must contain program or program_nr"))? as u8
+        };
+        // 0 stands for no soundfont, backends using soundfonts check for it
+        let soundfont = match (destination.soundfont, &destination.instrument) {
+            (Some(_), &Some(_)) => fail!("Destination must not contain both soundfont and instrument"),
+            (soundfont, _) => soundfont.unwrap_or(0),
+        };
+        ensure!(router.used_channels < MAX_DESTINATIONS, "A synth can have at most {} destinations", MAX_DESTINATIONS);
+        let channel = router.used_channels as u8;
+        let volume = destination.volume.unwrap_or(1.0);
+        let pan = destination.pan.unwrap_or(0.0);
+        let transpose = destination.transpose.unwrap_or(0);
+        let detune = destination.detune.unwrap_or(0.0);
+        ensure!(volume >= 0.0 && volume <= 1.0, "Destination volume must be between 0.0 and 1.0");
+        ensure!(pan >= -1.0 && pan <= 1.0, "Destination pan must be between -1.0 and 1.0");
+        ensure!(transpose >= -127 && transpose <= 127, "Destination transpose must be between -127 and 127");
+        ensure!(detune >= -100.0 && detune <= 100.0, "Destination detune must be between -100.0 and 100.0 cents");
+
+        let tuning = match destination.tuning {
+            Some(ref tuning) => Some(router.add_tuning(tuning::generate_tuning(tuning, resources)?)),
+            None => synth_tuning,
+        };
+        synth_destinations.push(SynthesizerDestination {
+            channel,
+            soundfont,
+            bank: destination_bank,
+            program: destination_program,
+            instrument: destination.instrument.clone(),
+            volume,
+            pan,
+            transpose,
+            detune,
+            velocity_curve: generate_velocity_curve(destination)?,
+            tuning,
+        });
+        router.used_channels += 1;
+    }
+
+    let res = SynthesizerMapping {
+        condition: SynthesizerCondition {
+            channel,
+            program,
+        },
+        destinations: synth_destinations,
+        controller_rules,
+    };
+    Ok(res)
+}
+
+/// Builds the mapping of a synth, assigning one destination channel to every destination of every condition.
+/// Tunings are loaded relative to the resource directory.
+pub fn generate_router(synthsettings: &TOMLSynth, resources: &ResourcePaths) -> Result<ChannelRouter, Error> {
+    let mut router = ChannelRouter::new();
+
+    // A tuning of the synth applies to all destinations without their own tuning
+    let synth_tuning = match synthsettings.tuning {
+        Some(ref tuning) => Some(router.add_tuning(tuning::generate_tuning(tuning, resources)?)),
+        None => None,
+    };
+
+    for (_id, mapping) in &synthsettings.mapping {
+        for condition in &mapping.condition {
+            let controller_rules = generate_controller_rules(&mapping.controller)?;
+            let mapping = generate_single_mapping(&mut router, &condition, &mapping.destination, controller_rules, synth_tuning, resources)?;
+            router.mapping.push(mapping);
+        }
+    }
+    Ok(router)
+}
+
@@ -1,13 +1,19 @@
 use std::cmp;
+use std::env;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use ghakuf::messages::*;
 use ghakuf::reader::*;
 
 use types::*;
 
-impl MIDIHandlerData {
+impl TempoMap {
     pub fn pulse_to_time(&self, pulse: u64) -> f64 {
-        assert!(self.tempo_changes.len() > 0);
         let mut acc = 0.0;
         let mut last_change: u64 = 0;
         let mut current_uspp = 0.0;
@@ -29,8 +35,68 @@ impl MIDIHandlerData {
         acc
     }
 
-    pub fn max_time(&self) -> f64 {
-        self.pulse_to_time(self.max_pulse)
+    /// Converts a position in bars and beats, both starting at 1, to pulses using the time signatures.
+    /// Time signature changes are expected at the start of a bar.
+    pub fn position_to_pulse(&self, bar: u32, beat: f64) -> f64 {
+        assert!(bar >= 1 && beat >= 1.0, "Bars and beats start at 1");
+        let pulses_per_bar = |numerator: u8, denominator: u8| f64::from(self.pulses_per_quarter_note) * 4.0 * f64::from(numerator) / f64::from(denominator);
+
+        let mut bar_start = 0.0;
+        let mut current_bar = 1;
+        let (mut numerator, mut denominator) = (4, 4);
+        for signature in &self.time_signatures {
+            let bars = ((signature.pulse as f64 - bar_start) / pulses_per_bar(numerator, denominator)).round() as u32;
+            if current_bar + bars > bar {
+                break;
+            }
+            bar_start += f64::from(bars) * pulses_per_bar(numerator, denominator);
+            current_bar += bars;
+            numerator = signature.numerator;
+            denominator = signature.denominator;
+        }
+        bar_start + f64::from(bar - current_bar) * pulses_per_bar(numerator, denominator)
+            + (beat - 1.0) * f64::from(self.pulses_per_quarter_note) * 4.0 / f64::from(denominator)
+    }
+
+    /// Converts pulses to a position in bars and beats, both starting at 1.
+    pub fn pulse_to_position(&self, pulse: u64) -> (u32, f64) {
+        let pulses_per_bar = |numerator: u8, denominator: u8| f64::from(self.pulses_per_quarter_note) * 4.0 * f64::from(numerator) / f64::from(denominator);
+
+        let mut bar_start = 0.0;
+        let mut current_bar = 1;
+        let (mut numerator, mut denominator) = (4, 4);
+        for signature in self.time_signatures.iter().take_while(|s| s.pulse <= pulse) {
+            let bars = ((signature.pulse as f64 - bar_start) / pulses_per_bar(numerator, denominator)).round() as u32;
+            bar_start += f64::from(bars) * pulses_per_bar(numerator, denominator);
+            current_bar += bars;
+            numerator = signature.numerator;
+            denominator = signature.denominator;
+        }
+        let bars = ((pulse as f64 - bar_start) / pulses_per_bar(numerator, denominator)).floor();
+        let offset = pulse as f64 - bar_start - bars * pulses_per_bar(numerator, denominator);
+        (current_bar + bars as u32, offset / (f64::from(self.pulses_per_quarter_note) * 4.0 / f64::from(denominator)) + 1.0)
+    }
+
+    /// Converts a position in bars and beats to microseconds.
+    pub fn position_to_time(&self, bar: u32, beat: f64) -> f64 {
+        self.pulse_to_time(self.position_to_pulse(bar, beat).round() as u64)
+    }
+}
+
+impl MIDIParseData {
+    pub fn new(ignore_program_changes: bool) -> MIDIParseData {
+        MIDIParseData {
+            tempo_map: TempoMap {
+                pulses_per_quarter_note: 0,
+                tempo_changes: Vec::new(),
+                time_signatures: Vec::new(),
+            },
+            events: Vec::new(),
+            ignore_program_changes,
+            current_pulse: 0,
+            max_pulse: 0,
+            error: None,
+        }
     }
 
     pub fn add_delta_time(&mut self, delta_time: u32) {
@@ -41,6 +107,198 @@ impl MIDIHandlerData {
     pub fn reset_current_pulse(&mut self) {
         self.current_pulse = 0;
     }
+
+    /// Keeps the first error, it is returned once the reader is done.
+    pub fn add_error(&mut self, message: &str) {
+        if self.error.is_none() {
+            self.error = Some(Error::new(message));
+        }
+    }
+
+    pub fn add_event(&mut self, message: MIDIMessage) {
+        if let MIDIMessage::ProgramChange { .. } = message {
+            if self.ignore_program_changes {
+                trace!("Ignoring program change: {:?}", message);
+                return;
+            }
+        }
+        self.events.push(MIDIEvent {
+            pulse: self.current_pulse,
+            message,
+        });
+    }
+}
+
+impl MIDIHandlerData {
+    pub fn max_time(&self) -> f64 {
+        self.tempo_map.pulse_to_time(self.max_pulse)
+    }
+
+    pub fn schedule_events(&mut self) -> Result<(), Error> {
+        // Tracks are read one after another, sort_by_key is stable so events of a track stay in order
+        self.events.sort_by_key(|e| e.pulse);
+        // Tempo changes and time signatures of all tracks are known now
+        self.tempo_map.tempo_changes.sort_by_key(|t| t.pulse);
+        self.tempo_map.time_signatures.sort_by_key(|t| t.pulse);
+        for synth in &mut self.synthesizers {
+            synth.synthesizer.set_tempo_map(&self.tempo_map);
+        }
+
+        let times: Vec<f64> = self.events.iter().map(|e| self.tempo_map.pulse_to_time(e.pulse)).collect();
+        for (event, time) in self.events.iter().zip(times) {
+            for synth in &mut self.synthesizers {
+                synth.synthesizer.schedule_event(time, &event.message)?;
+            }
+        }
+        debug!("Scheduled {} events", self.events.len());
+        Ok(())
+    }
+}
+
+/// Parses a MIDI file into its tempo map and events, independent of any synthesizer.
+pub fn parse_midi_file(file: &PathBuf, ignore_program_changes: bool) -> Result<MIDIParseData, Error> {
+    let data = Arc::new(Mutex::new(MIDIParseData::new(ignore_program_changes)));
+    {
+        let handler = Box::new(MIDIHandler {
+            data: data.clone(),
+        });
+        let mut reader = Reader::new(
+            handler,
+            &file.to_str().unwrap(),
+        ).map_err(|e| Error::new(format!("Could not read MIDI file '{}': {}", file.to_str().unwrap(), e)))?;
+
+        info!("Parsing MIDI file '{}'", file.to_str().unwrap());
+        reader.read().map_err(|e| Error::new(format!("Not a valid MIDI file '{}': {}", file.to_str().unwrap(), e)))?;
+    }
+    // The reader dropped its handler, so this is the last reference
+    let mut parsed = Arc::try_unwrap(data).ok().expect("MIDI handler outlived its reader").into_inner().unwrap();
+    if let Some(error) = parsed.error.take() {
+        fail!("Not a valid MIDI file '{}': {}", file.to_str().unwrap(), error);
+    }
+    ensure!(!parsed.tempo_map.tempo_changes.is_empty(), "MIDI file '{}' contains no tempo", file.to_str().unwrap());
+    Ok(parsed)
+}
+
+/// Reads a MIDI file. Its events are sent to the synthesizers by schedule_events.
+pub fn read_midi_file(file: &PathBuf, synthesizers: Vec<SynthesizerInstance>, ignore_program_changes: bool) -> Result<MIDIHandlerData, Error> {
+    let parsed = parse_midi_file(file, ignore_program_changes)?;
+    Ok(MIDIHandlerData {
+        synthesizers,
+        tempo_map: parsed.tempo_map,
+        events: parsed.events,
+        max_pulse: parsed.max_pulse,
+    })
+}
+
+// Numbers the temporary files of this process
+static TEMPORARY_FILES: AtomicUsize = AtomicUsize::new(0);
+
+impl Drop for TemporaryFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Reads MIDI data from memory. ghakuf only reads files, so the data is written to a temporary file first.
+pub fn read_midi_data(data: &[u8], synthesizers: Vec<SynthesizerInstance>, ignore_program_changes: bool) -> Result<MIDIHandlerData, Error> {
+    let number = TEMPORARY_FILES.fetch_add(1, Ordering::SeqCst);
+    let file = TemporaryFile {
+        path: env::temp_dir().join(format!("musicrenderer_{}_{}.mid", process::id(), number)),
+    };
+    File::create(&file.path)
+        .and_then(|mut f| f.write_all(data))
+        .map_err(|_| Error::new(format!("Could not write temporary MIDI file '{}'", file.path.to_str().unwrap())))?;
+    read_midi_file(&file.path, synthesizers, ignore_program_changes)
+}
+
+/// Returns the value a controller change should be forwarded with, or None if it is dropped.
+pub fn apply_controller_rules(rules: &[ControllerRule], control: u8, value: u8) -> Option<u8> {
+    let mut value = value;
+    for rule in rules.iter().filter(|r| r.control == control) {
+        value = match rule.action {
+            ControllerAction::Drop => return None,
+            ControllerAction::Clamp(min, max) => cmp::min(cmp::max(value, min), max),
+            ControllerAction::Rescale(min, max) => min + ((u32::from(value) * u32::from(max - min) + 63) / 127) as u8,
+            ControllerAction::Constant(constant) => constant,
+        };
+    }
+    Some(value)
+}
+
+impl SysExKind {
+    pub fn is_reset(&self) -> bool {
+        match *self {
+            SysExKind::GMSystemOn | SysExKind::GMSystemOff | SysExKind::GSReset | SysExKind::XGSystemOn => true,
+            _ => false,
+        }
+    }
+}
+
+/// Returns the MIDI channel of a GS part, parts are numbered 10, 1 - 9, 11 - 16.
+fn gs_part_channel(part: u8) -> u8 {
+    match part {
+        0 => 9,
+        p if p <= 9 => p - 1,
+        p => p,
+    }
+}
+
+/// Classifies a SysEx message given without the leading F0 and the trailing F7.
+pub fn classify_sysex(data: &[u8]) -> SysExKind {
+    let len = data.len();
+    if len == 4 && data[0] == 0x7E && data[2] == 0x09 {
+        match data[3] {
+            0x01 | 0x03 => return SysExKind::GMSystemOn,
+            0x02 => return SysExKind::GMSystemOff,
+            _ => {}
+        }
+    }
+    if len == 6 && data[0] == 0x7F && data[2] == 0x04 && data[3] == 0x01 {
+        return SysExKind::MasterVolume((u16::from(data[5]) << 7) | u16::from(data[4]));
+    }
+    if len == 9 && data[0] == 0x41 && data[2] == 0x42 && data[3] == 0x12 && data[4] == 0x40 {
+        if data[5] == 0x00 && data[6] == 0x7F && data[7] == 0x00 {
+            return SysExKind::GSReset;
+        }
+        if data[5] & 0xF0 == 0x10 && data[6] == 0x15 {
+            return SysExKind::DrumPart { channel: gs_part_channel(data[5] & 0x0F), drum: data[7] != 0 };
+        }
+    }
+    // Patch part parameters, controller settings and scale tunings of a GS part
+    if len >= 9 && data[0] == 0x41 && data[2] == 0x42 && data[3] == 0x12 && data[4] == 0x40 {
+        match data[5] >> 4 {
+            1 | 2 | 4 => return SysExKind::Part { channel: gs_part_channel(data[5] & 0x0F) },
+            _ => {}
+        }
+    }
+    if len == 7 && data[0] == 0x43 && data[1] & 0xF0 == 0x10 && data[2] == 0x4C {
+        if data[3] == 0x00 && data[4] == 0x00 && data[5] == 0x7E && data[6] == 0x00 {
+            return SysExKind::XGSystemOn;
+        }
+        if data[3] == 0x08 && data[4] < 16 && data[5] == 0x07 {
+            return SysExKind::DrumPart { channel: data[4], drum: data[6] != 0 };
+        }
+    }
+    // XG multi part parameters
+    if len >= 7 && data[0] == 0x43 && data[1] & 0xF0 == 0x10 && data[2] == 0x4C && data[3] == 0x08 && data[4] < 16 {
+        return SysExKind::Part { channel: data[4] };
+    }
+    SysExKind::Other
+}
+
+fn to_midi_message(event: &MidiEvent) -> Option<MIDIMessage> {
+    match *event {
+        MidiEvent::NoteOn { ch, note, velocity } if velocity == 0 => Some(MIDIMessage::NoteOff { channel: ch, key: note }),
+        MidiEvent::NoteOn { ch, note, velocity } => Some(MIDIMessage::NoteOn { channel: ch, key: note, velocity }),
+        MidiEvent::NoteOff { ch, note, .. } => Some(MIDIMessage::NoteOff { channel: ch, key: note }),
+        MidiEvent::PolyphonicKeyPressure { ch, note, velocity } => Some(MIDIMessage::KeyPressure { channel: ch, key: note, pressure: velocity }),
+        MidiEvent::ControlChange { ch, control, data } => Some(MIDIMessage::ControlChange { channel: ch, control, value: data }),
+        MidiEvent::ProgramChange { ch, program } => Some(MIDIMessage::ProgramChange { channel: ch, program }),
+        MidiEvent::ChannelPressure { ch, pressure } => Some(MIDIMessage::ChannelPressure { channel: ch, pressure }),
+        // ghakuf reports pitch bends centered around 0, FluidSynth expects 0 - 16383
+        MidiEvent::PitchBendChange { ch, data } => Some(MIDIMessage::PitchBend { channel: ch, value: (i32::from(data) + 8192) as u16 }),
+        MidiEvent::Unknown { .. } => None,
+    }
 }
 
 
@@ -48,53 +306,189 @@ impl Handler for MIDIHandler {
     fn header(&mut self, format: u16, track: u16, time_base: u16) {
         let debug_header = (format, track, time_base);
         trace!("SMF header: {:?}", debug_header);
-        unsafe { (*self.data).pulses_per_quarter_note = time_base; }
+        self.data.lock().unwrap().tempo_map.pulses_per_quarter_note = time_base;
     }
 
     fn meta_event(&mut self, delta_time: u32, event: &MetaEvent, data: &Vec<u8>) {
         let debug_event = (delta_time, event, data);
         trace!("SMF meta event: {:?}", debug_event);
-        unsafe { (*self.data).add_delta_time(delta_time); }
+        let mut parse_data = self.data.lock().unwrap();
+        parse_data.add_delta_time(delta_time);
         match event {
             &MetaEvent::SetTempo => {
-                unsafe { assert_ne!((*self.data).pulses_per_quarter_note, 0); }
-                assert_eq!(data.len(), 3);
+                if parse_data.tempo_map.pulses_per_quarter_note == 0 || data.len() != 3 {
+                    parse_data.add_error("invalid tempo event");
+                    return;
+                }
 
                 let us_per_qn = ((data[0] as u32) << 16) + ((data[1] as u32) << 8) + (data[2] as u32);
                 let bpm = 60000000.0 / us_per_qn as f64;
-                let uspp = unsafe { (us_per_qn as f64 / (*self.data).pulses_per_quarter_note as f64) };
+                let uspp = us_per_qn as f64 / parse_data.tempo_map.pulses_per_quarter_note as f64;
 
                 debug!("New tempo: {} USPQN / {:.*} BPM / {} USPP", us_per_qn, 0, bpm, uspp);
-                unsafe {
-                    (*self.data).tempo_changes.push(MIDITempoChange {
-                        pulse: (*self.data).current_pulse,
-                        us_per_pulse: uspp,
-                    });
-                    trace!("Current tempo changes: {:?}", (*self.data).tempo_changes);
+                let pulse = parse_data.current_pulse;
+                parse_data.tempo_map.tempo_changes.push(MIDITempoChange {
+                    pulse,
+                    us_per_pulse: uspp,
+                });
+                trace!("Current tempo changes: {:?}", parse_data.tempo_map.tempo_changes);
+            }
+            &MetaEvent::TimeSignature => {
+                if data.len() != 4 {
+                    parse_data.add_error("invalid time signature event");
+                    return;
                 }
+                // The denominator is given as a power of 2
+                let signature = MIDITimeSignature {
+                    pulse: parse_data.current_pulse,
+                    numerator: data[0],
+                    denominator: 1 << data[1],
+                };
+                debug!("New time signature: {}/{}", signature.numerator, signature.denominator);
+                parse_data.tempo_map.time_signatures.push(signature);
+            }
+            &MetaEvent::Marker => {
+                let text = String::from_utf8_lossy(data).trim().to_string();
+                debug!("Marker: '{}'", text);
+                parse_data.add_event(MIDIMessage::Marker { text });
             }
             _ => {}
         }
     }
 
-    fn midi_event(&mut self, delta_time: u32, _event: &MidiEvent) {
+    fn midi_event(&mut self, delta_time: u32, event: &MidiEvent) {
         //        let debug_event = (delta_time, event);
         //        trace!("SMF midi event: {:?}", debug_event);
-        unsafe {
-            (*self.data).add_delta_time(delta_time);
-        }
+        let mut parse_data = self.data.lock().unwrap();
+        parse_data.add_delta_time(delta_time);
 
-        // TODO: Schedule event in synth sequencer
+        if let Some(message) = to_midi_message(event) {
+            parse_data.add_event(message);
+        }
     }
 
     fn sys_ex_event(&mut self, delta_time: u32, event: &SysExEvent, data: &Vec<u8>) {
         let debug_event = (delta_time, event, data);
         trace!("SMF sysex event: {:?}", debug_event);
-        unsafe { (*self.data).add_delta_time(delta_time); }
+        let mut parse_data = self.data.lock().unwrap();
+        parse_data.add_delta_time(delta_time);
+        match event {
+            &SysExEvent::F0 => {
+                let mut data = data.clone();
+                if data.last() == Some(&0xF7) {
+                    data.pop();
+                }
+                parse_data.add_event(MIDIMessage::SysEx { data });
+            }
+            _ => {
+                // Escaped or split messages are not supported
+                debug!("Skipping SysEx event {:?}", event);
+            }
+        }
     }
 
     fn track_change(&mut self) {
         trace!("SMF track change");
-        unsafe { (*self.data).reset_current_pulse(); }
+        self.data.lock().unwrap().reset_current_pulse();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 120 BPM at 480 pulses per quarter note, 4/4 until bar 5, 3/4 for two bars, then 6/8.
+    fn tempo_map() -> TempoMap {
+        TempoMap {
+            pulses_per_quarter_note: 480,
+            tempo_changes: vec![MIDITempoChange { pulse: 0, us_per_pulse: 500_000.0 / 480.0 }],
+            time_signatures: vec![
+                MIDITimeSignature { pulse: 0, numerator: 4, denominator: 4 },
+                MIDITimeSignature { pulse: 7680, numerator: 3, denominator: 4 },
+                MIDITimeSignature { pulse: 10_560, numerator: 6, denominator: 8 },
+            ],
+        }
+    }
+
+    #[test]
+    fn position_to_pulse_follows_time_signatures() {
+        let tempo_map = tempo_map();
+        assert_eq!(tempo_map.position_to_pulse(1, 1.0), 0.0);
+        assert_eq!(tempo_map.position_to_pulse(1, 3.0), 960.0);
+        assert_eq!(tempo_map.position_to_pulse(2, 1.0), 1920.0);
+        assert_eq!(tempo_map.position_to_pulse(5, 1.0), 7680.0);
+        assert_eq!(tempo_map.position_to_pulse(6, 2.0), 9600.0);
+        // Beats of 6/8 are eighths
+        assert_eq!(tempo_map.position_to_pulse(7, 2.0), 10_800.0);
+        assert_eq!(tempo_map.position_to_pulse(8, 1.0), 12_000.0);
+    }
+
+    #[test]
+    fn pulse_to_position_inverts_position_to_pulse() {
+        let tempo_map = tempo_map();
+        assert_eq!(tempo_map.pulse_to_position(0), (1, 1.0));
+        assert_eq!(tempo_map.pulse_to_position(960), (1, 3.0));
+        assert_eq!(tempo_map.pulse_to_position(9600), (6, 2.0));
+        assert_eq!(tempo_map.pulse_to_position(10_800), (7, 2.0));
+        assert_eq!(tempo_map.pulse_to_position(12_120), (8, 1.5));
+        for &(bar, beat) in &[(3, 2.5), (5, 3.0), (9, 6.0)] {
+            assert_eq!(tempo_map.pulse_to_position(tempo_map.position_to_pulse(bar, beat) as u64), (bar, beat));
+        }
+    }
+
+    #[test]
+    fn position_to_time_uses_the_tempo() {
+        assert!((tempo_map().position_to_time(2, 1.0) - 2_000_000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn controller_rules_apply_in_order() {
+        let rules = vec![
+            ControllerRule { control: 7, action: ControllerAction::Clamp(20, 100) },
+            ControllerRule { control: 10, action: ControllerAction::Rescale(32, 96) },
+            ControllerRule { control: 11, action: ControllerAction::Drop },
+            ControllerRule { control: 91, action: ControllerAction::Constant(40) },
+            ControllerRule { control: 93, action: ControllerAction::Clamp(0, 50) },
+            ControllerRule { control: 93, action: ControllerAction::Rescale(0, 100) },
+        ];
+        assert_eq!(apply_controller_rules(&rules, 7, 5), Some(20));
+        assert_eq!(apply_controller_rules(&rules, 7, 60), Some(60));
+        assert_eq!(apply_controller_rules(&rules, 7, 127), Some(100));
+        assert_eq!(apply_controller_rules(&rules, 10, 0), Some(32));
+        assert_eq!(apply_controller_rules(&rules, 10, 127), Some(96));
+        assert_eq!(apply_controller_rules(&rules, 11, 64), None);
+        assert_eq!(apply_controller_rules(&rules, 91, 127), Some(40));
+        assert_eq!(apply_controller_rules(&rules, 93, 127), Some(39));
+        assert_eq!(apply_controller_rules(&rules, 1, 77), Some(77));
+    }
+
+    #[test]
+    fn classify_sysex_recognizes_resets() {
+        assert_eq!(classify_sysex(&[0x7E, 0x7F, 0x09, 0x01]), SysExKind::GMSystemOn);
+        assert_eq!(classify_sysex(&[0x7E, 0x7F, 0x09, 0x03]), SysExKind::GMSystemOn);
+        assert_eq!(classify_sysex(&[0x7E, 0x7F, 0x09, 0x02]), SysExKind::GMSystemOff);
+        assert_eq!(classify_sysex(&[0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7F, 0x00, 0x41]), SysExKind::GSReset);
+        assert_eq!(classify_sysex(&[0x43, 0x10, 0x4C, 0x00, 0x00, 0x7E, 0x00]), SysExKind::XGSystemOn);
+    }
+
+    #[test]
+    fn classify_sysex_recognizes_parameters() {
+        assert_eq!(classify_sysex(&[0x7F, 0x7F, 0x04, 0x01, 0x00, 0x40]), SysExKind::MasterVolume(8192));
+        // GS part 10 is channel 9, part 1 channel 0 and part 11 channel 10
+        assert_eq!(classify_sysex(&[0x41, 0x10, 0x42, 0x12, 0x40, 0x10, 0x15, 0x01, 0x1A]), SysExKind::DrumPart { channel: 9, drum: true });
+        assert_eq!(classify_sysex(&[0x41, 0x10, 0x42, 0x12, 0x40, 0x11, 0x15, 0x00, 0x1A]), SysExKind::DrumPart { channel: 0, drum: false });
+        assert_eq!(classify_sysex(&[0x41, 0x10, 0x42, 0x12, 0x40, 0x1A, 0x15, 0x02, 0x1A]), SysExKind::DrumPart { channel: 10, drum: true });
+        assert_eq!(classify_sysex(&[0x43, 0x10, 0x4C, 0x08, 0x03, 0x07, 0x01]), SysExKind::DrumPart { channel: 3, drum: true });
+        assert_eq!(classify_sysex(&[0x41, 0x10, 0x42, 0x12, 0x40, 0x12, 0x19, 0x40, 0x00]), SysExKind::Part { channel: 1 });
+        assert_eq!(classify_sysex(&[0x43, 0x10, 0x4C, 0x08, 0x05, 0x01, 0x00]), SysExKind::Part { channel: 5 });
+    }
+
+    #[test]
+    fn classify_sysex_leaves_unknown_messages() {
+        assert_eq!(classify_sysex(&[]), SysExKind::Other);
+        assert_eq!(classify_sysex(&[0x7E, 0x7F, 0x09, 0x04]), SysExKind::Other);
+        // GS system parameters are not part parameters
+        assert_eq!(classify_sysex(&[0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x04, 0x7F, 0x3D]), SysExKind::Other);
+        assert_eq!(classify_sysex(&[0x44, 0x00, 0x01]), SysExKind::Other);
     }
 }
@@ -0,0 +1,195 @@
+extern crate libc;
+extern crate time;
+
+use std::io::{self, Write};
+
+use types::*;
+
+// Minimum time between two progress reports in seconds
+const REPORT_INTERVAL: f64 = 0.2;
+
+const PROGRESS_BAR_WIDTH: usize = 30;
+
+/// Quotes a string for JSON.
+pub fn json_string(text: &str) -> String {
+    let mut res = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// JSON numbers cannot be infinite, so silent peaks are floored like in the batch summary.
+fn decibels(value: f32) -> f64 {
+    20.0 * f64::from(value.max(1e-9)).log10()
+}
+
+fn format_time(time: f64) -> String {
+    let seconds = time / 1_000_000.0;
+    format!("{}:{:04.1}", (seconds / 60.0).floor(), seconds % 60.0)
+}
+
+/// Formats a JSON object, the values of `fields` must already be JSON.
+pub fn json_object(fields: &[(&str, String)]) -> String {
+    let members: Vec<String> = fields.iter().map(|&(name, ref value)| format!("{}:{}", json_string(name), value)).collect();
+    format!("{{{}}}", members.join(","))
+}
+
+/// Formats a JSON array of values which are already JSON.
+pub fn json_array(values: &[String]) -> String {
+    format!("[{}]", values.join(","))
+}
+
+/// Formats a JSON event, the values of `fields` must already be JSON.
+pub fn event_line(event: &str, fields: &[(&str, String)]) -> String {
+    let mut all = vec![("event", json_string(event))];
+    all.extend(fields.iter().cloned());
+    json_object(&all)
+}
+
+/// Emits one JSON event. Lines are written at once, so events of parallel jobs do not interleave.
+pub fn emit(event: &str, fields: &[(&str, String)]) {
+    println!("{}", event_line(event, fields));
+}
+
+/// JSON events with --json, otherwise a progress bar if standard error is a terminal and not quiet.
+pub fn report_mode(json: bool, quiet: bool) -> ReportMode {
+    if json {
+        ReportMode::Json
+    } else if !quiet && unsafe { libc::isatty(libc::STDERR_FILENO) } == 1 {
+        ReportMode::ProgressBar
+    } else {
+        ReportMode::Log
+    }
+}
+
+/// Reports that a render was skipped because its inputs have not changed.
+pub fn skipped(mode: ReportMode, output_file: &str) {
+    if mode == ReportMode::Json {
+        emit("skipped", &[("output", json_string(output_file))]);
+    }
+}
+
+impl ProgressReporter {
+    pub fn new(mode: ReportMode, output_file: &str) -> ProgressReporter {
+        ProgressReporter {
+            mode,
+            output_file: output_file.to_string(),
+            synths: 0,
+            length: 0.0,
+            start: 0.0,
+            last_report: 0.0,
+        }
+    }
+
+    /// Starts the render of `synths` synths, each `length` microseconds long.
+    pub fn started(&mut self, synths: usize, length: f64) {
+        self.synths = synths;
+        self.length = length;
+        self.start = time::precise_time_s();
+        self.last_report = self.start;
+        if self.mode == ReportMode::Json {
+            emit("started", &[
+                ("output", json_string(&self.output_file)),
+                ("synths", synths.to_string()),
+                ("length", (length / 1_000_000.0).to_string()),
+            ]);
+        }
+    }
+
+    /// Reports that synth `synth` (starting at 0) rendered up to `position` microseconds, at most every REPORT_INTERVAL.
+    pub fn progress(&mut self, synth: usize, id: &str, position: f64) {
+        if self.mode == ReportMode::Log || self.synths == 0 || self.length <= 0.0 {
+            return;
+        }
+        let now = time::precise_time_s();
+        if now - self.last_report < REPORT_INTERVAL {
+            return;
+        }
+        self.last_report = now;
+
+        let fraction = ((synth as f64 + position / self.length) / self.synths as f64).min(1.0);
+        // Audio of all synths rendered so far per second of wall time
+        let realtime_factor = (synth as f64 * self.length + position) / 1_000_000.0 / (now - self.start).max(1e-6);
+        match self.mode {
+            ReportMode::Json => emit("progress", &[
+                ("output", json_string(&self.output_file)),
+                ("synth", json_string(id)),
+                ("position", (position / 1_000_000.0).to_string()),
+                ("length", (self.length / 1_000_000.0).to_string()),
+                ("fraction", fraction.to_string()),
+                ("realtime_factor", realtime_factor.to_string()),
+            ]),
+            _ => {
+                let filled = (fraction * PROGRESS_BAR_WIDTH as f64) as usize;
+                eprint!("\r[{}{}] {:3.0} %  {} / {}  synth {}/{} '{}'  {:.1}x realtime ",
+                        "#".repeat(filled), "-".repeat(PROGRESS_BAR_WIDTH - filled), fraction * 100.0,
+                        format_time(position), format_time(self.length), synth + 1, self.synths, id, realtime_factor);
+                let _ = io::stderr().flush();
+            }
+        }
+    }
+
+    pub fn finished(&self, result: &RenderResult) {
+        let render_time = time::precise_time_s() - self.start;
+        match self.mode {
+            ReportMode::Json => emit("finished", &[
+                ("output", json_string(&self.output_file)),
+                ("duration", (result.length / 1_000_000.0).to_string()),
+                ("peak", decibels(result.peak).to_string()),
+                ("loudness", result.loudness.map_or("null".to_string(), |l| l.to_string())),
+                ("render_time", render_time.to_string()),
+            ]),
+            ReportMode::ProgressBar => {
+                // Ends the progress bar line
+                eprintln!();
+                info!("Rendered '{}' in {:.1} s", self.output_file, render_time);
+            }
+            ReportMode::Log => info!("Rendered '{}' in {:.1} s", self.output_file, render_time),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_string_escapes_special_characters() {
+        assert_eq!(json_string("song.wav"), "\"song.wav\"");
+        assert_eq!(json_string("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(json_string("C:\\music"), "\"C:\\\\music\"");
+        assert_eq!(json_string("a\nb\r\tc"), "\"a\\nb\\r\\tc\"");
+        assert_eq!(json_string("\u{1}\u{1f}"), "\"\\u0001\\u001f\"");
+        assert_eq!(json_string("Klänge ♪"), "\"Klänge ♪\"");
+        assert_eq!(json_string(""), "\"\"");
+    }
+
+    #[test]
+    fn event_line_starts_with_the_event() {
+        let line = event_line("skipped", &[("output", json_string("out.wav")), ("synths", json_array(&["1".to_string(), "2".to_string()]))]);
+        assert_eq!(line, "{\"event\":\"skipped\",\"output\":\"out.wav\",\"synths\":[1,2]}");
+    }
+
+    #[test]
+    fn decibels_are_finite_for_silence() {
+        assert_eq!(decibels(1.0), 0.0);
+        assert!((decibels(0.0) + 180.0).abs() < 1e-4);
+        assert!((decibels(0.5) + 6.0206).abs() < 1e-4);
+    }
+
+    #[test]
+    fn format_time_shows_minutes_and_seconds() {
+        assert_eq!(format_time(0.0), "0:00.0");
+        assert_eq!(format_time(83_400_000.0), "1:23.4");
+    }
+}
@@ -1,42 +1,251 @@
 extern crate time;
+extern crate hound;
 
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Seek, Write};
 use std::path::PathBuf;
 
-use ghakuf::reader::*;
-
 use types;
-use fluidsynthesizer;
+use synthesizer;
+use midiparser;
+use effects;
+use automation;
+use debugoutput;
+use manifest;
+use stdio;
+use progress;
+
+// Time in microseconds rendered after the last MIDI event, so releases and reverb tails can decay
+const RENDER_TAIL: f64 = 2_000_000.0;
+
+const RENDER_BLOCK_SIZE: usize = 64;
+
+/// Writes interleaved stereo samples as a 16 bit WAV file.
+pub fn write_wav<W: Write + Seek>(writer: W, sample_rate: u64, samples: &[f32]) -> Result<(), types::Error> {
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: sample_rate as u32,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let write_error = |e: hound::Error| types::Error::new(format!("Could not write output: {}", e));
+    let mut writer = hound::WavWriter::new(writer, spec).map_err(write_error)?;
+    for sample in samples {
+        let sample = sample.max(-1.0).min(1.0);
+        writer.write_sample((sample * i16::max_value() as f32) as i16).map_err(write_error)?;
+    }
+    writer.finalize().map_err(write_error)
+}
 
-pub fn process_render_settings(render_settings: &types::TOMLRenderSettings, resources: &PathBuf) {
+pub fn write_output(output_file: &PathBuf, sample_rate: u64, samples: &[f32]) -> Result<(), types::Error> {
+    let file = File::create(output_file).map_err(|_| types::Error::new(format!("Could not create output file '{}'", output_file.to_str().unwrap())))?;
+    write_wav(BufWriter::new(file), sample_rate, samples)
+}
+
+/// Adds the time since `start` to the timing log and returns the current time, both in seconds.
+fn record_stage(timings: &mut Vec<(String, f64)>, stage: &str, start: f64) -> f64 {
+    let now = time::precise_time_s();
+    debug!("Stage '{}' took {:.3} s", stage, now - start);
+    timings.push((stage.to_string(), now - start));
+    now
+}
+
+/// Reads the MIDI file, or `midi_data` if given, and loads all synthesizers, effects and automation, so invalid settings
+/// fail before rendering.
+pub fn prepare_render(render_settings: &types::TOMLRenderSettings, resources: &types::ResourcePaths, midi_data: Option<&[u8]>, timings: &mut Vec<(String, f64)>) -> Result<types::PreparedRender, types::Error> {
     let mut midi_file = render_settings.input_path.clone();
     midi_file.push(&render_settings.input_file);
-    let mut handler_data = types::MIDIHandlerData {
-        fluid_synthesizers: Vec::new(),
-        pulses_per_quarter_note: 0,
-        tempo_changes: Vec::new(),
-        current_pulse: 0,
-        max_pulse: 0,
+    let stdin_data;
+    let midi_data = if midi_data.is_none() && stdio::is_standard_stream(&render_settings.input_file) {
+        info!("Reading MIDI data from standard input");
+        stdin_data = stdio::read_stdin()?;
+        Some(&stdin_data[..])
+    } else {
+        midi_data
     };
+    let mut start = time::precise_time_s();
 
-    {
-        let handler = Box::new(types::MIDIHandler {
-            data: &mut handler_data as *mut types::MIDIHandlerData,
-        });
+    info!("Generating synthesizers...");
+    let synthesizers = synthesizer::generate_synthesizers(&render_settings, resources)?;
+    let elements = synthesizers.len();
+    info!("Generated {} synthesizer{}", elements, if elements == 1 { "" } else { "s" });
+    start = record_stage(timings, "generate synthesizers", start);
 
-        info!("Generating FluidSynth synthesizers...");
-        let fluid_synthesizers = fluidsynthesizer::generate_fluid_synthesizers(&render_settings, resources);
-        let elements = fluid_synthesizers.len();
-        info!("Generated {} FluidSynth synthesizer{}", elements, if elements == 1 { "" } else { "s" });
+    let mut handler_data = match midi_data {
+        Some(data) => midiparser::read_midi_data(data, synthesizers, render_settings.ignore_program_changes)?,
+        None => midiparser::read_midi_file(&midi_file, synthesizers, render_settings.ignore_program_changes)?,
+    };
+    start = record_stage(timings, "parse MIDI file", start);
+    info!("Scheduling MIDI events");
+    handler_data.schedule_events()?;
+    info!("MIDI length: {}", time::Duration::microseconds(handler_data.max_time() as i64));
+    start = record_stage(timings, "schedule events", start);
 
-        unsafe { (*handler.data).fluid_synthesizers = fluid_synthesizers; }
-        let mut reader = Reader::new(
-            handler,
-            &midi_file.to_str().unwrap(),
-        ).unwrap();
+    let sample_rate = render_settings.sample_rate;
+    let master_effects = effects::generate_effects(&render_settings.effect, resources, sample_rate)?;
+    let synth_effects = handler_data.synthesizers.iter()
+        .map(|s| match render_settings.synth[&s.id].effect {
+            Some(ref chain) => effects::generate_effects(chain, resources, sample_rate),
+            None => Ok(Vec::new()),
+        })
+        .collect::<Result<Vec<Vec<types::Effect>>, types::Error>>()?;
+    let synth_lanes = handler_data.synthesizers.iter().zip(synth_effects.iter())
+        .map(|(s, e)| match render_settings.synth[&s.id].automation {
+            Some(ref automation) => automation::generate_lanes(automation, e, &handler_data.tempo_map, &handler_data.events, sample_rate),
+            None => Ok(Vec::new()),
+        })
+        .collect::<Result<Vec<Vec<types::AutomationLane>>, types::Error>>()?;
+    record_stage(timings, "generate effects and automation", start);
+
+    Ok(types::PreparedRender {
+        handler_data,
+        master_effects,
+        synth_effects,
+        synth_lanes,
+    })
+}
 
-        info!("Parsing MIDI file");
-        let _ = reader.read();
+pub fn validate_render_settings(render_settings: &types::TOMLRenderSettings, resources: &types::ResourcePaths, report: types::ReportMode) -> Result<(), types::Error> {
+    let resources = resources.for_settings(render_settings);
+    let prepared = prepare_render(render_settings, &resources, None, &mut Vec::new())?;
+    if report == types::ReportMode::Json {
+        progress::emit("valid", &[
+            ("synths", prepared.handler_data.synthesizers.len().to_string()),
+            ("events", prepared.handler_data.events.len().to_string()),
+            ("length", (prepared.handler_data.max_time() / 1_000_000.0).to_string()),
+        ]);
+    } else {
+        println!("Render settings are valid: {} synthesizers, {} MIDI events, {} long",
+                 prepared.handler_data.synthesizers.len(),
+                 prepared.handler_data.events.len(),
+                 time::Duration::microseconds(prepared.handler_data.max_time() as i64));
     }
+    Ok(())
+}
 
-    info!("MIDI length: {}", time::Duration::microseconds(handler_data.max_time() as i64));
+/// Renders the MIDI file, or `midi_data` if given, into memory. With a debug directory the tempo map, the events and
+/// the dry output of every synth are written to it.
+pub fn render_audio(render_settings: &types::TOMLRenderSettings, resources: &types::ResourcePaths, midi_data: Option<&[u8]>,
+                    debug_directory: Option<&PathBuf>, timings: &mut Vec<(String, f64)>, progress: &mut types::ProgressReporter) -> Result<types::RenderedAudio, types::Error> {
+    let mut prepared = prepare_render(render_settings, resources, midi_data, timings)?;
+    if let Some(directory) = debug_directory {
+        debugoutput::write_tempo_map(directory, &prepared.handler_data.tempo_map)?;
+        debugoutput::write_events(directory, &prepared.handler_data)?;
+    }
+    let mut start = time::precise_time_s();
+
+    let sample_rate = render_settings.sample_rate;
+    // Reverbs decay after the synth tails, synth and master chains add up
+    let effect_tail = prepared.synth_effects.iter().map(|e| effects::tail(e)).fold(0.0, f64::max) + effects::tail(&prepared.master_effects);
+    // Audio clips may end after the tail of the last MIDI event
+    let end_time = prepared.handler_data.synthesizers.iter()
+        .map(|s| s.synthesizer.end_time())
+        .fold(prepared.handler_data.max_time() + RENDER_TAIL, f64::max);
+    let length = end_time + effect_tail;
+    let samples = (length * sample_rate as f64 / 1_000_000.0).ceil() as usize;
+    let mut mix = vec![0.0f32; samples * 2];
+    progress.started(prepared.handler_data.synthesizers.len(), length);
+    for (index, ((synth, synth_effects), lanes)) in prepared.handler_data.synthesizers.iter_mut().zip(prepared.synth_effects.iter()).zip(prepared.synth_lanes.iter()).enumerate() {
+        info!("Rendering {} samples of synth '{}'", samples, synth.id);
+        let mut rendered = vec![0.0f32; samples * 2];
+        for (block_index, block) in rendered.chunks_mut(RENDER_BLOCK_SIZE * 2).enumerate() {
+            synth.synthesizer.render_block(block)?;
+            let position = ((block_index + 1) * RENDER_BLOCK_SIZE) as f64 * 1_000_000.0 / sample_rate as f64;
+            progress.progress(index, &synth.id, position.min(length));
+        }
+        synth.synthesizer.finish();
+        start = record_stage(timings, &format!("render synth '{}'", synth.id), start);
+        if let Some(directory) = debug_directory {
+            let mut dry_file = directory.clone();
+            dry_file.push(format!("{}.dry.wav", synth.id));
+            write_output(&dry_file, sample_rate, &rendered)?;
+        }
+
+        effects::process_effects(synth_effects, lanes, &mut rendered, sample_rate);
+        start = record_stage(timings, &format!("effects of synth '{}'", synth.id), start);
+
+        // Automated gain replaces the static gain, pan keeps the louder side at full level like the clip voices
+        let gain_lane = automation::find_lane(lanes, &types::AutomationTarget::Gain);
+        let pan_lane = automation::find_lane(lanes, &types::AutomationTarget::Pan);
+        for (frame, (m, s)) in mix.chunks_mut(2).zip(rendered.chunks(2)).enumerate() {
+            let gain = gain_lane.map_or(f64::from(synth.gain), |l| l.value_at(frame as u64));
+            let pan = pan_lane.map_or(0.0, |l| l.value_at(frame as u64));
+            m[0] += (f64::from(s[0]) * gain * (1.0 - pan).min(1.0)) as f32;
+            m[1] += (f64::from(s[1]) * gain * (1.0 + pan).min(1.0)) as f32;
+        }
+        start = record_stage(timings, &format!("mix synth '{}'", synth.id), start);
+    }
+    effects::process_effects(&prepared.master_effects, &[], &mut mix, sample_rate);
+    record_stage(timings, "master effects", start);
+
+    Ok(types::RenderedAudio {
+        sample_rate,
+        samples: mix,
+    })
+}
+
+/// Renders the MIDI file and writes the mix. In debug mode the interstage products are written next to it.
+pub fn process_render_settings(render_settings: &types::TOMLRenderSettings, resources: &types::ResourcePaths, debug: bool, report: types::ReportMode) -> Result<types::RenderResult, types::Error> {
+    ensure!(!(report == types::ReportMode::Json && stdio::is_standard_stream(&render_settings.output_file)),
+            "JSON output cannot be combined with writing the audio to standard output");
+    let debug_directory = if debug { Some(debugoutput::debug_directory(render_settings)?) } else { None };
+    if let Some(ref directory) = debug_directory {
+        debugoutput::write_settings(directory, render_settings)?;
+        debugoutput::write_mapping(directory, render_settings, resources)?;
+    }
+
+    let mut timings = Vec::new();
+    let mut progress = types::ProgressReporter::new(report, &render_settings.output_file);
+    let audio = render_audio(render_settings, resources, None, debug_directory.as_ref(), &mut timings, &mut progress)?;
+    let start = time::precise_time_s();
+
+    let mut output_file = render_settings.input_path.clone();
+    output_file.push(&render_settings.output_file);
+    if stdio::is_standard_stream(&render_settings.output_file) {
+        // hound seeks to write the header, so the file is encoded in memory first
+        info!("Writing output to standard output");
+        let mut data = Cursor::new(Vec::new());
+        write_wav(&mut data, audio.sample_rate, &audio.samples)?;
+        stdio::write_stdout(data.get_ref())?;
+    } else {
+        info!("Writing output to '{}'", output_file.to_str().unwrap());
+        write_output(&output_file, audio.sample_rate, &audio.samples)?;
+    }
+    record_stage(&mut timings, "write output", start);
+
+    if let Some(ref directory) = debug_directory {
+        debugoutput::write_timings(directory, &timings)?;
+    }
+
+    let peak = audio.peak();
+    if peak > 1.0 {
+        warn!("Output '{}' clips, its peak is {:.1} dBFS", output_file.to_str().unwrap(), 20.0 * peak.log10());
+    }
+    let result = types::RenderResult {
+        length: audio.duration() * 1_000_000.0,
+        peak,
+        loudness: audio.loudness(),
+    };
+    progress.finished(&result);
+    Ok(result)
+}
+
+/// Renders unless the output was written for the same inputs before, as recorded in the manifest next to it.
+/// Debug mode always renders so the interstage products are written. Returns None if the render was skipped.
+/// The resource_path of the settings is searched before `resources`. Renders from or to standard streams are never skipped.
+pub fn process_if_changed(render_settings: &types::TOMLRenderSettings, resources: &types::ResourcePaths, debug: bool, force: bool, report: types::ReportMode) -> Result<Option<types::RenderResult>, types::Error> {
+    let resources = &resources.for_settings(render_settings);
+    if stdio::is_standard_stream(&render_settings.input_file) || stdio::is_standard_stream(&render_settings.output_file) {
+        // Streams cannot be compared with an earlier render
+        return process_render_settings(render_settings, resources, debug, report).map(Some);
+    }
+    let hash = manifest::input_hash(render_settings, resources);
+    if !force && !debug && manifest::is_up_to_date(render_settings, hash) {
+        info!("Skipping '{}', its inputs have not changed", render_settings.output_file);
+        progress::skipped(report, &render_settings.output_file);
+        return Ok(None);
+    }
+    let result = process_render_settings(render_settings, resources, debug, report)?;
+    manifest::write_manifest(render_settings, hash);
+    Ok(Some(result))
 }
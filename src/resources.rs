@@ -0,0 +1,101 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use types::*;
+use sfz;
+
+fn home_directory() -> Result<String, Error> {
+    env::var("HOME").or_else(|_| env::var("USERPROFILE")).map_err(|_| Error::new("Could not expand '~', HOME is not set"))
+}
+
+/// Replaces a leading '~' by the home directory.
+pub fn expand_home(path: &str) -> Result<String, Error> {
+    if path == "~" || path.starts_with("~/") {
+        Ok(format!("{}{}", home_directory()?, &path[1..]))
+    } else {
+        Ok(path.to_string())
+    }
+}
+
+impl ResourcePaths {
+    /// Reads resource directories separated like PATH, e.g. "resources:~/soundfonts".
+    pub fn from_argument(argument: &str) -> Result<ResourcePaths, Error> {
+        let directories = env::split_paths(argument)
+            .map(|d| expand_home(d.to_str().unwrap()).map(PathBuf::from))
+            .collect::<Result<Vec<_>, Error>>()?;
+        ensure!(!directories.is_empty(), "At least one resource directory is required");
+        Ok(ResourcePaths { directories })
+    }
+
+    /// Returns the search paths for a render, the resource_path of its settings come first.
+    pub fn for_settings(&self, render_settings: &TOMLRenderSettings) -> ResourcePaths {
+        ResourcePaths {
+            directories: render_settings.resource_path.iter().chain(self.directories.iter()).cloned().collect(),
+        }
+    }
+
+    /// Returns the file in the first directory that contains it. Absolute paths are returned unchanged,
+    /// missing files are reported relative to the first directory. Without directories the working directory is used.
+    pub fn find<P: AsRef<Path>>(&self, file: P) -> PathBuf {
+        let file = file.as_ref();
+        if file.is_absolute() {
+            return file.to_path_buf();
+        }
+        self.directories.iter()
+            .map(|d| d.join(file))
+            .find(|f| f.exists())
+            .unwrap_or_else(|| self.directories.first().map_or_else(|| file.to_path_buf(), |d| d.join(file)))
+    }
+}
+
+fn tuning_files(tuning: &TOMLTuning, resources: &ResourcePaths, files: &mut Vec<PathBuf>) {
+    files.extend(tuning.scale.iter().chain(tuning.keyboard_map.iter()).map(|f| resources.find(f)));
+}
+
+/// Returns every file the backends and effects of a render load: SoundFonts, SFZ instruments and their samples, audio
+/// clips, Scala files and impulse responses. Each file is listed once, in the order of the synth ids.
+pub fn resource_files(render_settings: &TOMLRenderSettings, resources: &ResourcePaths) -> Vec<PathBuf> {
+    let mut ids: Vec<&String> = render_settings.synth.keys().collect();
+    ids.sort();
+    let mut files = Vec::new();
+    for id in ids {
+        let synth = &render_settings.synth[id];
+        let directory = synth.directory.as_ref().map_or_else(PathBuf::new, PathBuf::from);
+        for soundfont in synth.soundfont.iter().flat_map(|s| s.iter()) {
+            files.push(resources.find(&soundfont.file));
+        }
+        if let Some(ref tuning) = synth.tuning {
+            tuning_files(tuning, resources, &mut files);
+        }
+        let mut mappings: Vec<&String> = synth.mapping.keys().collect();
+        mappings.sort();
+        for destination in mappings.iter().flat_map(|m| synth.mapping[*m].destination.iter()) {
+            if let Some(ref instrument) = destination.instrument {
+                let file = resources.find(directory.join(instrument));
+                if synth.synthtype == "sfz" {
+                    files.extend(sfz::sample_files(&file));
+                }
+                files.push(file);
+            }
+            if let Some(ref tuning) = destination.tuning {
+                tuning_files(tuning, resources, &mut files);
+            }
+        }
+        for clip in synth.clip.iter().flat_map(|c| c.iter()) {
+            files.push(resources.find(directory.join(&clip.file)));
+        }
+        for effect in synth.effect.iter().flat_map(|e| e.iter()) {
+            files.extend(effect.impulse.iter().map(|i| resources.find(i)));
+        }
+    }
+    for effect in &render_settings.effect {
+        files.extend(effect.impulse.iter().map(|i| resources.find(i)));
+    }
+    let mut unique = Vec::new();
+    for file in files {
+        if !unique.contains(&file) {
+            unique.push(file);
+        }
+    }
+    unique
+}
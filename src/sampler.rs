@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+
+use types::*;
+use midiparser;
+use synthesizer;
+
+// Events are applied between sub blocks of at most this many frames
+const SAMPLER_SUB_BLOCK: u64 = 64;
+
+/// A backend rendering its own voices, driven by the events a SamplerCore queues for it. The functions of this module
+/// implement the scheduling part of the Synthesizer trait for all of them.
+pub trait Sampler {
+    fn core(&mut self) -> &mut SamplerCore;
+
+    /// Returns whether the backend interprets SysEx messages of this kind, all others are reported as unhandled.
+    fn interprets_sysex(&self, kind: SysExKind) -> bool;
+
+    fn process_event(&mut self, event: SamplerEvent);
+
+    /// Adds all voices to a part of the buffer, dropping the ones which have finished.
+    fn render_voices(&mut self, buffer: &mut [f32]);
+
+    /// Drops all voices and returns the channels to their state before the first event.
+    fn reset_voices(&mut self);
+}
+
+impl SamplerCore {
+    /// `name` is used in messages, e.g. "the sf2 synthesizer".
+    pub fn new(name: &'static str) -> SamplerCore {
+        SamplerCore {
+            name,
+            sample_rate: 48_000,
+            rendered_samples: 0,
+            router: ChannelRouter::new(),
+            events: VecDeque::new(),
+            sysex: synthesizer::DEFAULT_SYSEX,
+            unhandled_sysex: Vec::new(),
+        }
+    }
+
+    /// Takes the sample rate and the SysEx handling of the synth table.
+    pub fn configure(&mut self, settings: &TOMLSynth, sample_rate: u64) -> Result<(), Error> {
+        self.sample_rate = sample_rate;
+        if let Some(ref sysex) = settings.sysex {
+            self.sysex = synthesizer::generate_sysex(sysex)?;
+        }
+        Ok(())
+    }
+
+    /// Converts a time in microseconds to samples.
+    pub fn sample_time(&self, time: f64) -> u64 {
+        (time * self.sample_rate as f64 / 1_000_000.0).round() as u64
+    }
+
+    /// Queues an event at `time` in samples, after all events at the same time.
+    pub fn add_event(&mut self, time: u64, event: SamplerEvent) {
+        // Events mostly arrive in chronological order, so the search starts at the back
+        let index = self.events.iter().rposition(|e| e.0 <= time).map_or(0, |i| i + 1);
+        self.events.insert(index, (time, event));
+    }
+
+    fn next_event(&mut self, now: u64) -> Option<SamplerEvent> {
+        if self.events.front().map_or(false, |e| e.0 <= now) {
+            self.events.pop_front().map(|e| e.1)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the end of the sub block starting at `frame` of the current block, at the latest at the next event
+    /// so notes start sample accurately.
+    fn sub_block_end(&self, frame: u64, frames: u64) -> u64 {
+        let end = frames.min(frame + SAMPLER_SUB_BLOCK);
+        match self.events.front() {
+            Some(&(time, _)) => end.min(time - self.rendered_samples),
+            None => end,
+        }
+    }
+}
+
+/// The event the samplers interpret a SysEx message as, None for messages they cannot interpret.
+fn sysex_event(router: &ChannelRouter, kind: SysExKind) -> Option<SamplerEvent> {
+    match kind {
+        SysExKind::GMSystemOn | SysExKind::GMSystemOff | SysExKind::GSReset | SysExKind::XGSystemOn => Some(SamplerEvent::SystemReset),
+        SysExKind::MasterVolume(volume) => Some(SamplerEvent::MasterVolume(f32::from(volume) / 16383.0)),
+        SysExKind::DrumPart { channel, drum } => Some(SamplerEvent::DrumChannels {
+            channels: router.matching_destinations(channel).iter().map(|d| d.channel).collect(),
+            drum,
+        }),
+        SysExKind::Part { .. } | SysExKind::Other => None,
+    }
+}
+
+fn schedule_sysex<S: Sampler>(sampler: &mut S, time: u64, data: &[u8]) {
+    let kind = midiparser::classify_sysex(data);
+    if sampler.core().sysex.handling_of(kind) == SysExHandling::Ignore {
+        debug!("Ignoring SysEx {:?}", kind);
+        return;
+    }
+    // There is no other synthesizer to pass messages on to, so honoured messages are interpreted like translated ones
+    let event = if sampler.interprets_sysex(kind) { sysex_event(&sampler.core().router, kind) } else { None };
+    let core = sampler.core();
+    match event {
+        Some(event) => core.add_event(time, event),
+        None => core.unhandled_sysex.push(data.to_vec()),
+    }
+}
+
+/// Queues a MIDI message at `time` (in microseconds) for all destination channels it is routed to.
+pub fn schedule_event<S: Sampler>(sampler: &mut S, time: f64, message: &MIDIMessage) {
+    let time = sampler.core().sample_time(time);
+    match *message {
+        MIDIMessage::SysEx { ref data } => schedule_sysex(sampler, time, data),
+        // Markers are not bound to a channel, they are passed on unrouted
+        MIDIMessage::Marker { .. } => sampler.core().add_event(time, SamplerEvent::Message(message.clone())),
+        _ => {
+            let core = sampler.core();
+            for message in core.router.route(message) {
+                core.add_event(time, SamplerEvent::Message(message));
+            }
+        }
+    }
+}
+
+/// Renders the next block, applying all events which are due between its sub blocks.
+pub fn render_block<S: Sampler>(sampler: &mut S, buffer: &mut [f32]) {
+    let frames = (buffer.len() / 2) as u64;
+    let mut frame = 0;
+    while frame < frames {
+        let now = sampler.core().rendered_samples + frame;
+        while let Some(event) = sampler.core().next_event(now) {
+            sampler.process_event(event);
+        }
+        let end = sampler.core().sub_block_end(frame, frames);
+        sampler.render_voices(&mut buffer[frame as usize * 2..end as usize * 2]);
+        frame = end;
+    }
+    sampler.core().rendered_samples += frames;
+}
+
+pub fn reset<S: Sampler>(sampler: &mut S) {
+    {
+        let core = sampler.core();
+        debug!("Resetting {}", core.name);
+        core.events.clear();
+        core.rendered_samples = 0;
+        core.router.reset();
+        core.unhandled_sysex.clear();
+    }
+    sampler.reset_voices();
+}
+
+pub fn finish<S: Sampler>(sampler: &mut S) {
+    let core = sampler.core();
+    synthesizer::report_unhandled_sysex(core.name, &core.unhandled_sysex);
+}
@@ -0,0 +1,562 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use types::*;
+use progress;
+
+pub const GEN_START_ADDRS_OFFSET: usize = 0;
+pub const GEN_END_ADDRS_OFFSET: usize = 1;
+pub const GEN_STARTLOOP_ADDRS_OFFSET: usize = 2;
+pub const GEN_ENDLOOP_ADDRS_OFFSET: usize = 3;
+pub const GEN_START_ADDRS_COARSE_OFFSET: usize = 4;
+pub const GEN_MOD_LFO_TO_PITCH: usize = 5;
+pub const GEN_VIB_LFO_TO_PITCH: usize = 6;
+pub const GEN_MOD_ENV_TO_PITCH: usize = 7;
+pub const GEN_INITIAL_FILTER_FC: usize = 8;
+pub const GEN_INITIAL_FILTER_Q: usize = 9;
+pub const GEN_MOD_LFO_TO_FILTER_FC: usize = 10;
+pub const GEN_MOD_ENV_TO_FILTER_FC: usize = 11;
+pub const GEN_END_ADDRS_COARSE_OFFSET: usize = 12;
+pub const GEN_MOD_LFO_TO_VOLUME: usize = 13;
+pub const GEN_CHORUS_EFFECTS_SEND: usize = 15;
+pub const GEN_REVERB_EFFECTS_SEND: usize = 16;
+pub const GEN_PAN: usize = 17;
+pub const GEN_DELAY_MOD_LFO: usize = 21;
+pub const GEN_FREQ_MOD_LFO: usize = 22;
+pub const GEN_DELAY_VIB_LFO: usize = 23;
+pub const GEN_FREQ_VIB_LFO: usize = 24;
+pub const GEN_DELAY_MOD_ENV: usize = 25;
+pub const GEN_KEYNUM_TO_MOD_ENV_DECAY: usize = 32;
+pub const GEN_DELAY_VOL_ENV: usize = 33;
+pub const GEN_KEYNUM_TO_VOL_ENV_DECAY: usize = 40;
+pub const GEN_INSTRUMENT: usize = 41;
+pub const GEN_KEY_RANGE: usize = 43;
+pub const GEN_VEL_RANGE: usize = 44;
+pub const GEN_STARTLOOP_ADDRS_COARSE_OFFSET: usize = 45;
+pub const GEN_KEYNUM: usize = 46;
+pub const GEN_VELOCITY: usize = 47;
+pub const GEN_INITIAL_ATTENUATION: usize = 48;
+pub const GEN_ENDLOOP_ADDRS_COARSE_OFFSET: usize = 50;
+pub const GEN_COARSE_TUNE: usize = 51;
+pub const GEN_FINE_TUNE: usize = 52;
+pub const GEN_SAMPLE_ID: usize = 53;
+pub const GEN_SAMPLE_MODES: usize = 54;
+pub const GEN_SCALE_TUNING: usize = 56;
+pub const GEN_EXCLUSIVE_CLASS: usize = 57;
+pub const GEN_OVERRIDING_ROOT_KEY: usize = 58;
+// Not a generator of the specification, the default pitch wheel modulator targets it
+pub const GEN_PITCH: usize = 59;
+pub const GEN_COUNT: usize = 61;
+
+// Generators which are only valid in instrument zones
+const INSTRUMENT_ONLY_GENERATORS: [usize; 14] = [
+    GEN_START_ADDRS_OFFSET, GEN_END_ADDRS_OFFSET, GEN_STARTLOOP_ADDRS_OFFSET, GEN_ENDLOOP_ADDRS_OFFSET,
+    GEN_START_ADDRS_COARSE_OFFSET, GEN_END_ADDRS_COARSE_OFFSET, GEN_STARTLOOP_ADDRS_COARSE_OFFSET,
+    GEN_KEYNUM, GEN_VELOCITY, GEN_ENDLOOP_ADDRS_COARSE_OFFSET, GEN_SAMPLE_ID, GEN_SAMPLE_MODES,
+    GEN_EXCLUSIVE_CLASS, GEN_OVERRIDING_ROOT_KEY,
+];
+
+// The modulators every voice starts with, see section 8.4 of the SoundFont 2.04 specification
+pub const DEFAULT_MODULATORS: [SF2Modulator; 10] = [
+    // Velocity to attenuation and filter cutoff
+    SF2Modulator { source: 0x0502, destination: GEN_INITIAL_ATTENUATION as u16, amount: 960, amount_source: 0, transform: 0 },
+    SF2Modulator { source: 0x0102, destination: GEN_INITIAL_FILTER_FC as u16, amount: -2400, amount_source: 0, transform: 0 },
+    // Channel pressure and modulation wheel to vibrato
+    SF2Modulator { source: 0x000D, destination: GEN_VIB_LFO_TO_PITCH as u16, amount: 50, amount_source: 0, transform: 0 },
+    SF2Modulator { source: 0x0081, destination: GEN_VIB_LFO_TO_PITCH as u16, amount: 50, amount_source: 0, transform: 0 },
+    // Volume, pan and expression
+    SF2Modulator { source: 0x0587, destination: GEN_INITIAL_ATTENUATION as u16, amount: 960, amount_source: 0, transform: 0 },
+    SF2Modulator { source: 0x028A, destination: GEN_PAN as u16, amount: 1000, amount_source: 0, transform: 0 },
+    SF2Modulator { source: 0x058B, destination: GEN_INITIAL_ATTENUATION as u16, amount: 960, amount_source: 0, transform: 0 },
+    // Reverb and chorus sends
+    SF2Modulator { source: 0x00DB, destination: GEN_REVERB_EFFECTS_SEND as u16, amount: 200, amount_source: 0, transform: 0 },
+    SF2Modulator { source: 0x00DD, destination: GEN_CHORUS_EFFECTS_SEND as u16, amount: 200, amount_source: 0, transform: 0 },
+    // Pitch wheel, scaled by the pitch wheel sensitivity
+    SF2Modulator { source: 0x020E, destination: GEN_PITCH as u16, amount: 12700, amount_source: 0x0010, transform: 0 },
+];
+
+fn default_generators() -> [i32; GEN_COUNT] {
+    let mut generators = [0; GEN_COUNT];
+    generators[GEN_INITIAL_FILTER_FC] = 13500;
+    for generator in &[21, 23, 25, 26, 27, 28, 30, 33, 34, 35, 36, 38] {
+        generators[*generator] = -12000;
+    }
+    generators[GEN_KEY_RANGE] = 127 << 8;
+    generators[GEN_VEL_RANGE] = 127 << 8;
+    generators[GEN_KEYNUM] = -1;
+    generators[GEN_VELOCITY] = -1;
+    generators[GEN_SCALE_TUNING] = 100;
+    generators[GEN_OVERRIDING_ROOT_KEY] = -1;
+    generators
+}
+
+fn read_u16(data: &[u8], pos: usize) -> u16 {
+    u16::from(data[pos]) | (u16::from(data[pos + 1]) << 8)
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from(read_u16(data, pos)) | (u32::from(read_u16(data, pos + 2)) << 16)
+}
+
+fn read_name(data: &[u8]) -> String {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+/// Splits RIFF data into (id, body) chunks.
+fn read_chunks(data: &[u8]) -> Result<Vec<(&[u8], &[u8])>, Error> {
+    let mut res = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let size = read_u32(data, pos + 4) as usize;
+        let start = pos + 8;
+        ensure!(start + size <= data.len(), "Truncated chunk in SoundFont");
+        res.push((id, &data[start..start + size]));
+        // Chunks are padded to an even size
+        pos = start + size + (size & 1);
+    }
+    Ok(res)
+}
+
+fn find_chunk<'a>(chunks: &[(&[u8], &'a [u8])], id: &[u8]) -> Result<&'a [u8], Error> {
+    chunks.iter()
+        .find(|c| c.0 == id)
+        .map(|c| c.1)
+        .ok_or_else(|| Error::new(format!("SoundFont is missing the '{}' chunk", String::from_utf8_lossy(id))))
+}
+
+fn records(data: &[u8], size: usize) -> Result<Vec<&[u8]>, Error> {
+    ensure!(data.len() % size == 0, "SoundFont contains a chunk of invalid size");
+    Ok(data.chunks(size).collect())
+}
+
+fn read_zones(bags: &[&[u8]], first_bag: usize, last_bag: usize, generators: &[&[u8]], modulators: &[&[u8]], terminal: usize) -> Result<(Option<SF2Zone>, Vec<SF2Zone>), Error> {
+    ensure!(first_bag <= last_bag && last_bag < bags.len(), "SoundFont contains an invalid zone index");
+    let mut global_zone = None;
+    let mut zones = Vec::new();
+    for bag in first_bag..last_bag {
+        let (first_generator, last_generator) = (read_u16(bags[bag], 0) as usize, read_u16(bags[bag + 1], 0) as usize);
+        let (first_modulator, last_modulator) = (read_u16(bags[bag], 2) as usize, read_u16(bags[bag + 1], 2) as usize);
+        ensure!(first_generator <= last_generator && last_generator <= generators.len()
+                && first_modulator <= last_modulator && last_modulator <= modulators.len(),
+                "SoundFont contains an invalid generator or modulator index");
+        let zone = SF2Zone {
+            generators: generators[first_generator..last_generator].iter().map(|g| SF2Generator {
+                operator: read_u16(g, 0),
+                amount: read_u16(g, 2),
+            }).collect(),
+            modulators: modulators[first_modulator..last_modulator].iter().map(|m| SF2Modulator {
+                source: read_u16(m, 0),
+                destination: read_u16(m, 2),
+                amount: read_u16(m, 4) as i16,
+                amount_source: read_u16(m, 6),
+                transform: read_u16(m, 8),
+            }).collect(),
+        };
+
+        // Zones have to end with the terminal generator, only the first zone may omit it to be the global zone
+        let terminated = zone.generators.last().map_or(false, |g| g.operator as usize == terminal);
+        if terminated {
+            zones.push(zone);
+        } else if bag == first_bag {
+            global_zone = Some(zone);
+        } else {
+            debug!("Ignoring zone without terminal generator");
+        }
+    }
+    Ok((global_zone, zones))
+}
+
+/// Parses a SoundFont 2 file from memory.
+pub fn parse_soundfont(data: &[u8]) -> Result<SoundFont, Error> {
+    ensure!(data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"sfbk", "Not a SoundFont 2 file");
+    let mut name = String::new();
+    let mut sample_data = Vec::new();
+    let mut pdta = None;
+
+    for (id, body) in read_chunks(&data[12..])? {
+        if id != b"LIST" || body.len() < 4 {
+            continue;
+        }
+        let chunks = read_chunks(&body[4..])?;
+        match &body[0..4] {
+            b"INFO" => {
+                if let Some(inam) = chunks.iter().find(|c| c.0 == b"INAM") {
+                    name = read_name(inam.1);
+                }
+            }
+            b"sdta" => {
+                let smpl = find_chunk(&chunks, b"smpl")?;
+                sample_data = (0..smpl.len() / 2).map(|i| read_u16(smpl, i * 2) as i16).collect();
+            }
+            b"pdta" => pdta = Some(chunks),
+            _ => {}
+        }
+    }
+    let pdta = pdta.ok_or_else(|| Error::new("SoundFont is missing the 'pdta' list"))?;
+
+    let phdr = records(find_chunk(&pdta, b"phdr")?, 38)?;
+    let pbag = records(find_chunk(&pdta, b"pbag")?, 4)?;
+    let pmod = records(find_chunk(&pdta, b"pmod")?, 10)?;
+    let pgen = records(find_chunk(&pdta, b"pgen")?, 4)?;
+    let inst = records(find_chunk(&pdta, b"inst")?, 22)?;
+    let ibag = records(find_chunk(&pdta, b"ibag")?, 4)?;
+    let imod = records(find_chunk(&pdta, b"imod")?, 10)?;
+    let igen = records(find_chunk(&pdta, b"igen")?, 4)?;
+    let shdr = records(find_chunk(&pdta, b"shdr")?, 46)?;
+    ensure!(phdr.len() >= 2 && inst.len() >= 2 && shdr.len() >= 1, "SoundFont contains no presets");
+
+    // The last record of each header list only terminates the list
+    let presets = phdr.windows(2).map(|p| {
+        let (global_zone, zones) = read_zones(&pbag, read_u16(p[0], 24) as usize, read_u16(p[1], 24) as usize, &pgen, &pmod, GEN_INSTRUMENT)?;
+        Ok(SF2Preset {
+            name: read_name(&p[0][0..20]),
+            program: read_u16(p[0], 20),
+            bank: read_u16(p[0], 22),
+            global_zone,
+            zones,
+        })
+    }).collect::<Result<Vec<_>, Error>>()?;
+
+    let instruments = inst.windows(2).map(|i| {
+        let (global_zone, zones) = read_zones(&ibag, read_u16(i[0], 20) as usize, read_u16(i[1], 20) as usize, &igen, &imod, GEN_SAMPLE_ID)?;
+        Ok(SF2Instrument {
+            name: read_name(&i[0][0..20]),
+            global_zone,
+            zones,
+        })
+    }).collect::<Result<Vec<_>, Error>>()?;
+
+    let samples = shdr[..shdr.len() - 1].iter().map(|s| SF2Sample {
+        name: read_name(&s[0..20]),
+        start: read_u32(s, 20),
+        end: read_u32(s, 24),
+        loop_start: read_u32(s, 28),
+        loop_end: read_u32(s, 32),
+        sample_rate: read_u32(s, 36),
+        original_pitch: s[40],
+        pitch_correction: s[41] as i8,
+        sample_type: read_u16(s, 44),
+    }).collect();
+
+    Ok(SoundFont {
+        name,
+        presets,
+        instruments,
+        samples,
+        data: sample_data,
+    })
+}
+
+pub fn load_soundfont(file: &PathBuf) -> Result<SoundFont, Error> {
+    let mut data = Vec::new();
+    File::open(file)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(|_| Error::new(format!("Could not read SoundFont '{}'", file.to_str().unwrap())))?;
+    let soundfont = parse_soundfont(&data).map_err(|e| Error::new(format!("Could not load SoundFont '{}': {}", file.to_str().unwrap(), e)))?;
+    info!("Loaded SoundFont '{}' with {} presets and {} samples", soundfont.name, soundfont.presets.len(), soundfont.samples.len());
+    Ok(soundfont)
+}
+
+thread_local! {
+    // SoundFonts with the modification time of their file when they were loaded
+    static SOUNDFONT_CACHE: RefCell<HashMap<PathBuf, (SystemTime, Rc<SoundFont>)>> = RefCell::new(HashMap::new());
+}
+
+/// Loads a SoundFont, reusing the one loaded before on this thread if its file has not been modified since.
+pub fn load_cached_soundfont(file: &PathBuf) -> Result<Rc<SoundFont>, Error> {
+    let modified = fs::metadata(file).and_then(|m| m.modified()).ok();
+    SOUNDFONT_CACHE.with(|cache| {
+        if let (Some(modified), Some(&(loaded, ref soundfont))) = (modified, cache.borrow().get(file)) {
+            if loaded == modified {
+                info!("Reusing SoundFont '{}'", file.to_str().unwrap());
+                return Ok(soundfont.clone());
+            }
+        }
+        let soundfont = Rc::new(load_soundfont(file)?);
+        if let Some(modified) = modified {
+            cache.borrow_mut().insert(file.clone(), (modified, soundfont.clone()));
+        }
+        Ok(soundfont)
+    })
+}
+
+/// Prints the presets of a SoundFont as bank:program: name, or emits them as 'preset' events.
+pub fn list_presets(file: &PathBuf, report: ReportMode) -> Result<(), Error> {
+    let soundfont = load_soundfont(file)?;
+    let mut presets: Vec<&SF2Preset> = soundfont.presets.iter().collect();
+    presets.sort_by_key(|p| (p.bank, p.program));
+    for preset in presets {
+        if report == ReportMode::Json {
+            progress::emit("preset", &[
+                ("bank", preset.bank.to_string()),
+                ("program", preset.program.to_string()),
+                ("name", progress::json_string(&preset.name)),
+            ]);
+        } else {
+            println!("{}:{}: {}", preset.bank, preset.program, preset.name);
+        }
+    }
+    Ok(())
+}
+
+fn in_range(generators: &[SF2Generator], global_zone: &Option<SF2Zone>, operator: usize, value: u8) -> bool {
+    let range = generators.iter()
+        .chain(global_zone.iter().flat_map(|z| z.generators.iter()))
+        .find(|g| g.operator as usize == operator);
+    match range {
+        Some(range) => (range.amount & 0xFF) as u8 <= value && value <= (range.amount >> 8) as u8,
+        None => true,
+    }
+}
+
+fn generator_value(generator: &SF2Generator) -> i32 {
+    match generator.operator as usize {
+        GEN_KEY_RANGE | GEN_VEL_RANGE | GEN_INSTRUMENT | GEN_SAMPLE_ID | GEN_SAMPLE_MODES => i32::from(generator.amount),
+        _ => i32::from(generator.amount as i16),
+    }
+}
+
+/// Adds modulators, replacing identical ones as described in section 9.5 of the specification.
+fn merge_modulators(modulators: &mut Vec<SF2Modulator>, zone: &[SF2Modulator]) {
+    for modulator in zone {
+        let identical = modulators.iter().position(|m| m.source == modulator.source && m.destination == modulator.destination
+            && m.amount_source == modulator.amount_source && m.transform == modulator.transform);
+        match identical {
+            Some(index) => modulators[index] = *modulator,
+            None => modulators.push(*modulator),
+        }
+    }
+}
+
+impl SoundFont {
+    pub fn find_preset(&self, bank: u16, program: u16) -> Option<usize> {
+        self.presets.iter().position(|p| p.bank == bank && p.program == program)
+    }
+
+    /// Returns the generators, modulators and sample of every instrument zone a note starts.
+    pub fn voice_zones(&self, preset: usize, key: u8, velocity: u8) -> Vec<([i32; GEN_COUNT], Vec<SF2Modulator>, usize)> {
+        let preset = &self.presets[preset];
+        let mut res = Vec::new();
+        for preset_zone in &preset.zones {
+            if !in_range(&preset_zone.generators, &preset.global_zone, GEN_KEY_RANGE, key) || !in_range(&preset_zone.generators, &preset.global_zone, GEN_VEL_RANGE, velocity) {
+                continue;
+            }
+            let instrument = &self.instruments[preset_zone.generators.last().unwrap().amount as usize];
+            trace!("Preset '{}' plays instrument '{}'", preset.name, instrument.name);
+
+            for instrument_zone in &instrument.zones {
+                if !in_range(&instrument_zone.generators, &instrument.global_zone, GEN_KEY_RANGE, key) || !in_range(&instrument_zone.generators, &instrument.global_zone, GEN_VEL_RANGE, velocity) {
+                    continue;
+                }
+                let sample = instrument_zone.generators.last().unwrap().amount as usize;
+                if sample >= self.samples.len() || self.samples[sample].sample_type & 0x8000 != 0 {
+                    debug!("Skipping zone with invalid or ROM sample");
+                    continue;
+                }
+
+                // Instrument generators are absolute, local ones replace global ones
+                let mut generators = default_generators();
+                for generator in instrument.global_zone.iter().flat_map(|z| z.generators.iter()).chain(instrument_zone.generators.iter()) {
+                    if (generator.operator as usize) < GEN_COUNT {
+                        generators[generator.operator as usize] = generator_value(generator);
+                    }
+                }
+
+                // Preset generators are relative and added to the instrument's
+                let mut preset_generators = [0; GEN_COUNT];
+                for generator in preset.global_zone.iter().flat_map(|z| z.generators.iter()).chain(preset_zone.generators.iter()) {
+                    let operator = generator.operator as usize;
+                    if operator < GEN_COUNT && operator != GEN_INSTRUMENT && operator != GEN_KEY_RANGE && operator != GEN_VEL_RANGE && !INSTRUMENT_ONLY_GENERATORS.contains(&operator) {
+                        preset_generators[operator] = generator_value(generator);
+                    }
+                }
+                for (generator, value) in generators.iter_mut().zip(preset_generators.iter()) {
+                    *generator += *value;
+                }
+
+                let mut modulators = DEFAULT_MODULATORS.to_vec();
+                let mut instrument_modulators = Vec::new();
+                if let Some(ref zone) = instrument.global_zone {
+                    merge_modulators(&mut instrument_modulators, &zone.modulators);
+                }
+                merge_modulators(&mut instrument_modulators, &instrument_zone.modulators);
+                merge_modulators(&mut modulators, &instrument_modulators);
+
+                // Preset modulators are added to the instrument's instead of replacing them
+                let mut preset_modulators = Vec::new();
+                if let Some(ref zone) = preset.global_zone {
+                    merge_modulators(&mut preset_modulators, &zone.modulators);
+                }
+                merge_modulators(&mut preset_modulators, &preset_zone.modulators);
+                modulators.extend(preset_modulators);
+
+                res.push((generators, modulators, sample));
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut res = id.to_vec();
+        let size = body.len() as u32;
+        res.extend(&[size as u8, (size >> 8) as u8, (size >> 16) as u8, (size >> 24) as u8]);
+        res.extend(body);
+        if body.len() % 2 == 1 {
+            res.push(0);
+        }
+        res
+    }
+
+    fn list(kind: &[u8], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = kind.to_vec();
+        for c in chunks {
+            body.extend(c);
+        }
+        chunk(b"LIST", &body)
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut res = name.as_bytes().to_vec();
+        res.resize(20, 0);
+        res
+    }
+
+    fn words(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| vec![*v as u8, (*v >> 8) as u8]).collect()
+    }
+
+    fn preset(preset_name: &str, program: u16, bank: u16, bag: u16) -> Vec<u8> {
+        let mut res = name(preset_name);
+        res.extend(words(&[program, bank, bag, 0, 0, 0, 0, 0, 0]));
+        res
+    }
+
+    fn instrument(instrument_name: &str, bag: u16) -> Vec<u8> {
+        let mut res = name(instrument_name);
+        res.extend(words(&[bag]));
+        res
+    }
+
+    fn sample(sample_name: &str, start: u16, end: u16) -> Vec<u8> {
+        let mut res = name(sample_name);
+        res.extend(words(&[start, 0, end, 0, start, 0, end, 0, 44100, 0]));
+        res.extend(&[60, 0xFE, 0, 0, 1, 0]);
+        res
+    }
+
+    /// The pdta chunks of a SoundFont with one preset. Its global zone sets the pan, its only zone plays the instrument
+    /// tuned up by two semitones on keys up to 63. The instrument's only zone plays the sample attenuated by 10 dB.
+    fn pdta_chunks() -> Vec<Vec<u8>> {
+        vec![
+            chunk(b"phdr", &[preset("Piano", 1, 0, 0), preset("EOP", 0, 0, 2)].concat()),
+            chunk(b"pbag", &words(&[0, 0, 1, 0, 4, 0])),
+            chunk(b"pmod", &[0; 10]),
+            chunk(b"pgen", &words(&[GEN_PAN as u16, 100, GEN_KEY_RANGE as u16, 63 << 8, GEN_COARSE_TUNE as u16, 2,
+                                    GEN_INSTRUMENT as u16, 0, 0, 0])),
+            chunk(b"inst", &[instrument("Piano", 0), instrument("EOI", 1)].concat()),
+            chunk(b"ibag", &words(&[0, 0, 2, 0])),
+            chunk(b"imod", &[0; 10]),
+            chunk(b"igen", &words(&[GEN_INITIAL_ATTENUATION as u16, 100, GEN_SAMPLE_ID as u16, 0, 0, 0])),
+            chunk(b"shdr", &[sample("Sine", 0, 4), sample("EOS", 0, 0)].concat()),
+        ]
+    }
+
+    fn soundfont(pdta: &[Vec<u8>]) -> Vec<u8> {
+        let body = [
+            b"sfbk".to_vec(),
+            list(b"INFO", &[chunk(b"INAM", b"Test Font\0")]),
+            list(b"sdta", &[chunk(b"smpl", &words(&[0, 1000, (-1000i16) as u16, 0]))]),
+            list(b"pdta", pdta),
+        ].concat();
+        chunk(b"RIFF", &body)
+    }
+
+    #[test]
+    fn parse_soundfont_reads_presets_instruments_and_samples() {
+        let soundfont = parse_soundfont(&soundfont(&pdta_chunks())).unwrap();
+        assert_eq!(soundfont.name, "Test Font");
+        assert_eq!(soundfont.data, vec![0, 1000, -1000, 0]);
+
+        assert_eq!(soundfont.presets.len(), 1);
+        let preset = &soundfont.presets[0];
+        assert_eq!((preset.name.as_str(), preset.bank, preset.program), ("Piano", 0, 1));
+        let global_generators = &preset.global_zone.as_ref().unwrap().generators;
+        assert_eq!(global_generators.len(), 1);
+        assert_eq!((global_generators[0].operator as usize, global_generators[0].amount), (GEN_PAN, 100));
+        assert_eq!(preset.zones.len(), 1);
+        assert_eq!(preset.zones[0].generators.len(), 3);
+
+        assert_eq!(soundfont.instruments.len(), 1);
+        assert!(soundfont.instruments[0].global_zone.is_none());
+        assert_eq!(soundfont.instruments[0].zones.len(), 1);
+
+        assert_eq!(soundfont.samples.len(), 1);
+        let sample = &soundfont.samples[0];
+        assert_eq!((sample.name.as_str(), sample.start, sample.end, sample.sample_rate), ("Sine", 0, 4, 44100));
+        assert_eq!((sample.original_pitch, sample.pitch_correction, sample.sample_type), (60, -2, 1));
+    }
+
+    #[test]
+    fn voice_zones_combine_preset_and_instrument_generators() {
+        let soundfont = parse_soundfont(&soundfont(&pdta_chunks())).unwrap();
+        assert_eq!(soundfont.find_preset(0, 1), Some(0));
+        assert_eq!(soundfont.find_preset(128, 1), None);
+
+        let zones = soundfont.voice_zones(0, 60, 100);
+        assert_eq!(zones.len(), 1);
+        let (ref generators, ref modulators, sample) = zones[0];
+        assert_eq!(sample, 0);
+        assert_eq!(generators[GEN_INITIAL_ATTENUATION], 100);
+        assert_eq!(generators[GEN_COARSE_TUNE], 2);
+        assert_eq!(generators[GEN_PAN], 100);
+        assert_eq!(generators[GEN_INITIAL_FILTER_FC], 13500);
+        assert_eq!(modulators, &DEFAULT_MODULATORS.to_vec());
+
+        assert!(soundfont.voice_zones(0, 64, 100).is_empty());
+    }
+
+    #[test]
+    fn parse_soundfont_rejects_other_files() {
+        let error = parse_soundfont(b"RIFF\x04\0\0\0WAVE").unwrap_err();
+        assert_eq!(error.message(), "Not a SoundFont 2 file");
+    }
+
+    #[test]
+    fn parse_soundfont_rejects_truncated_chunks() {
+        let mut data = soundfont(&pdta_chunks());
+        let length = data.len() - 10;
+        data.truncate(length);
+        assert_eq!(parse_soundfont(&data).unwrap_err().message(), "Truncated chunk in SoundFont");
+    }
+
+    #[test]
+    fn parse_soundfont_rejects_missing_chunks() {
+        let mut pdta = pdta_chunks();
+        pdta.pop();
+        assert_eq!(parse_soundfont(&soundfont(&pdta)).unwrap_err().message(), "SoundFont is missing the 'shdr' chunk");
+
+        let mut pdta = pdta_chunks();
+        pdta[1] = chunk(b"pbag", &words(&[0, 0, 1]));
+        assert_eq!(parse_soundfont(&soundfont(&pdta)).unwrap_err().message(), "SoundFont contains a chunk of invalid size");
+    }
+
+    #[test]
+    fn parse_soundfont_rejects_invalid_generator_indices() {
+        let mut pdta = pdta_chunks();
+        pdta[1] = chunk(b"pbag", &words(&[0, 0, 1, 0, 9, 0]));
+        let error = parse_soundfont(&soundfont(&pdta)).unwrap_err();
+        assert_eq!(error.message(), "SoundFont contains an invalid generator or modulator index");
+    }
+}
@@ -0,0 +1,639 @@
+use std::f64::consts::PI;
+use std::path::PathBuf;
+
+use types::*;
+use mapping;
+use sampler;
+use sampler::Sampler;
+use sf2;
+use sf2::*;
+use synthesizer::Synthesizer;
+
+// Like the default synth.gain of FluidSynth, so both backends render at a similar level
+const SF2_MASTER_GAIN: f64 = 0.2;
+const SF2_MAX_VOICES: usize = 256;
+
+const CC_DATA_ENTRY: u8 = 6;
+const CC_EXPRESSION: u8 = 11;
+const CC_DATA_ENTRY_LSB: u8 = 38;
+const CC_SUSTAIN: u8 = 64;
+const CC_RPN_LSB: u8 = 100;
+const CC_RPN_MSB: u8 = 101;
+const CC_ALL_SOUND_OFF: u8 = 120;
+const CC_RESET_CONTROLLERS: u8 = 121;
+const CC_ALL_NOTES_OFF: u8 = 123;
+
+impl SF2Synthesizer {
+    pub fn new() -> SF2Synthesizer {
+        SF2Synthesizer {
+            core: SamplerCore::new("the sf2 synthesizer"),
+            soundfonts: Vec::new(),
+            channels: Vec::new(),
+            voices: Vec::new(),
+            master_volume: 1.0,
+        }
+    }
+
+    pub fn load_soundfont(&mut self, file: &PathBuf, offset: i32) -> Result<(), Error> {
+        let soundfont = sf2::load_cached_soundfont(file)?;
+        self.soundfonts.push((soundfont, offset));
+        Ok(())
+    }
+
+    /// Looks up the preset of a destination channel. SoundFont ids start at 1 in load order, like in FluidSynth.
+    fn select_preset(&mut self, channel: u8) {
+        let (soundfont, bank, program) = {
+            let destination = self.core.router.destination(channel);
+            (destination.soundfont as usize - 1, destination.bank as i32, destination.program)
+        };
+        let drum = self.channels[channel as usize].drum;
+        let (ref font, offset) = self.soundfonts[soundfont];
+
+        let bank = if drum { 128 } else { bank - offset };
+        let preset = if bank >= 0 { font.find_preset(bank as u16, u16::from(program)) } else { None };
+        // Fall back to the standard drum kit or the GM melodic bank, as FluidSynth does
+        let preset = preset.or_else(|| if drum { font.find_preset(128, 0) } else { font.find_preset(0, u16::from(program)) });
+        match preset {
+            Some(preset) => debug!("Channel {}: {} - {}:{} '{}'", channel, soundfont + 1, bank, program, font.presets[preset].name),
+            None => warn!("SoundFont {} has no preset {}:{}, channel {} stays silent", soundfont + 1, bank, program, channel),
+        }
+        self.channels[channel as usize].preset = preset.map(|p| (soundfont, p));
+    }
+
+    /// Returns all destination channels to their state before the first event.
+    fn restore_channels(&mut self) {
+        self.channels = (0..self.core.router.used_channels).map(|_| new_channel()).collect();
+        let mut controllers = Vec::new();
+        for mapping in &self.core.router.mapping {
+            for destination in &mapping.destinations {
+                for (control, value) in mapping::initial_controllers(destination, &mapping.controller_rules) {
+                    controllers.push((destination.channel, control, value));
+                }
+            }
+        }
+        for (channel, control, value) in controllers {
+            self.channels[channel as usize].controllers[control as usize] = value;
+        }
+        for channel in 0..self.core.router.used_channels {
+            self.select_preset(channel as u8);
+        }
+    }
+
+    fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        let (soundfont, preset) = match self.channels[channel as usize].preset {
+            Some(preset) => preset,
+            None => return,
+        };
+        let (key_cents, detune) = {
+            let destination = self.core.router.destination(channel);
+            let key_cents = match destination.tuning {
+                Some(tuning) => self.core.router.tunings[tuning][key as usize],
+                None => f64::from(key) * 100.0,
+            };
+            (key_cents, destination.detune)
+        };
+
+        let font = &self.soundfonts[soundfont].0;
+        let mut started = Vec::new();
+        for (generators, modulators, sample) in font.voice_zones(preset, key, velocity) {
+            let sample = &font.samples[sample];
+            let root_key = if generators[GEN_OVERRIDING_ROOT_KEY] >= 0 {
+                generators[GEN_OVERRIDING_ROOT_KEY]
+            } else if sample.original_pitch <= 127 {
+                i32::from(sample.original_pitch)
+            } else {
+                60
+            };
+            let played_cents = if generators[GEN_KEYNUM] >= 0 { f64::from(generators[GEN_KEYNUM]) * 100.0 } else { key_cents };
+            let root_cents = f64::from(root_key) * 100.0;
+            // The pitch correction is applied on playback, like FluidSynth does
+            let pitch = (played_cents - root_cents) * f64::from(generators[GEN_SCALE_TUNING]) / 100.0
+                + f64::from(sample.pitch_correction) + detune;
+
+            let offset = |fine: usize, coarse: usize| generators[fine] + generators[coarse] * 32768;
+            let clamp = |address: i64| address.max(0).min(font.data.len() as i64 - 1) as usize;
+            let start = clamp(i64::from(sample.start) + i64::from(offset(GEN_START_ADDRS_OFFSET, GEN_START_ADDRS_COARSE_OFFSET)));
+            let end = clamp(i64::from(sample.end) + i64::from(offset(GEN_END_ADDRS_OFFSET, GEN_END_ADDRS_COARSE_OFFSET)));
+            let loop_start = clamp(i64::from(sample.loop_start) + i64::from(offset(GEN_STARTLOOP_ADDRS_OFFSET, GEN_STARTLOOP_ADDRS_COARSE_OFFSET)));
+            let loop_end = clamp(i64::from(sample.loop_end) + i64::from(offset(GEN_ENDLOOP_ADDRS_OFFSET, GEN_ENDLOOP_ADDRS_COARSE_OFFSET)));
+            if start >= end {
+                continue;
+            }
+            trace!("Starting sample '{}' on channel {}", sample.name, channel);
+            // Mode 2 is reserved and treated as no loop, as are broken loops
+            let loop_mode = match generators[GEN_SAMPLE_MODES] & 3 {
+                1 | 3 if start <= loop_start && loop_start < loop_end && loop_end <= end => generators[GEN_SAMPLE_MODES] & 3,
+                _ => 0,
+            };
+
+            let exclusive_class = generators[GEN_EXCLUSIVE_CLASS];
+            if exclusive_class != 0 {
+                self.voices.retain(|v| v.channel != channel || v.generators[GEN_EXCLUSIVE_CLASS] != exclusive_class);
+            }
+
+            started.push(SF2Voice {
+                channel,
+                key: if generators[GEN_KEYNUM] >= 0 { generators[GEN_KEYNUM] as u8 } else { key },
+                velocity: if generators[GEN_VELOCITY] >= 0 { generators[GEN_VELOCITY] as u8 } else { velocity },
+                soundfont,
+                generators,
+                modulators,
+                sample_rate: f64::from(sample.sample_rate),
+                pitch,
+                end,
+                loop_start,
+                loop_end,
+                loop_mode,
+                position: start as f64,
+                age: 0.0,
+                volume_envelope: new_envelope(),
+                modulation_envelope: new_envelope(),
+                filter_state: [0.0; 4],
+                released: false,
+                sustained: false,
+            });
+        }
+
+        for mut voice in started {
+            if self.voices.len() >= SF2_MAX_VOICES {
+                // Steal the oldest released voice, or the oldest one if all are held
+                let index = self.voices.iter().position(|v| v.released).unwrap_or(0);
+                self.voices.remove(index);
+            }
+            // Voices keep the key they were started with, so note offs find them
+            voice.key = key;
+            self.voices.push(voice);
+        }
+    }
+
+    fn note_off(&mut self, channel: u8, key: u8) {
+        let sustain = self.channels[channel as usize].controllers[CC_SUSTAIN as usize] >= 64;
+        for voice in self.voices.iter_mut().filter(|v| v.channel == channel && v.key == key && !v.released) {
+            if sustain {
+                voice.sustained = true;
+            } else {
+                release_voice(voice);
+            }
+        }
+    }
+
+    fn control_change(&mut self, channel: u8, control: u8, value: u8) {
+        self.channels[channel as usize].controllers[control as usize] = value;
+        match control {
+            CC_DATA_ENTRY | CC_DATA_ENTRY_LSB => {
+                let channel = &mut self.channels[channel as usize];
+                let msb = channel.controllers[CC_DATA_ENTRY as usize];
+                let lsb = channel.controllers[CC_DATA_ENTRY_LSB as usize];
+                match (channel.controllers[CC_RPN_MSB as usize], channel.controllers[CC_RPN_LSB as usize]) {
+                    (0, 0) => channel.bend_range = f64::from(msb) * 100.0 + f64::from(lsb),
+                    (0, 1) => channel.fine_tuning = (f64::from((u16::from(msb) << 7) | u16::from(lsb)) - 8192.0) / 8192.0 * 100.0,
+                    (0, 2) => channel.coarse_tuning = (f64::from(msb) - 64.0) * 100.0,
+                    _ => {}
+                }
+            }
+            CC_SUSTAIN if value < 64 => {
+                for voice in self.voices.iter_mut().filter(|v| v.channel == channel && v.sustained) {
+                    release_voice(voice);
+                }
+            }
+            CC_ALL_SOUND_OFF => self.voices.retain(|v| v.channel != channel),
+            CC_RESET_CONTROLLERS => {
+                // Volume and pan are kept, see RP-015
+                let channel = &mut self.channels[channel as usize];
+                for control in &[1, 64, 65, 66, 67] {
+                    channel.controllers[*control] = 0;
+                }
+                channel.controllers[CC_EXPRESSION as usize] = 127;
+                channel.controllers[CC_RPN_MSB as usize] = 127;
+                channel.controllers[CC_RPN_LSB as usize] = 127;
+                channel.key_pressure = [0; 128];
+                channel.channel_pressure = 0;
+                channel.pitch_bend = 8192;
+            }
+            CC_ALL_NOTES_OFF => {
+                let sustain = self.channels[channel as usize].controllers[CC_SUSTAIN as usize] >= 64;
+                for voice in self.voices.iter_mut().filter(|v| v.channel == channel && !v.released) {
+                    if sustain {
+                        voice.sustained = true;
+                    } else {
+                        release_voice(voice);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Sampler for SF2Synthesizer {
+    fn core(&mut self) -> &mut SamplerCore {
+        &mut self.core
+    }
+
+    fn interprets_sysex(&self, _kind: SysExKind) -> bool {
+        true
+    }
+
+    fn process_event(&mut self, event: SamplerEvent) {
+        match event {
+            SamplerEvent::Message(message) => match message {
+                MIDIMessage::NoteOn { channel, key, velocity } => self.note_on(channel, key, velocity),
+                MIDIMessage::NoteOff { channel, key } => self.note_off(channel, key),
+                MIDIMessage::KeyPressure { channel, key, pressure } => self.channels[channel as usize].key_pressure[key as usize] = pressure,
+                MIDIMessage::ControlChange { channel, control, value } => self.control_change(channel, control, value),
+                MIDIMessage::ChannelPressure { channel, pressure } => self.channels[channel as usize].channel_pressure = pressure,
+                MIDIMessage::PitchBend { channel, value } => self.channels[channel as usize].pitch_bend = value,
+                MIDIMessage::ProgramChange { .. } | MIDIMessage::SysEx { .. } | MIDIMessage::Marker { .. } => {}
+            },
+            SamplerEvent::SystemReset => self.reset_voices(),
+            SamplerEvent::MasterVolume(volume) => self.master_volume = volume,
+            SamplerEvent::DrumChannels { channels, drum } => {
+                for channel in channels {
+                    self.channels[channel as usize].drum = drum;
+                    self.select_preset(channel);
+                }
+            }
+            SamplerEvent::Clip(_) => {}
+        }
+    }
+
+    fn render_voices(&mut self, buffer: &mut [f32]) {
+        let gain = SF2_MASTER_GAIN * f64::from(self.master_volume);
+        let sample_rate = self.core.sample_rate as f64;
+        let mut i = 0;
+        while i < self.voices.len() {
+            let alive = {
+                let voice = &mut self.voices[i];
+                let data = &self.soundfonts[voice.soundfont].0.data;
+                render_voice(voice, &self.channels[voice.channel as usize], data, sample_rate, gain, buffer)
+            };
+            if alive {
+                i += 1;
+            } else {
+                self.voices.remove(i);
+            }
+        }
+    }
+
+    fn reset_voices(&mut self) {
+        self.voices.clear();
+        self.master_volume = 1.0;
+        self.restore_channels();
+    }
+}
+
+fn new_channel() -> SF2Channel {
+    let mut controllers = [0; 128];
+    controllers[mapping::CC_VOLUME as usize] = 100;
+    controllers[mapping::CC_PAN as usize] = 64;
+    controllers[CC_EXPRESSION as usize] = 127;
+    controllers[CC_RPN_MSB as usize] = 127;
+    controllers[CC_RPN_LSB as usize] = 127;
+    SF2Channel {
+        preset: None,
+        drum: false,
+        controllers,
+        key_pressure: [0; 128],
+        channel_pressure: 0,
+        pitch_bend: 8192,
+        bend_range: 200.0,
+        fine_tuning: 0.0,
+        coarse_tuning: 0.0,
+    }
+}
+
+fn new_envelope() -> SF2Envelope {
+    SF2Envelope {
+        stage: SF2EnvelopeStage::Delay,
+        stage_time: 0.0,
+        value: 0.0,
+    }
+}
+
+fn release_envelope(envelope: &mut SF2Envelope) {
+    match envelope.stage {
+        SF2EnvelopeStage::Delay => envelope.value = 0.0,
+        SF2EnvelopeStage::Finished => return,
+        _ => {}
+    }
+    envelope.stage = SF2EnvelopeStage::Release;
+}
+
+fn release_voice(voice: &mut SF2Voice) {
+    voice.released = true;
+    voice.sustained = false;
+    // The volume envelope attacks with linear amplitude but releases linearly in dB
+    if voice.volume_envelope.stage == SF2EnvelopeStage::Attack {
+        let amplitude = voice.volume_envelope.value;
+        voice.volume_envelope.value = if amplitude > 0.0 { (1.0 + amplitude.log10() / 4.8).max(0.0) } else { 0.0 };
+    }
+    release_envelope(&mut voice.volume_envelope);
+    release_envelope(&mut voice.modulation_envelope);
+}
+
+/// Returns the value of a modulator source between -1.0 and 1.0, None for "no controller".
+fn source_value(source: u16, channel: &SF2Channel, key: u8, velocity: u8) -> Option<f64> {
+    let index = (source & 0x7F) as usize;
+    let x = if source & 0x80 != 0 {
+        f64::from(channel.controllers[index]) / 128.0
+    } else {
+        match index {
+            2 => f64::from(velocity) / 128.0,
+            3 => f64::from(key) / 128.0,
+            10 => f64::from(channel.key_pressure[key as usize]) / 128.0,
+            13 => f64::from(channel.channel_pressure) / 128.0,
+            14 => f64::from(channel.pitch_bend) / 16384.0,
+            // Pitch wheel sensitivity in semitones
+            16 => channel.bend_range / 100.0 / 127.0,
+            _ => return None,
+        }
+    };
+    let x = if source & 0x100 != 0 { 1.0 - x } else { x };
+    let bipolar = source & 0x200 != 0;
+    let shape = |x: f64| match source >> 10 {
+        1 => concave(x),
+        2 => 1.0 - concave(1.0 - x),
+        3 => if x >= 0.5 { 1.0 } else { 0.0 },
+        _ => x,
+    };
+    Some(if bipolar {
+        if x >= 0.5 { shape(2.0 * x - 1.0) } else { -shape(1.0 - 2.0 * x) }
+    } else {
+        shape(x)
+    })
+}
+
+fn concave(x: f64) -> f64 {
+    if x >= 1.0 {
+        1.0
+    } else {
+        (-(400.0 / 960.0) * (1.0 - x).log10()).max(0.0).min(1.0)
+    }
+}
+
+/// Applies the modulators of a voice to its generators.
+fn modulated_generators(voice: &SF2Voice, channel: &SF2Channel) -> [f64; GEN_COUNT] {
+    let mut generators = [0.0; GEN_COUNT];
+    for (modulated, generator) in generators.iter_mut().zip(voice.generators.iter()) {
+        *modulated = f64::from(*generator);
+    }
+    for modulator in &voice.modulators {
+        let destination = modulator.destination as usize;
+        // Linked modulators are not supported
+        if destination >= GEN_COUNT {
+            continue;
+        }
+        let value = match source_value(modulator.source, channel, voice.key, voice.velocity) {
+            Some(value) => value,
+            None => continue,
+        };
+        let amount = if modulator.amount_source == 0 {
+            1.0
+        } else {
+            source_value(modulator.amount_source, channel, voice.key, voice.velocity).unwrap_or(1.0)
+        };
+        let value = f64::from(modulator.amount) * value * amount;
+        generators[destination] += if modulator.transform == 2 { value.abs() } else { value };
+    }
+    generators
+}
+
+fn timecents_to_seconds(timecents: f64) -> f64 {
+    if timecents <= -12000.0 {
+        0.0
+    } else {
+        2.0f64.powf(timecents / 1200.0)
+    }
+}
+
+/// Returns delay, attack, hold, decay, sustain level and release of the envelope starting at generator `first`.
+fn envelope_parameters(generators: &[f64; GEN_COUNT], first: usize, key: u8, volume: bool) -> [f64; 6] {
+    let key_scaling = |generator: usize| (60.0 - f64::from(key)) * generators[generator];
+    let sustain = if volume {
+        1.0 - generators[first + 4] / 960.0
+    } else {
+        1.0 - generators[first + 4] / 1000.0
+    };
+    [
+        timecents_to_seconds(generators[first]),
+        timecents_to_seconds(generators[first + 1]),
+        timecents_to_seconds(generators[first + 2] + key_scaling(first + 6)),
+        timecents_to_seconds(generators[first + 3] + key_scaling(first + 7)),
+        sustain.max(0.0).min(1.0),
+        timecents_to_seconds(generators[first + 5]),
+    ]
+}
+
+/// Advances an envelope by `seconds`. Parameters are delay, attack, hold, decay, sustain level and release.
+fn advance_envelope(envelope: &mut SF2Envelope, parameters: &[f64; 6], seconds: f64) {
+    envelope.stage_time += seconds;
+    loop {
+        match envelope.stage {
+            SF2EnvelopeStage::Delay => {
+                if envelope.stage_time < parameters[0] {
+                    return;
+                }
+                envelope.stage_time -= parameters[0];
+                envelope.stage = SF2EnvelopeStage::Attack;
+            }
+            SF2EnvelopeStage::Attack => {
+                if envelope.stage_time < parameters[1] {
+                    envelope.value = envelope.stage_time / parameters[1];
+                    return;
+                }
+                envelope.stage_time -= parameters[1];
+                envelope.value = 1.0;
+                envelope.stage = SF2EnvelopeStage::Hold;
+            }
+            SF2EnvelopeStage::Hold => {
+                if envelope.stage_time < parameters[2] {
+                    return;
+                }
+                envelope.stage_time -= parameters[2];
+                envelope.stage = SF2EnvelopeStage::Decay;
+            }
+            SF2EnvelopeStage::Decay => {
+                // The decay time is the time to fall from full level to zero, not to the sustain level
+                envelope.value = if parameters[3] > 0.0 { 1.0 - envelope.stage_time / parameters[3] } else { 0.0 };
+                if envelope.value > parameters[4] {
+                    return;
+                }
+                envelope.value = parameters[4];
+                envelope.stage = SF2EnvelopeStage::Sustain;
+            }
+            SF2EnvelopeStage::Sustain => {
+                envelope.value = parameters[4];
+                return;
+            }
+            SF2EnvelopeStage::Release => {
+                envelope.value -= if parameters[5] > 0.0 { seconds / parameters[5] } else { 1.0 };
+                if envelope.value > 0.0 {
+                    return;
+                }
+                envelope.value = 0.0;
+                envelope.stage = SF2EnvelopeStage::Finished;
+            }
+            SF2EnvelopeStage::Finished => return,
+        }
+    }
+}
+
+/// The amplitude of the volume envelope: linear during the attack, then linear in dB over 96 dB.
+fn volume_amplitude(envelope: &SF2Envelope) -> f64 {
+    match envelope.stage {
+        SF2EnvelopeStage::Delay | SF2EnvelopeStage::Finished => 0.0,
+        SF2EnvelopeStage::Attack => envelope.value,
+        _ => 10.0f64.powf((envelope.value - 1.0) * 4.8),
+    }
+}
+
+/// A triangle wave between -1.0 and 1.0 starting after its delay, frequency in absolute cents.
+fn lfo(age: f64, delay: f64, frequency: f64) -> f64 {
+    let delay = timecents_to_seconds(delay);
+    if age < delay {
+        return 0.0;
+    }
+    let phase = ((age - delay) * 8.176 * 2.0f64.powf(frequency / 1200.0)).fract();
+    if phase < 0.25 {
+        4.0 * phase
+    } else if phase < 0.75 {
+        2.0 - 4.0 * phase
+    } else {
+        4.0 * phase - 4.0
+    }
+}
+
+/// Adds one voice to interleaved stereo frames, returns whether it is still sounding.
+fn render_voice(voice: &mut SF2Voice, channel: &SF2Channel, data: &[i16], sample_rate: f64, gain: f64, buffer: &mut [f32]) -> bool {
+    let frames = buffer.len() / 2;
+    let duration = frames as f64 / sample_rate;
+    let generators = modulated_generators(voice, channel);
+
+    // Modulation sources are updated once per sub block
+    let modulation_parameters = envelope_parameters(&generators, GEN_DELAY_MOD_ENV, voice.key, false);
+    advance_envelope(&mut voice.modulation_envelope, &modulation_parameters, duration);
+    let modulation_envelope = match voice.modulation_envelope.stage {
+        SF2EnvelopeStage::Delay => 0.0,
+        _ => voice.modulation_envelope.value,
+    };
+    let modulation_lfo = lfo(voice.age, generators[GEN_DELAY_MOD_LFO], generators[GEN_FREQ_MOD_LFO]);
+    let vibrato_lfo = lfo(voice.age, generators[GEN_DELAY_VIB_LFO], generators[GEN_FREQ_VIB_LFO]);
+    voice.age += duration;
+
+    let cents = voice.pitch + generators[GEN_COARSE_TUNE] * 100.0 + generators[GEN_FINE_TUNE]
+        + channel.fine_tuning + channel.coarse_tuning
+        + modulation_envelope * generators[GEN_MOD_ENV_TO_PITCH]
+        + modulation_lfo * generators[GEN_MOD_LFO_TO_PITCH]
+        + vibrato_lfo * generators[GEN_VIB_LFO_TO_PITCH]
+        + generators[GEN_PITCH];
+    let step = 2.0f64.powf(cents / 1200.0) * voice.sample_rate / sample_rate;
+
+    // Resonant lowpass, see the Audio EQ Cookbook
+    let cutoff = generators[GEN_INITIAL_FILTER_FC] + modulation_envelope * generators[GEN_MOD_ENV_TO_FILTER_FC]
+        + modulation_lfo * generators[GEN_MOD_LFO_TO_FILTER_FC];
+    let cutoff = (8.176 * 2.0f64.powf(cutoff / 1200.0)).max(5.0).min(0.45 * sample_rate);
+    let resonance = generators[GEN_INITIAL_FILTER_Q].max(0.0).min(960.0) / 10.0;
+    let q = 10.0f64.powf((resonance - 3.01) / 20.0);
+    let w0 = 2.0 * PI * cutoff / sample_rate;
+    let alpha = w0.sin() / (2.0 * q);
+    let a0 = 1.0 + alpha;
+    let b0 = (1.0 - w0.cos()) / 2.0 / a0;
+    let b1 = (1.0 - w0.cos()) / a0;
+    let a1 = -2.0 * w0.cos() / a0;
+    let a2 = (1.0 - alpha) / a0;
+
+    let attenuation = generators[GEN_INITIAL_ATTENUATION].max(0.0).min(1440.0) + modulation_lfo * generators[GEN_MOD_LFO_TO_VOLUME];
+    let amplitude = 10.0f64.powf(-attenuation / 200.0) * gain;
+    // Constant power pan
+    let pan = (generators[GEN_PAN] / 1000.0).max(-0.5).min(0.5) + 0.5;
+    let (left, right) = ((pan * PI / 2.0).cos() * amplitude, (pan * PI / 2.0).sin() * amplitude);
+
+    let volume_parameters = envelope_parameters(&generators, GEN_DELAY_VOL_ENV, voice.key, true);
+    let frame_duration = 1.0 / sample_rate;
+    let looping = voice.loop_mode == 1 || (voice.loop_mode == 3 && !voice.released);
+    let (mut x1, mut x2) = (voice.filter_state[0], voice.filter_state[1]);
+    let (mut y1, mut y2) = (voice.filter_state[2], voice.filter_state[3]);
+
+    for frame in buffer.chunks_mut(2) {
+        advance_envelope(&mut voice.volume_envelope, &volume_parameters, frame_duration);
+        if voice.volume_envelope.stage == SF2EnvelopeStage::Finished {
+            return false;
+        }
+
+        let index = voice.position as usize;
+        let fraction = voice.position - index as f64;
+        let next = if looping && index + 1 >= voice.loop_end { voice.loop_start } else { index + 1 };
+        let current = f64::from(data[index]) / 32768.0;
+        let next = if next < voice.end { f64::from(data[next]) / 32768.0 } else { 0.0 };
+        let x = current + (next - current) * fraction;
+
+        let y = b0 * x + b1 * x1 + b0 * x2 - a1 * y1 - a2 * y2;
+        x2 = x1;
+        x1 = x;
+        y2 = y1;
+        y1 = y;
+
+        let y = y * volume_amplitude(&voice.volume_envelope);
+        frame[0] += (y * left) as f32;
+        frame[1] += (y * right) as f32;
+
+        voice.position += step;
+        if looping {
+            while voice.position >= voice.loop_end as f64 {
+                voice.position -= (voice.loop_end - voice.loop_start) as f64;
+            }
+        } else if voice.position >= voice.end as f64 {
+            return false;
+        }
+    }
+    voice.filter_state = [x1, x2, y1, y2];
+    true
+}
+
+impl Synthesizer for SF2Synthesizer {
+    fn configure(&mut self, settings: &TOMLSynth, sample_rate: u64) -> Result<(), Error> {
+        self.core.configure(settings, sample_rate)?;
+        if settings.reverb.is_some() || settings.chorus.is_some() || settings.setting.is_some() {
+            warn!("The sf2 synthesizer has no reverb, chorus or FluidSynth settings, ignoring them");
+        }
+        Ok(())
+    }
+
+    fn load_resources(&mut self, settings: &TOMLSynth, resources: &ResourcePaths) -> Result<(), Error> {
+        if let Some(ref soundfonts) = settings.soundfont {
+            for soundfont in soundfonts {
+                let soundfont_file = resources.find(&soundfont.file);
+                info!("Loading soundfont '{}' with offset {}", soundfont_file.to_str().unwrap(), soundfont.offset);
+                self.load_soundfont(&soundfont_file, soundfont.offset)?;
+            }
+        }
+
+        self.core.router = mapping::generate_router(settings, resources)?;
+        for destination in self.core.router.mapping.iter().flat_map(|m| m.destinations.iter()) {
+            ensure!(destination.instrument.is_none(), "Destination instrument requires synthtype 'sfz' or 'audio'");
+            ensure!(destination.soundfont >= 1 && destination.soundfont as usize <= self.soundfonts.len(),
+                    "Destination soundfont must be between 1 and the number of loaded soundfonts");
+        }
+        self.restore_channels();
+        Ok(())
+    }
+
+    fn schedule_event(&mut self, time: f64, message: &MIDIMessage) -> Result<(), Error> {
+        sampler::schedule_event(self, time, message);
+        Ok(())
+    }
+
+    fn render_block(&mut self, buffer: &mut [f32]) -> Result<(), Error> {
+        sampler::render_block(self, buffer);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        sampler::reset(self);
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        sampler::finish(self);
+    }
+}
@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use types::*;
+use audiofile;
+
+/// Parses a key number or a note name like "c#4" or "eb3", where c4 is key 60.
+pub fn parse_key(value: &str) -> Result<u8, Error> {
+    if let Ok(key) = value.parse::<i32>() {
+        ensure!(key >= 0 && key <= 127, "Keys in SFZ files must be between 0 and 127");
+        return Ok(key as u8);
+    }
+
+    let value = value.to_lowercase();
+    let pitch_class = match value.chars().next() {
+        Some('c') => 0,
+        Some('d') => 2,
+        Some('e') => 4,
+        Some('f') => 5,
+        Some('g') => 7,
+        Some('a') => 9,
+        Some('b') => 11,
+        _ => fail!("Not a valid key in SFZ file: '{}'", value),
+    };
+    let rest = &value[1..];
+    let (accidental, octave) = if rest.starts_with('#') {
+        (1, &rest[1..])
+    } else if rest.starts_with('b') {
+        (-1, &rest[1..])
+    } else {
+        (0, rest)
+    };
+    let octave: i32 = octave.parse().map_err(|_| Error::new(format!("Not a valid key in SFZ file: '{}'", value)))?;
+    let key = (octave + 1) * 12 + pitch_class + accidental;
+    ensure!(key >= 0 && key <= 127, "Keys in SFZ files must be between 0 and 127");
+    Ok(key as u8)
+}
+
+fn strip_comments(text: &str) -> String {
+    let mut res = String::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        if rest.starts_with("//") {
+            rest = rest.find('\n').map_or("", |end| &rest[end..]);
+        } else if rest.starts_with("/*") {
+            rest = rest.find("*/").map_or("", |end| &rest[end + 2..]);
+        } else {
+            let c = rest.chars().next().unwrap();
+            res.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    res
+}
+
+/// Splits SFZ text into headers and their opcodes. Values may contain spaces, e.g. sample paths.
+fn parse_sections(text: &str) -> Vec<(String, Vec<(String, String)>)> {
+    let text = strip_comments(text).replace('<', " <").replace('>', "> ");
+    let mut res: Vec<(String, Vec<(String, String)>)> = Vec::new();
+    let mut skip = 0;
+    for token in text.split_whitespace() {
+        if skip > 0 {
+            skip -= 1;
+        } else if token.starts_with('#') {
+            warn!("Ignoring unsupported SFZ directive {}", token);
+            skip = if token == "#define" { 2 } else { 1 };
+        } else if token.starts_with('<') && token.ends_with('>') {
+            res.push((token[1..token.len() - 1].to_string(), Vec::new()));
+        } else if let Some(section) = res.last_mut() {
+            match token.find('=') {
+                Some(split) => section.1.push((token[..split].to_string(), token[split + 1..].to_string())),
+                None => match section.1.last_mut() {
+                    Some(opcode) => {
+                        opcode.1.push(' ');
+                        opcode.1.push_str(token);
+                    }
+                    None => warn!("Ignoring '{}' in SFZ file", token),
+                },
+            }
+        } else {
+            warn!("Ignoring '{}' before the first header of SFZ file", token);
+        }
+    }
+    res
+}
+
+fn opcode<'a>(opcodes: &'a HashMap<String, String>, names: &[&str]) -> Option<&'a str> {
+    names.iter().filter_map(|n| opcodes.get(*n)).next().map(|v| v.as_str())
+}
+
+fn number(opcodes: &HashMap<String, String>, names: &[&str], default: f64) -> Result<f64, Error> {
+    match opcode(opcodes, names) {
+        Some(value) => value.parse().map_err(|_| Error::new(format!("Not a valid value for SFZ opcode {}: '{}'", names[0], value))),
+        None => Ok(default),
+    }
+}
+
+fn key(opcodes: &HashMap<String, String>, name: &str, default: u8) -> Result<u8, Error> {
+    opcode(opcodes, &[name]).map_or(Ok(default), parse_key)
+}
+
+/// The sample file of a region, None for regions without sample or with a generator like *sine.
+fn sample_file(opcodes: &HashMap<String, String>, directory: &Path) -> Option<PathBuf> {
+    opcode(opcodes, &["sample"])
+        .filter(|s| !s.starts_with('*'))
+        .map(|s| directory.join(s.replace('\\', "/")))
+}
+
+fn generate_region(opcodes: &HashMap<String, String>, directory: &Path, samples: &mut Vec<AudioSample>, sample_files: &mut HashMap<PathBuf, usize>) -> Result<Option<SFZRegion>, Error> {
+    let file = match sample_file(opcodes, directory) {
+        Some(file) => file,
+        None => {
+            match opcode(opcodes, &["sample"]) {
+                Some(sample) => warn!("Ignoring region with unsupported generator sample {}", sample),
+                None => warn!("Ignoring region without sample"),
+            }
+            return Ok(None);
+        }
+    };
+    let sample = match sample_files.get(&file) {
+        Some(index) => *index,
+        None => {
+            debug!("Loading sample '{}'", file.to_str().unwrap());
+            samples.push(audiofile::load_audio(&file)?);
+            sample_files.insert(file, samples.len() - 1);
+            samples.len() - 1
+        }
+    };
+
+    // key sets the range and the key center at once
+    let key_opcode = match opcode(opcodes, &["key"]) {
+        Some(key) => Some(parse_key(key)?),
+        None => None,
+    };
+    let loop_mode = match opcode(opcodes, &["loop_mode", "loopmode"]) {
+        None | Some("no_loop") => SFZLoopMode::NoLoop,
+        Some("one_shot") => SFZLoopMode::OneShot,
+        Some("loop_continuous") => SFZLoopMode::LoopContinuous,
+        Some("loop_sustain") => SFZLoopMode::LoopSustain,
+        Some(mode) => fail!("Not a valid SFZ loop_mode: '{}'. Use 'no_loop', 'one_shot', 'loop_continuous' or 'loop_sustain'.", mode),
+    };
+    let trigger = match opcode(opcodes, &["trigger"]) {
+        None | Some("attack") | Some("first") | Some("legato") => SFZTrigger::Attack,
+        Some("release") => SFZTrigger::Release,
+        Some(trigger) => fail!("Not a valid SFZ trigger: '{}'. Use 'attack', 'first', 'legato' or 'release'.", trigger),
+    };
+    let position = |names: &[&str]| -> Result<Option<usize>, Error> {
+        match opcode(opcodes, names) {
+            Some(_) => Ok(Some(number(opcodes, names, 0.0)?.max(0.0) as usize)),
+            None => Ok(None),
+        }
+    };
+    let key = |name: &str, default: u8| key_opcode.map_or_else(|| key(opcodes, name, default), Ok);
+
+    let seq_length = number(opcodes, &["seq_length"], 1.0)? as u32;
+    let seq_position = number(opcodes, &["seq_position"], 1.0)? as u32;
+    ensure!(seq_length >= 1 && seq_position >= 1 && seq_position <= seq_length, "SFZ seq_position must be between 1 and seq_length");
+
+    Ok(Some(SFZRegion {
+        sample,
+        lokey: key("lokey", 0)?,
+        hikey: key("hikey", 127)?,
+        lovel: number(opcodes, &["lovel"], 1.0)? as u8,
+        hivel: number(opcodes, &["hivel"], 127.0)? as u8,
+        lorand: number(opcodes, &["lorand"], 0.0)?,
+        hirand: number(opcodes, &["hirand"], 1.0)?,
+        seq_length,
+        seq_position,
+        trigger,
+        pitch_keycenter: key("pitch_keycenter", 60)?,
+        pitch_keytrack: number(opcodes, &["pitch_keytrack"], 100.0)?,
+        tune: number(opcodes, &["tune", "pitch"], 0.0)? + number(opcodes, &["transpose"], 0.0)? * 100.0,
+        volume: number(opcodes, &["volume"], 0.0)?,
+        pan: number(opcodes, &["pan"], 0.0)?.max(-100.0).min(100.0),
+        amp_veltrack: number(opcodes, &["amp_veltrack"], 100.0)?,
+        offset: position(&["offset"])?.unwrap_or(0),
+        end: position(&["end"])?,
+        loop_mode,
+        loop_start: position(&["loop_start", "loopstart"])?,
+        loop_end: position(&["loop_end", "loopend"])?,
+        envelope: [
+            number(opcodes, &["ampeg_delay"], 0.0)?,
+            number(opcodes, &["ampeg_attack"], 0.0)?,
+            number(opcodes, &["ampeg_hold"], 0.0)?,
+            number(opcodes, &["ampeg_decay"], 0.0)?,
+            (number(opcodes, &["ampeg_sustain"], 100.0)? / 100.0).max(0.0).min(1.0),
+            number(opcodes, &["ampeg_release"], 0.001)?,
+        ],
+        group: number(opcodes, &["group"], 0.0)? as u32,
+        off_by: number(opcodes, &["off_by"], 0.0)? as u32,
+    }))
+}
+
+/// Returns the opcodes of all regions merged with the headers above them, and the directory their samples are
+/// relative to.
+fn merged_regions(file: &Path, text: &str) -> Vec<(HashMap<String, String>, PathBuf)> {
+    // Opcodes of the outer headers apply to all regions below them until the header is repeated
+    let mut control = HashMap::new();
+    let mut global = HashMap::new();
+    let mut master = HashMap::new();
+    let mut group = HashMap::new();
+    let mut regions = Vec::new();
+    for (header, opcodes) in parse_sections(text) {
+        match header.as_str() {
+            "control" => control = opcodes.into_iter().collect(),
+            "global" => {
+                global = opcodes.into_iter().collect();
+                master.clear();
+                group.clear();
+            }
+            "master" => {
+                master = opcodes.into_iter().collect();
+                group.clear();
+            }
+            "group" => group = opcodes.into_iter().collect(),
+            "region" => {
+                let mut merged = global.clone();
+                merged.extend(master.clone());
+                merged.extend(group.clone());
+                merged.extend(opcodes);
+                let mut directory = file.parent().unwrap().to_path_buf();
+                if let Some(default_path) = control.get("default_path") {
+                    directory.push(default_path.replace('\\', "/"));
+                }
+                regions.push((merged, directory));
+            }
+            _ => debug!("Ignoring SFZ header <{}>", header),
+        }
+    }
+    regions
+}
+
+/// Loads an SFZ instrument, adding its samples to `samples`. Samples already in `sample_files` are shared.
+pub fn load_instrument(file: &PathBuf, samples: &mut Vec<AudioSample>, sample_files: &mut HashMap<PathBuf, usize>) -> Result<SFZInstrument, Error> {
+    let mut text = String::new();
+    File::open(file)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|_| Error::new(format!("Could not read SFZ file '{}'", file.to_str().unwrap())))?;
+
+    let mut regions = Vec::new();
+    for &(ref opcodes, ref directory) in &merged_regions(file, &text) {
+        regions.extend(generate_region(opcodes, directory, samples, sample_files)?);
+    }
+    info!("Loaded SFZ instrument '{}' with {} regions", file.to_str().unwrap(), regions.len());
+    Ok(SFZInstrument { regions })
+}
+
+/// Returns the sample files an SFZ instrument references, without loading them. An unreadable instrument has none.
+pub fn sample_files(file: &PathBuf) -> Vec<PathBuf> {
+    let mut text = String::new();
+    if File::open(file).and_then(|mut f| f.read_to_string(&mut text)).is_err() {
+        return Vec::new();
+    }
+    merged_regions(file, &text).iter()
+        .filter_map(|&(ref opcodes, ref directory)| sample_file(opcodes, directory))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opcodes(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    /// Generates a region whose sample counts as already loaded, so no file is read.
+    fn region(pairs: &[(&str, &str)]) -> Result<Option<SFZRegion>, Error> {
+        let directory = Path::new("/instrument");
+        let mut sample_files = HashMap::new();
+        sample_files.insert(directory.join("piano.wav"), 3);
+        generate_region(&opcodes(pairs), directory, &mut Vec::new(), &mut sample_files)
+    }
+
+    #[test]
+    fn parse_key_accepts_numbers_and_note_names() {
+        assert_eq!(parse_key("0").unwrap(), 0);
+        assert_eq!(parse_key("127").unwrap(), 127);
+        assert_eq!(parse_key("c4").unwrap(), 60);
+        assert_eq!(parse_key("C#4").unwrap(), 61);
+        assert_eq!(parse_key("eb3").unwrap(), 51);
+        assert_eq!(parse_key("a-1").unwrap(), 9);
+        assert_eq!(parse_key("g9").unwrap(), 127);
+    }
+
+    #[test]
+    fn parse_key_rejects_invalid_keys() {
+        assert_eq!(parse_key("128").unwrap_err().message(), "Keys in SFZ files must be between 0 and 127");
+        assert_eq!(parse_key("c-2").unwrap_err().message(), "Keys in SFZ files must be between 0 and 127");
+        assert_eq!(parse_key("h4").unwrap_err().message(), "Not a valid key in SFZ file: 'h4'");
+        assert_eq!(parse_key("c#").unwrap_err().message(), "Not a valid key in SFZ file: 'c#'");
+    }
+
+    #[test]
+    fn merged_regions_inherit_the_headers_above_them() {
+        let text = "// Piano\n<control> default_path=samples\\\n\
+                    <global> volume=-3 /* quieter */ <group> lokey=c4 hikey=b4\n\
+                    <region> sample=soft piano.wav hivel=63\n<region> sample=loud.wav lovel=64\n\
+                    <group> key=72 <region> sample=*sine";
+        let regions = merged_regions(Path::new("/instrument/piano.sfz"), text);
+        assert_eq!(regions.len(), 3);
+
+        let (ref first, ref directory) = regions[0];
+        assert_eq!(directory, &PathBuf::from("/instrument/samples/"));
+        assert_eq!(first, &opcodes(&[("volume", "-3"), ("lokey", "c4"), ("hikey", "b4"), ("sample", "soft piano.wav"), ("hivel", "63")]));
+        assert_eq!(regions[1].0.get("lovel").map(|v| v.as_str()), Some("64"));
+        assert_eq!(regions[1].0.get("hivel"), None);
+        // A new group replaces the opcodes of the previous one
+        assert_eq!(regions[2].0, opcodes(&[("volume", "-3"), ("key", "72"), ("sample", "*sine")]));
+    }
+
+    #[test]
+    fn generate_region_reads_opcodes() {
+        let region = region(&[("sample", "piano.wav"), ("lokey", "c4"), ("hikey", "64"), ("pitch_keycenter", "62"),
+                              ("tune", "-10"), ("transpose", "1"), ("pan", "-150"), ("loop_mode", "loop_sustain"),
+                              ("ampeg_sustain", "50"), ("seq_length", "2"), ("seq_position", "2")]).unwrap().unwrap();
+        assert_eq!(region.sample, 3);
+        assert_eq!((region.lokey, region.hikey, region.pitch_keycenter), (60, 64, 62));
+        assert_eq!((region.lovel, region.hivel), (1, 127));
+        assert_eq!(region.tune, 90.0);
+        assert_eq!(region.pan, -100.0);
+        assert_eq!(region.loop_mode, SFZLoopMode::LoopSustain);
+        assert_eq!(region.envelope[4], 0.5);
+        assert_eq!((region.seq_length, region.seq_position), (2, 2));
+        assert_eq!(region.end, None);
+    }
+
+    #[test]
+    fn generate_region_key_sets_range_and_center() {
+        let region = region(&[("sample", "piano.wav"), ("key", "d4"), ("lokey", "0")]).unwrap().unwrap();
+        assert_eq!((region.lokey, region.hikey, region.pitch_keycenter), (62, 62, 62));
+    }
+
+    #[test]
+    fn generate_region_skips_regions_without_sample_file() {
+        assert!(region(&[("sample", "*sine")]).unwrap().is_none());
+        assert!(region(&[("lokey", "60")]).unwrap().is_none());
+    }
+
+    #[test]
+    fn generate_region_rejects_invalid_opcodes() {
+        let error = region(&[("sample", "piano.wav"), ("loop_mode", "forever")]).unwrap_err();
+        assert!(error.message().starts_with("Not a valid SFZ loop_mode: 'forever'"), "{}", error);
+        let error = region(&[("sample", "piano.wav"), ("volume", "loud")]).unwrap_err();
+        assert_eq!(error.message(), "Not a valid value for SFZ opcode volume: 'loud'");
+        let error = region(&[("sample", "piano.wav"), ("seq_length", "2"), ("seq_position", "3")]).unwrap_err();
+        assert_eq!(error.message(), "SFZ seq_position must be between 1 and seq_length");
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::path::PathBuf;
+
+use types::*;
+use mapping;
+use sampler;
+use sampler::Sampler;
+use sfz;
+use synthesizer::Synthesizer;
+
+// Leaves headroom for several notes of samples normalized to full scale
+const SFZ_MASTER_GAIN: f64 = 0.5;
+const SFZ_MAX_VOICES: usize = 256;
+
+const CC_EXPRESSION: u8 = 11;
+const CC_DATA_ENTRY: u8 = 6;
+const CC_DATA_ENTRY_LSB: u8 = 38;
+const CC_SUSTAIN: u8 = 64;
+const CC_RPN_LSB: u8 = 100;
+const CC_RPN_MSB: u8 = 101;
+const CC_ALL_SOUND_OFF: u8 = 120;
+const CC_ALL_NOTES_OFF: u8 = 123;
+
+const RANDOM_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+impl SFZSynthesizer {
+    pub fn new() -> SFZSynthesizer {
+        SFZSynthesizer {
+            core: SamplerCore::new("the sfz synthesizer"),
+            samples: Vec::new(),
+            instruments: Vec::new(),
+            channel_instruments: Vec::new(),
+            channels: Vec::new(),
+            voices: Vec::new(),
+            master_volume: 1.0,
+            random: RANDOM_SEED,
+        }
+    }
+
+    /// Returns a pseudo random number between 0.0 and 1.0 (xorshift), the same sequence for every render.
+    fn next_random(&mut self) -> f64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        (self.random >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn restore_channels(&mut self) {
+        self.channels = self.channel_instruments.iter().map(|i| new_channel(*i)).collect();
+        let mut controllers = Vec::new();
+        for mapping in &self.core.router.mapping {
+            for destination in &mapping.destinations {
+                for (control, value) in mapping::initial_controllers(destination, &mapping.controller_rules) {
+                    controllers.push((destination.channel, control, value));
+                }
+            }
+        }
+        for (channel, control, value) in controllers {
+            self.channels[channel as usize].controllers[control as usize] = value;
+        }
+    }
+
+    /// Starts all regions of the channel's instrument matching the note and trigger.
+    fn start_regions(&mut self, channel: u8, key: u8, velocity: u8, trigger: SFZTrigger, sequence: u32) {
+        let random = self.next_random();
+        let instrument = self.channels[channel as usize].instrument;
+        let (key_cents, detune) = {
+            let destination = self.core.router.destination(channel);
+            let key_cents = match destination.tuning {
+                Some(tuning) => self.core.router.tunings[tuning][key as usize],
+                None => f64::from(key) * 100.0,
+            };
+            (key_cents, destination.detune)
+        };
+
+        for (index, region) in self.instruments[instrument].regions.iter().enumerate() {
+            if region.trigger != trigger || key < region.lokey || key > region.hikey || velocity < region.lovel || velocity > region.hivel
+                || random < region.lorand || random >= region.hirand || sequence % region.seq_length + 1 != region.seq_position {
+                continue;
+            }
+            // Starting a region of a group silences the regions it turns off
+            if region.group != 0 {
+                let instruments = &self.instruments;
+                self.voices.retain(|v| v.channel != channel || instruments[v.instrument].regions[v.region].off_by != region.group);
+            }
+
+            let sample = &self.samples[region.sample];
+            let frames = sample.data.len() / sample.channels;
+            let end = region.end.map_or(frames, |e| e + 1).min(frames);
+            if region.offset >= end {
+                continue;
+            }
+            let velocity_gain = 1.0 - region.amp_veltrack / 100.0 * (1.0 - (f64::from(velocity) / 127.0).powi(2));
+
+            if self.voices.len() >= SFZ_MAX_VOICES {
+                let index = self.voices.iter().position(|v| v.released).unwrap_or(0);
+                self.voices.remove(index);
+            }
+            self.voices.push(SFZVoice {
+                channel,
+                key,
+                instrument,
+                region: index,
+                pitch: (key_cents - f64::from(region.pitch_keycenter) * 100.0) * region.pitch_keytrack / 100.0 + region.tune + detune,
+                amplitude: 10.0f64.powf(region.volume / 20.0) * velocity_gain.max(0.0),
+                position: region.offset as f64,
+                end,
+                loop_start: region.loop_start.unwrap_or(0).min(end - 1),
+                // Loop ends are inclusive in SFZ
+                loop_end: region.loop_end.map_or(end, |e| e + 1).min(end),
+                envelope: new_envelope(),
+                released: false,
+                sustained: false,
+            });
+        }
+    }
+
+    fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        let sequence = {
+            let channel = &mut self.channels[channel as usize];
+            channel.velocities[key as usize] = velocity;
+            channel.sequence[key as usize] += 1;
+            channel.sequence[key as usize] - 1
+        };
+        self.start_regions(channel, key, velocity, SFZTrigger::Attack, sequence);
+    }
+
+    fn note_off(&mut self, channel: u8, key: u8) {
+        let (sustain, velocity, sequence) = {
+            let channel = &self.channels[channel as usize];
+            (channel.controllers[CC_SUSTAIN as usize] >= 64, channel.velocities[key as usize], channel.sequence[key as usize].saturating_sub(1))
+        };
+        {
+            let instruments = &self.instruments;
+            for voice in self.voices.iter_mut().filter(|v| v.channel == channel && v.key == key && !v.released) {
+                if instruments[voice.instrument].regions[voice.region].loop_mode == SFZLoopMode::OneShot {
+                    continue;
+                }
+                if sustain {
+                    voice.sustained = true;
+                } else {
+                    release_voice(voice);
+                }
+            }
+        }
+        self.start_regions(channel, key, velocity, SFZTrigger::Release, sequence);
+    }
+
+    fn control_change(&mut self, channel: u8, control: u8, value: u8) {
+        self.channels[channel as usize].controllers[control as usize] = value;
+        match control {
+            CC_DATA_ENTRY | CC_DATA_ENTRY_LSB => {
+                let channel = &mut self.channels[channel as usize];
+                if channel.controllers[CC_RPN_MSB as usize] == 0 && channel.controllers[CC_RPN_LSB as usize] == 0 {
+                    channel.bend_range = f64::from(channel.controllers[CC_DATA_ENTRY as usize]) * 100.0 + f64::from(channel.controllers[CC_DATA_ENTRY_LSB as usize]);
+                }
+            }
+            CC_SUSTAIN if value < 64 => {
+                for voice in self.voices.iter_mut().filter(|v| v.channel == channel && v.sustained) {
+                    release_voice(voice);
+                }
+            }
+            CC_ALL_SOUND_OFF => self.voices.retain(|v| v.channel != channel),
+            CC_ALL_NOTES_OFF => {
+                for voice in self.voices.iter_mut().filter(|v| v.channel == channel) {
+                    release_voice(voice);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Sampler for SFZSynthesizer {
+    fn core(&mut self) -> &mut SamplerCore {
+        &mut self.core
+    }
+
+    /// SFZ instruments have no drum parts.
+    fn interprets_sysex(&self, kind: SysExKind) -> bool {
+        match kind {
+            SysExKind::DrumPart { .. } => false,
+            _ => true,
+        }
+    }
+
+    fn process_event(&mut self, event: SamplerEvent) {
+        match event {
+            SamplerEvent::Message(message) => match message {
+                MIDIMessage::NoteOn { channel, key, velocity } => self.note_on(channel, key, velocity),
+                MIDIMessage::NoteOff { channel, key } => self.note_off(channel, key),
+                MIDIMessage::ControlChange { channel, control, value } => self.control_change(channel, control, value),
+                MIDIMessage::PitchBend { channel, value } => self.channels[channel as usize].pitch_bend = value,
+                _ => {}
+            },
+            SamplerEvent::SystemReset => {
+                self.voices.clear();
+                self.master_volume = 1.0;
+                self.restore_channels();
+            }
+            SamplerEvent::MasterVolume(volume) => self.master_volume = volume,
+            SamplerEvent::DrumChannels { .. } | SamplerEvent::Clip(_) => {}
+        }
+    }
+
+    fn render_voices(&mut self, buffer: &mut [f32]) {
+        let gain = SFZ_MASTER_GAIN * f64::from(self.master_volume);
+        let sample_rate = self.core.sample_rate as f64;
+        let mut i = 0;
+        while i < self.voices.len() {
+            let alive = {
+                let voice = &mut self.voices[i];
+                let region = &self.instruments[voice.instrument].regions[voice.region];
+                render_voice(voice, region, &self.samples[region.sample], &self.channels[voice.channel as usize], sample_rate, gain, buffer)
+            };
+            if alive {
+                i += 1;
+            } else {
+                self.voices.remove(i);
+            }
+        }
+    }
+
+    fn reset_voices(&mut self) {
+        self.voices.clear();
+        self.master_volume = 1.0;
+        // Renders after a reset pick the same random regions again
+        self.random = RANDOM_SEED;
+        self.restore_channels();
+    }
+}
+
+fn new_channel(instrument: usize) -> SFZChannel {
+    let mut controllers = [0; 128];
+    controllers[mapping::CC_VOLUME as usize] = 100;
+    controllers[mapping::CC_PAN as usize] = 64;
+    controllers[CC_EXPRESSION as usize] = 127;
+    controllers[CC_RPN_MSB as usize] = 127;
+    controllers[CC_RPN_LSB as usize] = 127;
+    SFZChannel {
+        instrument,
+        controllers,
+        pitch_bend: 8192,
+        bend_range: 200.0,
+        sequence: [0; 128],
+        velocities: [0; 128],
+    }
+}
+
+fn new_envelope() -> SFZEnvelope {
+    SFZEnvelope {
+        stage: SF2EnvelopeStage::Delay,
+        stage_time: 0.0,
+        value: 0.0,
+        release_level: 0.0,
+    }
+}
+
+fn release_envelope(envelope: &mut SFZEnvelope) {
+    match envelope.stage {
+        SF2EnvelopeStage::Release | SF2EnvelopeStage::Finished => return,
+        SF2EnvelopeStage::Delay => envelope.value = 0.0,
+        _ => {}
+    }
+    envelope.stage = SF2EnvelopeStage::Release;
+    envelope.stage_time = 0.0;
+    envelope.release_level = envelope.value;
+}
+
+/// Advances an ampeg envelope by `seconds`. Parameters are delay, attack, hold, decay, sustain level and release.
+/// Unlike in SF2, the decay is the time to fall from full level to the sustain level and the release the time to
+/// fall from the level the note was released at to zero, both linear in amplitude.
+fn advance_envelope(envelope: &mut SFZEnvelope, parameters: &[f64; 6], seconds: f64) {
+    envelope.stage_time += seconds;
+    loop {
+        let duration = match envelope.stage {
+            SF2EnvelopeStage::Delay => parameters[0],
+            SF2EnvelopeStage::Attack => parameters[1],
+            SF2EnvelopeStage::Hold => parameters[2],
+            SF2EnvelopeStage::Decay => parameters[3],
+            SF2EnvelopeStage::Release => parameters[5],
+            SF2EnvelopeStage::Sustain | SF2EnvelopeStage::Finished => return,
+        };
+        let progress = if duration > 0.0 { envelope.stage_time / duration } else { 1.0 };
+        if progress < 1.0 {
+            envelope.value = match envelope.stage {
+                SF2EnvelopeStage::Attack => progress,
+                SF2EnvelopeStage::Hold => 1.0,
+                SF2EnvelopeStage::Decay => 1.0 - (1.0 - parameters[4]) * progress,
+                SF2EnvelopeStage::Release => envelope.release_level * (1.0 - progress),
+                _ => envelope.value,
+            };
+            return;
+        }
+        envelope.stage_time -= duration;
+        let (stage, value) = match envelope.stage {
+            SF2EnvelopeStage::Delay => (SF2EnvelopeStage::Attack, 0.0),
+            SF2EnvelopeStage::Attack => (SF2EnvelopeStage::Hold, 1.0),
+            SF2EnvelopeStage::Hold => (SF2EnvelopeStage::Decay, 1.0),
+            SF2EnvelopeStage::Decay => (SF2EnvelopeStage::Sustain, parameters[4]),
+            _ => (SF2EnvelopeStage::Finished, 0.0),
+        };
+        envelope.stage = stage;
+        envelope.value = value;
+    }
+}
+
+fn release_voice(voice: &mut SFZVoice) {
+    voice.released = true;
+    voice.sustained = false;
+    release_envelope(&mut voice.envelope);
+}
+
+/// Adds one voice to interleaved stereo frames, returns whether it is still sounding.
+fn render_voice(voice: &mut SFZVoice, region: &SFZRegion, sample: &AudioSample, channel: &SFZChannel, sample_rate: f64, gain: f64, buffer: &mut [f32]) -> bool {
+    let bend = (f64::from(channel.pitch_bend) - 8192.0) / 8192.0 * channel.bend_range;
+    let step = 2.0f64.powf((voice.pitch + bend) / 1200.0) * sample.sample_rate / sample_rate;
+
+    let volume = (f64::from(channel.controllers[mapping::CC_VOLUME as usize]) / 127.0).powi(2)
+        * (f64::from(channel.controllers[CC_EXPRESSION as usize]) / 127.0).powi(2);
+    let amplitude = voice.amplitude * volume * gain;
+    let pan = region.pan + (f64::from(channel.controllers[mapping::CC_PAN as usize]) - 64.0) / 63.0 * 100.0;
+    let pan = (pan.max(-100.0).min(100.0) + 100.0) / 200.0;
+    // Constant power pan, stereo samples keep their level in the center
+    let scale = if sample.channels == 2 { 2.0f64.sqrt() } else { 1.0 };
+    let (left, right) = ((pan * PI / 2.0).cos() * amplitude * scale, (pan * PI / 2.0).sin() * amplitude * scale);
+
+    let looping = region.loop_mode == SFZLoopMode::LoopContinuous || (region.loop_mode == SFZLoopMode::LoopSustain && !voice.released);
+    let frame_duration = 1.0 / sample_rate;
+    let channels = sample.channels;
+
+    for frame in buffer.chunks_mut(2) {
+        advance_envelope(&mut voice.envelope, &region.envelope, frame_duration);
+        let envelope = match voice.envelope.stage {
+            SF2EnvelopeStage::Finished => return false,
+            SF2EnvelopeStage::Delay => 0.0,
+            _ => voice.envelope.value,
+        };
+
+        let index = voice.position as usize;
+        let fraction = voice.position - index as f64;
+        let next = if looping && index + 1 >= voice.loop_end { voice.loop_start } else { index + 1 };
+        let value = |frame: usize, channel: usize| if frame < voice.end { f64::from(sample.data[frame * channels + channel]) } else { 0.0 };
+        let interpolate = |channel: usize| value(index, channel) + (value(next, channel) - value(index, channel)) * fraction;
+        let (l, r) = if channels == 2 { (interpolate(0), interpolate(1)) } else { (interpolate(0), interpolate(0)) };
+
+        frame[0] += (l * left * envelope) as f32;
+        frame[1] += (r * right * envelope) as f32;
+
+        voice.position += step;
+        if looping && voice.loop_start < voice.loop_end {
+            while voice.position >= voice.loop_end as f64 {
+                voice.position -= (voice.loop_end - voice.loop_start) as f64;
+            }
+        } else if voice.position >= voice.end as f64 {
+            return false;
+        }
+    }
+    true
+}
+
+impl Synthesizer for SFZSynthesizer {
+    fn configure(&mut self, settings: &TOMLSynth, sample_rate: u64) -> Result<(), Error> {
+        self.core.configure(settings, sample_rate)?;
+        if settings.reverb.is_some() || settings.chorus.is_some() || settings.setting.is_some() || settings.soundfont.is_some() {
+            warn!("The sfz synthesizer has no soundfonts, reverb, chorus or FluidSynth settings, ignoring them");
+        }
+        Ok(())
+    }
+
+    fn load_resources(&mut self, settings: &TOMLSynth, resources: &ResourcePaths) -> Result<(), Error> {
+        let directory = settings.directory.as_ref().map_or_else(PathBuf::new, PathBuf::from);
+
+        self.core.router = mapping::generate_router(settings, resources)?;
+        let mut instrument_files: HashMap<PathBuf, usize> = HashMap::new();
+        let mut sample_files = HashMap::new();
+        self.channel_instruments = vec![0; self.core.router.used_channels];
+        for destination in self.core.router.mapping.iter().flat_map(|m| m.destinations.iter()) {
+            let instrument = destination.instrument.as_ref().ok_or_else(|| Error::new("Destinations of synthtype 'sfz' must contain instrument"))?;
+            let file = resources.find(directory.join(instrument));
+            let instrument = match instrument_files.get(&file) {
+                Some(index) => *index,
+                None => {
+                    info!("Loading SFZ instrument '{}'", file.to_str().unwrap());
+                    self.instruments.push(sfz::load_instrument(&file, &mut self.samples, &mut sample_files)?);
+                    self.instruments.len() - 1
+                }
+            };
+            instrument_files.insert(file, instrument);
+            self.channel_instruments[destination.channel as usize] = instrument;
+        }
+        self.restore_channels();
+        Ok(())
+    }
+
+    fn schedule_event(&mut self, time: f64, message: &MIDIMessage) -> Result<(), Error> {
+        sampler::schedule_event(self, time, message);
+        Ok(())
+    }
+
+    fn render_block(&mut self, buffer: &mut [f32]) -> Result<(), Error> {
+        sampler::render_block(self, buffer);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        sampler::reset(self);
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        sampler::finish(self);
+    }
+}
@@ -0,0 +1,24 @@
+use std::io::{self, Read, Write};
+
+use types::*;
+
+/// File name standing for standard input or standard output.
+pub const STANDARD_STREAM: &str = "-";
+
+pub fn is_standard_stream(file: &str) -> bool {
+    file == STANDARD_STREAM
+}
+
+pub fn read_stdin() -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    io::stdin().read_to_end(&mut data).map_err(|_| Error::new("Could not read standard input"))?;
+    Ok(data)
+}
+
+pub fn write_stdout(data: &[u8]) -> Result<(), Error> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(data)
+        .and_then(|_| stdout.flush())
+        .map_err(|_| Error::new("Could not write standard output"))
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+use types::*;
+
+/// A backend rendering MIDI events for one `[synth.<id>]` table, selected by its `synthtype`.
+pub trait Synthesizer {
+    /// Applies all settings which have to be known before resources are loaded.
+    fn configure(&mut self, settings: &TOMLSynth, sample_rate: u64) -> Result<(), Error>;
+
+    /// Loads SoundFonts, samples and other files relative to the resource directory and builds the mapping.
+    fn load_resources(&mut self, settings: &TOMLSynth, resources: &ResourcePaths) -> Result<(), Error>;
+
+    /// Called with the tempo map of the MIDI file before the first event is scheduled.
+    fn set_tempo_map(&mut self, _tempo_map: &TempoMap) {}
+
+    /// Schedules a MIDI message at `time` (in microseconds). Events are passed in chronological order.
+    fn schedule_event(&mut self, time: f64, message: &MIDIMessage) -> Result<(), Error>;
+
+    /// Time in microseconds up to which the synthesizer sounds regardless of the MIDI events, e.g. the end of an audio
+    /// clip. Known once all events are scheduled.
+    fn end_time(&self) -> f64 {
+        0.0
+    }
+
+    /// Renders the next block of interleaved stereo frames into `buffer`, which is zeroed.
+    fn render_block(&mut self, buffer: &mut [f32]) -> Result<(), Error>;
+
+    /// Drops all scheduled events and returns to the state after `load_resources`.
+    fn reset(&mut self) -> Result<(), Error>;
+
+    /// Called after the last block was rendered.
+    fn finish(&mut self) {}
+}
+
+pub const DEFAULT_SYSEX: SynthesizerSysEx = SynthesizerSysEx {
+    reset: SysExHandling::Honour,
+    master_volume: SysExHandling::Honour,
+    drum_part: SysExHandling::Honour,
+    other: SysExHandling::Honour,
+};
+
+/// Creates a synthesizer, backends which need settings to be constructed get the synth table and the sample rate.
+pub type SynthesizerConstructor = fn(&TOMLSynth, u64) -> Result<Box<dyn Synthesizer>, Error>;
+
+fn new_fluid_synthesizer(settings: &TOMLSynth, sample_rate: u64) -> Result<Box<dyn Synthesizer>, Error> {
+    Ok(Box::new(FluidSynthesizer::new(settings, sample_rate)?))
+}
+
+fn new_sf2_synthesizer(_settings: &TOMLSynth, _sample_rate: u64) -> Result<Box<dyn Synthesizer>, Error> {
+    Ok(Box::new(SF2Synthesizer::new()))
+}
+
+fn new_sfz_synthesizer(_settings: &TOMLSynth, _sample_rate: u64) -> Result<Box<dyn Synthesizer>, Error> {
+    Ok(Box::new(SFZSynthesizer::new()))
+}
+
+fn new_test_synthesizer(_settings: &TOMLSynth, _sample_rate: u64) -> Result<Box<dyn Synthesizer>, Error> {
+    Ok(Box::new(TestSynthesizer::new()))
+}
+
+fn new_audio_synthesizer(_settings: &TOMLSynth, _sample_rate: u64) -> Result<Box<dyn Synthesizer>, Error> {
+    Ok(Box::new(AudioSynthesizer::new()))
+}
+
+impl SynthesizerSysEx {
+    pub fn handling_of(&self, kind: SysExKind) -> SysExHandling {
+        match kind {
+            SysExKind::GMSystemOn | SysExKind::GMSystemOff | SysExKind::GSReset | SysExKind::XGSystemOn => self.reset,
+            SysExKind::MasterVolume(_) => self.master_volume,
+            SysExKind::DrumPart { .. } => self.drum_part,
+            SysExKind::Part { .. } | SysExKind::Other => self.other,
+        }
+    }
+}
+
+fn sysex_handling_of(name: &Option<String>, translatable: bool) -> Result<SysExHandling, Error> {
+    match name.as_ref().map(|n| n.as_str()) {
+        None | Some("honour") => Ok(SysExHandling::Honour),
+        Some("ignore") => Ok(SysExHandling::Ignore),
+        Some("translate") if translatable => Ok(SysExHandling::Translate),
+        Some(name) => fail!("Not a valid SysEx handling: '{}'. Use 'honour', 'ignore'{}.", name, if translatable { " or 'translate'" } else { "" }),
+    }
+}
+
+pub fn generate_sysex(settings: &TOMLSynthSysEx) -> Result<SynthesizerSysEx, Error> {
+    Ok(SynthesizerSysEx {
+        reset: sysex_handling_of(&settings.reset, true)?,
+        master_volume: sysex_handling_of(&settings.master_volume, true)?,
+        drum_part: sysex_handling_of(&settings.drum_part, true)?,
+        other: sysex_handling_of(&settings.other, false)?,
+    })
+}
+
+/// Warns about SysEx messages a backend could not interpret.
+pub fn report_unhandled_sysex(backend: &str, messages: &[Vec<u8>]) {
+    if messages.is_empty() {
+        return;
+    }
+    warn!("{} SysEx message{} not handled by {}:", messages.len(), if messages.len() == 1 { " was" } else { "s were" }, backend);
+    for data in messages {
+        let hex: Vec<String> = data.iter().map(|b| format!("{:02X}", b)).collect();
+        warn!(" => F0 {} F7", hex.join(" "));
+    }
+}
+
+/// Returns the constructors of all backends, keyed by `synthtype`.
+pub fn registry() -> HashMap<&'static str, SynthesizerConstructor> {
+    let mut registry: HashMap<&'static str, SynthesizerConstructor> = HashMap::new();
+    registry.insert("fluidsynth", new_fluid_synthesizer);
+    registry.insert("sf2", new_sf2_synthesizer);
+    registry.insert("sfz", new_sfz_synthesizer);
+    registry.insert("test", new_test_synthesizer);
+    registry.insert("audio", new_audio_synthesizer);
+    registry
+}
+
+pub fn generate_synthesizers(settings: &TOMLRenderSettings, resources: &ResourcePaths) -> Result<Vec<SynthesizerInstance>, Error> {
+    let registry = registry();
+    let mut res = Vec::new();
+    for (id, synthsettings) in &settings.synth {
+        let constructor = match registry.get(synthsettings.synthtype.as_str()) {
+            Some(constructor) => constructor,
+            None => {
+                let mut known: Vec<&str> = registry.keys().cloned().collect();
+                known.sort();
+                fail!("Synth '{}' has an unknown synthtype: '{}'. Supported types are: {}", id, synthsettings.synthtype, known.join(", "));
+            }
+        };
+
+        info!("Building {} synthesizer '{}'", synthsettings.synthtype, id);
+        let mut synthesizer = constructor(synthsettings, settings.sample_rate)?;
+        synthesizer.configure(synthsettings, settings.sample_rate)?;
+        synthesizer.load_resources(synthsettings, resources)?;
+
+        res.push(SynthesizerInstance {
+            id: id.clone(),
+            gain: synthsettings.gain,
+            synthesizer,
+        });
+    }
+    Ok(res)
+}
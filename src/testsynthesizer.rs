@@ -0,0 +1,142 @@
+use std::f64::consts::PI;
+
+use types::*;
+use mapping;
+use sampler;
+use sampler::Sampler;
+use synthesizer::Synthesizer;
+
+// Peak amplitude of a note with velocity 127 at full channel volume
+const TEST_AMPLITUDE: f64 = 0.5;
+
+const CC_ALL_SOUND_OFF: u8 = 120;
+const CC_ALL_NOTES_OFF: u8 = 123;
+
+impl TestSynthesizer {
+    pub fn new() -> TestSynthesizer {
+        TestSynthesizer {
+            core: SamplerCore::new("the test synthesizer"),
+            controllers: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    fn restore_channels(&mut self) {
+        let mut controllers = vec![[0; 128]; self.core.router.used_channels];
+        for mapping in &self.core.router.mapping {
+            for destination in &mapping.destinations {
+                for (control, value) in mapping::initial_controllers(destination, &mapping.controller_rules) {
+                    controllers[destination.channel as usize][control as usize] = value;
+                }
+            }
+        }
+        self.controllers = controllers;
+    }
+
+    /// Even destination programs play sines, odd ones squares. The pitch follows the tuning and detune of the destination.
+    fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        let (cents, waveform) = {
+            let destination = self.core.router.destination(channel);
+            let key_cents = match destination.tuning {
+                Some(tuning) => self.core.router.tunings[tuning][key as usize],
+                None => f64::from(key) * 100.0,
+            };
+            (key_cents + destination.detune, if destination.program % 2 == 0 { TestWaveform::Sine } else { TestWaveform::Square })
+        };
+        self.notes.retain(|n| n.channel != channel || n.key != key);
+        self.notes.push(TestNote {
+            channel,
+            key,
+            frequency: 440.0 * 2.0f64.powf((cents - 6900.0) / 1200.0),
+            amplitude: TEST_AMPLITUDE * f64::from(velocity) / 127.0,
+            waveform,
+            age: 0,
+        });
+    }
+}
+
+impl Sampler for TestSynthesizer {
+    fn core(&mut self) -> &mut SamplerCore {
+        &mut self.core
+    }
+
+    /// Master volume and drum parts have no meaning for plain oscillators.
+    fn interprets_sysex(&self, kind: SysExKind) -> bool {
+        kind.is_reset()
+    }
+
+    fn process_event(&mut self, event: SamplerEvent) {
+        match event {
+            SamplerEvent::Message(MIDIMessage::NoteOn { channel, key, velocity }) => self.note_on(channel, key, velocity),
+            SamplerEvent::Message(MIDIMessage::NoteOff { channel, key }) => self.notes.retain(|n| n.channel != channel || n.key != key),
+            SamplerEvent::Message(MIDIMessage::ControlChange { channel, control, value }) => {
+                self.controllers[channel as usize][control as usize] = value;
+                if control == CC_ALL_SOUND_OFF || control == CC_ALL_NOTES_OFF {
+                    self.notes.retain(|n| n.channel != channel);
+                }
+            }
+            SamplerEvent::SystemReset => self.reset_voices(),
+            _ => {}
+        }
+    }
+
+    fn render_voices(&mut self, buffer: &mut [f32]) {
+        let sample_rate = self.core.sample_rate as f64;
+        for note in &mut self.notes {
+            let controllers = &self.controllers[note.channel as usize];
+            let amplitude = note.amplitude * f64::from(controllers[mapping::CC_VOLUME as usize]) / 127.0;
+            // Linear pan which keeps both sides at full level in the center
+            let pan = ((f64::from(controllers[mapping::CC_PAN as usize]) - 64.0) / 63.0).max(-1.0).min(1.0);
+            let (left, right) = ((1.0 - pan).min(1.0) * amplitude, (1.0 + pan).min(1.0) * amplitude);
+
+            for frame in buffer.chunks_mut(2) {
+                let phase = (note.age as f64 * note.frequency / sample_rate).fract();
+                let value = match note.waveform {
+                    TestWaveform::Sine => (2.0 * PI * phase).sin(),
+                    TestWaveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+                };
+                frame[0] += (value * left) as f32;
+                frame[1] += (value * right) as f32;
+                note.age += 1;
+            }
+        }
+    }
+
+    fn reset_voices(&mut self) {
+        self.notes.clear();
+        self.restore_channels();
+    }
+}
+
+impl Synthesizer for TestSynthesizer {
+    fn configure(&mut self, settings: &TOMLSynth, sample_rate: u64) -> Result<(), Error> {
+        self.core.configure(settings, sample_rate)?;
+        Ok(())
+    }
+
+    fn load_resources(&mut self, settings: &TOMLSynth, resources: &ResourcePaths) -> Result<(), Error> {
+        // Only tunings are read from the resource directory
+        self.core.router = mapping::generate_router(settings, resources)?;
+        self.restore_channels();
+        Ok(())
+    }
+
+    fn schedule_event(&mut self, time: f64, message: &MIDIMessage) -> Result<(), Error> {
+        sampler::schedule_event(self, time, message);
+        Ok(())
+    }
+
+    fn render_block(&mut self, buffer: &mut [f32]) -> Result<(), Error> {
+        sampler::render_block(self, buffer);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        sampler::reset(self);
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        sampler::finish(self);
+    }
+}
@@ -1,20 +1,580 @@
-extern crate toml;
+pub(crate) extern crate toml;
 
-use std::fs::File;
+use std::env;
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use types::*;
+use resources;
+use stdio;
 
-pub fn read_input_file(options: &Options) -> TOMLRenderSettings {
-    let input_file = Path::new(&options.input);
-    let mut file = File::open(input_file.to_str().unwrap()).unwrap();
+/// Parses the value of a --set option as TOML, falling back to a string for bare words like sfz.
+fn parse_value(value: &str) -> toml::Value {
+    toml::from_str::<toml::Value>(&format!("value = {}", value)).ok()
+        .and_then(|v| v.get("value").cloned())
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+/// Sets a dotted key like synth.piano.gain, missing tables are created. Array elements are addressed starting at 1.
+fn set_value(root: &mut toml::Value, key: &str, value: toml::Value) -> Result<(), Error> {
+    let parts: Vec<&str> = key.split('.').collect();
+    let mut current = root;
+    for (i, part) in parts.iter().enumerate() {
+        let last = i == parts.len() - 1;
+        let next = match *current {
+            toml::Value::Table(ref mut table) => {
+                if last {
+                    table.insert(part.to_string(), value);
+                    return Ok(());
+                }
+                table.entry(part.to_string()).or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+            }
+            toml::Value::Array(ref mut array) => {
+                let index: usize = part.parse().map_err(|_| Error::new(format!("Not a valid array index in setting '{}': '{}'", key, part)))?;
+                ensure!(index >= 1 && index <= array.len(), "Array index in setting '{}' must be between 1 and {}", key, array.len());
+                if last {
+                    array[index - 1] = value;
+                    return Ok(());
+                }
+                &mut array[index - 1]
+            }
+            _ => fail!("Setting '{}' is not a table or array", parts[..i].join(".")),
+        };
+        current = next;
+    }
+    Ok(())
+}
+
+fn apply_setting(root: &mut toml::Value, setting: &str) -> Result<(), Error> {
+    let split = setting.find('=').ok_or_else(|| Error::new(format!("Not a valid setting: '{}'. Use key=value.", setting)))?;
+    let key = setting[..split].trim();
+    ensure!(!key.is_empty(), "Not a valid setting: '{}'. Use key=value.", setting);
+    let value = parse_value(setting[split + 1..].trim());
+    info!("Overriding '{}' with {}", key, value);
+    set_value(root, key, value)
+}
+
+/// Files given on the command line are relative to the working directory, not to the input file.
+fn absolute_path(file: &str) -> Result<String, Error> {
+    if stdio::is_standard_stream(file) {
+        return Ok(file.to_string());
+    }
+    Ok(env::current_dir().unwrap().join(resources::expand_home(file)?).to_str().unwrap().to_string())
+}
+
+/// Replaces every ${NAME} by `lookup(NAME)`, $${ is a literal ${.
+fn substitute<F: FnMut(&str) -> Result<String, Error>>(text: &str, mut lookup: F) -> Result<String, Error> {
+    let mut res = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            res.push_str(&rest[..start - 1]);
+            res.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        res.push_str(&rest[..start]);
+        let end = start + rest[start..].find('}').ok_or_else(|| Error::new(format!("Not a valid variable reference in '{}'. Use ${{NAME}}.", text)))?;
+        res.push_str(&lookup(&rest[start + 2..end])?);
+        rest = &rest[end + 1..];
+    }
+    res.push_str(rest);
+    Ok(res)
+}
+
+fn text_of(value: &toml::Value) -> String {
+    match *value {
+        toml::Value::String(ref text) => text.clone(),
+        ref value => value.to_string(),
+    }
+}
+
+fn environment_variable(name: &str) -> Result<String, Error> {
+    env::var(name).map_err(|_| Error::new(format!("Not a defined variable: '{}'. Define it in [vars] or the environment.", name)))
+}
+
+/// Replaces ${NAME} by the variable of the [vars] table, or else the environment variable, and a leading '~' by the home directory.
+fn expand_string(text: &str, vars: &toml::value::Table) -> Result<String, Error> {
+    let res = substitute(text, |name| vars.get(name).map_or_else(|| environment_variable(name), |v| Ok(text_of(v))))?;
+    resources::expand_home(&res)
+}
+
+/// Expands the variable `name` of the [vars] table, which may refer to other variables. `stack` holds the variables
+/// being expanded, to report cycles.
+fn resolve_variable(name: &str, vars: &toml::value::Table, resolved: &mut toml::value::Table, stack: &mut Vec<String>) -> Result<toml::Value, Error> {
+    if let Some(value) = resolved.get(name) {
+        return Ok(value.clone());
+    }
+    if stack.iter().any(|n| n == name) {
+        fail!("Variable cycle: {} -> {}", stack.join(" -> "), name);
+    }
+    let value = match vars[name] {
+        toml::Value::String(ref text) => {
+            stack.push(name.to_string());
+            let text = substitute(text, |n| {
+                if vars.contains_key(n) {
+                    resolve_variable(n, vars, resolved, stack).map(|v| text_of(&v))
+                } else {
+                    environment_variable(n)
+                }
+            })?;
+            stack.pop();
+            toml::Value::String(resources::expand_home(&text)?)
+        }
+        ref value => value.clone(),
+    };
+    resolved.insert(name.to_string(), value.clone());
+    Ok(value)
+}
+
+fn expand_value(value: &mut toml::Value, vars: &toml::value::Table) -> Result<(), Error> {
+    match *value {
+        toml::Value::String(ref mut text) => *text = expand_string(text, vars)?,
+        toml::Value::Array(ref mut array) => for element in array.iter_mut() {
+            expand_value(element, vars)?;
+        },
+        toml::Value::Table(ref mut table) => for element in table.values_mut() {
+            expand_value(element, vars)?;
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Removes the [vars] table and expands the variables in all strings. Variables may refer to other variables and to
+/// environment variables.
+fn expand_variables(root: &mut toml::Value) -> Result<(), Error> {
+    let vars = match root.as_table_mut().and_then(|t| t.remove("vars")) {
+        Some(toml::Value::Table(vars)) => vars,
+        Some(_) => fail!("vars must be a table of variables"),
+        None => toml::value::Table::new(),
+    };
+    let mut resolved = toml::value::Table::new();
+    for name in vars.keys() {
+        resolve_variable(name, &vars, &mut resolved, &mut Vec::new())?;
+    }
+    expand_value(root, &resolved)
+}
+
+fn parse_toml(contents: &str, file: &Path) -> Result<toml::value::Table, Error> {
+    toml::from_str(contents).map_err(|e| Error::new(format!("Not a valid TOML file '{}': {}", file.to_str().unwrap(), e)))
+}
+
+fn read_toml(file: &Path) -> Result<toml::value::Table, Error> {
     let mut contents = String::new();
-    if let Err(_) = file.read_to_string(&mut contents) {
-        panic!("Could not read input file!");
+    File::open(file)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|_| Error::new(format!("Could not read input file '{}'", file.to_str().unwrap())))?;
+    parse_toml(&contents, file)
+}
+
+/// Merges the tables of an included file. Other values must be equal in both, anything else is a conflict.
+fn merge_included(target: &mut toml::value::Table, included: toml::value::Table, path: &str, file: &Path) -> Result<(), Error> {
+    for (key, value) in included {
+        let key_path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+        if !target.contains_key(&key) {
+            target.insert(key, value);
+            continue;
+        }
+        match (target.get_mut(&key).unwrap(), value) {
+            (&mut toml::Value::Table(ref mut existing), toml::Value::Table(value)) => merge_included(existing, value, &key_path, file)?,
+            (existing, value) => ensure!(*existing == value, "Conflicting values for '{}' in '{}'", key_path, file.to_str().unwrap()),
+        }
+    }
+    Ok(())
+}
+
+/// Reads a file and the files in its include list, which are relative to the including file. Returns the merged table
+/// and all included files. `stack` holds the files currently being included, to detect cycles.
+fn read_with_includes(file: &Path, stack: &mut Vec<PathBuf>) -> Result<(toml::value::Table, Vec<PathBuf>), Error> {
+    let canonical = fs::canonicalize(file).map_err(|_| Error::new(format!("Could not read input file '{}'", file.to_str().unwrap())))?;
+    if stack.contains(&canonical) {
+        let chain: Vec<&str> = stack.iter().chain(Some(&canonical)).map(|f| f.to_str().unwrap()).collect();
+        fail!("Include cycle: {}", chain.join(" -> "));
+    }
+    stack.push(canonical);
+    let res = merge_includes(read_toml(file)?, file, file.parent().unwrap(), stack);
+    stack.pop();
+    res
+}
+
+/// Merges the files in the include list of `table`, which are relative to `directory`, with the table. Returns the
+/// merged table and all included files.
+fn merge_includes(mut table: toml::value::Table, file: &Path, directory: &Path, stack: &mut Vec<PathBuf>) -> Result<(toml::value::Table, Vec<PathBuf>), Error> {
+    let includes = match table.remove("include") {
+        Some(toml::Value::Array(includes)) => includes,
+        Some(_) => fail!("include in '{}' must be a list of files", file.to_str().unwrap()),
+        None => Vec::new(),
+    };
+    let mut res = toml::value::Table::new();
+    let mut included_files = Vec::new();
+    for include in includes {
+        let include = include.as_str().ok_or_else(|| Error::new(format!("include in '{}' must be a list of files", file.to_str().unwrap())))?.to_string();
+        let included_file = directory.join(&include);
+        debug!("Including '{}'", included_file.to_str().unwrap());
+        let (included, nested) = read_with_includes(&included_file, stack)?;
+        merge_included(&mut res, included, "", &included_file)?;
+        // A file included along several paths is listed once
+        for file in Some(included_file).into_iter().chain(nested) {
+            if !included_files.contains(&file) {
+                included_files.push(file);
+            }
+        }
+    }
+    merge_included(&mut res, table, "", file)?;
+    Ok((res, included_files))
+}
+
+/// Merges a table over the preset it extends: tables are merged, other values replace those of the preset.
+fn merge_extending(base: &mut toml::value::Table, table: toml::value::Table) {
+    for (key, value) in table {
+        match (base.get_mut(&key), value) {
+            (Some(&mut toml::Value::Table(ref mut existing)), toml::Value::Table(value)) => merge_extending(existing, value),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn extend(mut table: toml::value::Table, presets: &toml::value::Table, stack: &mut Vec<String>) -> Result<toml::value::Table, Error> {
+    let preset = match table.remove("extends") {
+        Some(toml::Value::String(preset)) => preset,
+        Some(_) => fail!("extends must be the name of a preset"),
+        None => return Ok(table),
+    };
+    if stack.contains(&preset) {
+        fail!("Preset cycle: {} -> {}", stack.join(" -> "), preset);
+    }
+    let mut base = match presets.get(&preset) {
+        Some(&toml::Value::Table(ref base)) => base.clone(),
+        _ => fail!("Not a valid preset: '{}'. Define it in a [preset.{}] table.", preset, preset),
+    };
+    stack.push(preset);
+    base = extend(base, presets, stack)?;
+    stack.pop();
+    merge_extending(&mut base, table);
+    Ok(base)
+}
+
+/// Replaces every synth that extends a preset of the [preset] table by the merged synth, and removes the presets.
+fn resolve_presets(root: &mut toml::value::Table) -> Result<(), Error> {
+    let presets = match root.remove("preset") {
+        Some(toml::Value::Table(presets)) => presets,
+        Some(_) => fail!("preset must be a table of synth presets"),
+        None => toml::value::Table::new(),
+    };
+    if let Some(&mut toml::Value::Table(ref mut synths)) = root.get_mut("synth") {
+        for (id, synth) in synths.iter_mut() {
+            if let toml::Value::Table(ref mut synth) = *synth {
+                let table = ::std::mem::replace(synth, toml::value::Table::new());
+                *synth = extend(table, &presets, &mut vec![format!("synth.{}", id)])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a settings file with its includes and resolves the presets its synths extend.
+pub fn read_settings_file(file: &Path) -> Result<Settings, Error> {
+    let (mut root, included_files) = read_with_includes(file, &mut Vec::new())?;
+    resolve_presets(&mut root)?;
+    Ok(Settings {
+        value: toml::Value::Table(root),
+        directory: file.parent().unwrap().to_path_buf(),
+        included_files,
+    })
+}
+
+/// Reads settings from a string named `name` in messages. Included files, the input_file and the output_file are
+/// relative to `directory`.
+pub fn read_settings_str(contents: &str, name: &Path, directory: &Path) -> Result<Settings, Error> {
+    let (mut root, included_files) = merge_includes(parse_toml(contents, name)?, name, directory, &mut Vec::new())?;
+    resolve_presets(&mut root)?;
+    Ok(Settings {
+        value: toml::Value::Table(root),
+        directory: directory.to_path_buf(),
+        included_files,
+    })
+}
+
+/// Sets a dotted key to a value given like the --set option.
+pub fn set_setting(settings: &mut Settings, key: &str, value: &str) -> Result<(), Error> {
+    set_value(&mut settings.value, key, parse_value(value))
+}
+
+/// Deserializes settings after all overrides were applied, so they are checked like values from the file.
+fn to_optional_render_settings(value: toml::Value) -> Result<TOMLOptionalRenderSettings, Error> {
+    value.try_into().map_err(|e| Error::new(format!("Not valid render settings: {}", e)))
+}
+
+/// Tables are sorted by key, so the debug format of a value is the same for equal settings.
+fn source_of(value: &toml::Value) -> String {
+    format!("{:?}", value)
+}
+
+/// Expands the variables of the settings and checks them.
+pub fn render_settings_of(settings: &Settings) -> Result<TOMLRenderSettings, Error> {
+    let mut value = settings.value.clone();
+    expand_variables(&mut value)?;
+    let source = source_of(&value);
+    let mut render_settings = to_render_settings(to_optional_render_settings(value)?, settings.directory.clone());
+    render_settings.source = source;
+    render_settings.included_files = settings.included_files.clone();
+    Ok(render_settings)
+}
+
+/// Reads the settings file `input`, or standard input for '-'. Files in settings from standard input are relative to the
+/// working directory.
+pub fn read_input_file(input: &str, overrides: &SettingOverrides) -> Result<TOMLRenderSettings, Error> {
+    let from_stdin = stdio::is_standard_stream(input);
+    let mut settings = if from_stdin {
+        let contents = String::from_utf8(stdio::read_stdin()?).map_err(|_| Error::new("Render settings on standard input must be UTF-8"))?;
+        read_settings_str(&contents, Path::new("<stdin>"), &env::current_dir().unwrap())?
+    } else {
+        read_settings_file(Path::new(input))?
+    };
+    for setting in &overrides.set {
+        apply_setting(&mut settings.value, setting)?;
+    }
+    let mut render_settings = render_settings_of(&settings)?;
+    if let Some(ref output) = overrides.output {
+        render_settings.output_file = absolute_path(output)?;
+    }
+    if let Some(ref input_midi) = overrides.input_midi {
+        render_settings.input_file = absolute_path(input_midi)?;
+    }
+    ensure!(!(from_stdin && stdio::is_standard_stream(&render_settings.input_file)),
+            "Render settings and MIDI data cannot both be read from standard input");
+    Ok(render_settings)
+}
+
+/// Reads a project file. Every [[job]] gets the settings outside of the jobs, with its input_file and output_file
+/// and the dotted keys of its overrides table applied.
+pub fn read_project_file(project: &str) -> Result<Vec<TOMLRenderSettings>, Error> {
+    let mut shared = read_settings_file(Path::new(project))?;
+    let jobs = match shared.value.as_table_mut().and_then(|t| t.remove("job")) {
+        Some(toml::Value::Array(jobs)) => jobs,
+        _ => fail!("Project file must contain [[job]] tables"),
+    };
+
+    jobs.into_iter().enumerate()
+        .map(|(i, job)| {
+            let mut job = match job {
+                toml::Value::Table(job) => job,
+                _ => fail!("Job {} is not a table", i + 1),
+            };
+            let mut settings = shared.clone();
+            for key in &["input_file", "output_file"] {
+                let file = job.remove(*key).ok_or_else(|| Error::new(format!("Job {} must contain {}", i + 1, key)))?;
+                set_value(&mut settings.value, key, file)?;
+            }
+            match job.remove("overrides") {
+                Some(toml::Value::Table(overrides)) => {
+                    for (key, override_value) in overrides {
+                        set_value(&mut settings.value, &key, override_value)?;
+                    }
+                }
+                Some(_) => fail!("Overrides of job {} must be a table", i + 1),
+                None => {}
+            }
+            if let Some(key) = job.keys().next() {
+                fail!("Not a valid key in job {}: '{}'. Use 'input_file', 'output_file' or 'overrides'.", i + 1, key);
+            }
+            render_settings_of(&settings)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    fn table(text: &str) -> toml::value::Table {
+        parse_toml(text, Path::new("<test>")).unwrap()
+    }
+
+    /// Reads `contents` as a project file written to a temporary file named after `name`.
+    fn project(name: &str, contents: &str) -> Result<Vec<TOMLRenderSettings>, Error> {
+        let file = env::temp_dir().join(format!("musicrenderer_{}_{}.toml", process::id(), name));
+        fs::write(&file, contents).unwrap();
+        let res = read_project_file(file.to_str().unwrap());
+        fs::remove_file(&file).unwrap();
+        res
+    }
+
+    const PROJECT_SETTINGS: &str = "sample_rate = 44100\n[synth.piano]\nsynthtype = 'test'\ngain = 1.0\n[synth.piano.mapping.all]\ncondition = [{ channel = 0 }]\ndestination = [{}]\n";
+
+    #[test]
+    fn merge_included_merges_tables() {
+        let mut target = table("a = 1\n[t]\nx = 1\n");
+        merge_included(&mut target, table("b = 2\n[t]\ny = 2\n"), "", Path::new("included.toml")).unwrap();
+        assert_eq!(target, table("a = 1\nb = 2\n[t]\nx = 1\ny = 2\n"));
+    }
+
+    #[test]
+    fn merge_included_accepts_equal_values() {
+        let mut target = table("a = 1\n");
+        merge_included(&mut target, table("a = 1\n"), "", Path::new("included.toml")).unwrap();
+        assert_eq!(target, table("a = 1\n"));
+    }
+
+    #[test]
+    fn merge_included_rejects_conflicts() {
+        let mut target = table("[t]\nx = 1\n");
+        let error = merge_included(&mut target, table("[t]\nx = 2\n"), "", Path::new("included.toml")).unwrap_err();
+        assert!(error.message().starts_with("Conflicting values for 't.x' in 'included.toml'"), "{}", error);
+    }
+
+    #[test]
+    fn extend_merges_over_the_preset() {
+        let presets = table("[base]\nsynthtype = 'test'\ngain = 1.0\n[base.t]\na = 1\n[louder]\nextends = 'base'\ngain = 2.0\n");
+        let extended = extend(table("extends = 'louder'\n[t]\nb = 2\n"), &presets, &mut Vec::new()).unwrap();
+        assert_eq!(extended, table("synthtype = 'test'\ngain = 2.0\n[t]\na = 1\nb = 2\n"));
+    }
+
+    #[test]
+    fn extend_keeps_tables_without_preset() {
+        assert_eq!(extend(table("gain = 0.5\n"), &table(""), &mut Vec::new()).unwrap(), table("gain = 0.5\n"));
+    }
+
+    #[test]
+    fn extend_rejects_cycles() {
+        let presets = table("[first]\nextends = 'second'\n[second]\nextends = 'first'\n");
+        let error = extend(table("extends = 'first'\n"), &presets, &mut vec!["synth.a".to_string()]).unwrap_err();
+        assert!(error.message().starts_with("Preset cycle: synth.a -> first -> second -> first"), "{}", error);
+    }
+
+    #[test]
+    fn extend_rejects_unknown_presets() {
+        let error = extend(table("extends = 'missing'\n"), &table(""), &mut Vec::new()).unwrap_err();
+        assert!(error.message().starts_with("Not a valid preset: 'missing'"), "{}", error);
     }
 
-    let render_settings: TOMLOptionalRenderSettings = toml::from_str(&contents).unwrap();
-    info!("Optional Render settings: {:?}", render_settings);
-    to_render_settings(render_settings, input_file.parent().unwrap().to_path_buf())
+    #[test]
+    fn expand_string_replaces_variables() {
+        let vars = table("name = 'song'\nrate = 48000\n");
+        assert_eq!(expand_string("out/${name}_${rate}.wav", &vars).unwrap(), "out/song_48000.wav");
+        assert_eq!(expand_string("plain", &vars).unwrap(), "plain");
+    }
+
+    #[test]
+    fn expand_string_falls_back_to_the_environment() {
+        env::set_var("MUSICRENDERER_TEST_DIRECTORY", "/tmp/renders");
+        assert_eq!(expand_string("${MUSICRENDERER_TEST_DIRECTORY}/a.wav", &table("")).unwrap(), "/tmp/renders/a.wav");
+    }
+
+    #[test]
+    fn expand_string_keeps_escaped_references() {
+        assert_eq!(expand_string("$${name} is ${name}", &table("name = 'song'\n")).unwrap(), "${name} is song");
+    }
+
+    #[test]
+    fn expand_variables_resolves_references_between_variables() {
+        let mut root = toml::Value::Table(table("output = '${directory}/${name}.wav'\n[vars]\ndirectory = 'out/${name}'\nname = 'song'\nliteral = '$${name}'\nquoted = '${literal}'\n"));
+        expand_variables(&mut root).unwrap();
+        assert_eq!(root, toml::Value::Table(table("output = 'out/song/song.wav'\n")));
+
+        let mut root = toml::Value::Table(table("text = '${quoted}'\n[vars]\nliteral = '$${name}'\nquoted = '${literal}'\n"));
+        expand_variables(&mut root).unwrap();
+        assert_eq!(root, toml::Value::Table(table("text = '${name}'\n")));
+    }
+
+    #[test]
+    fn expand_variables_rejects_cycles() {
+        let mut root = toml::Value::Table(table("[vars]\na = '${b}'\nb = 'x${c}'\nc = '${a}'\n"));
+        assert_eq!(expand_variables(&mut root).unwrap_err().message(), "Variable cycle: a -> b -> c -> a");
+    }
+
+    #[test]
+    fn expand_string_rejects_undefined_variables() {
+        let error = expand_string("${MUSICRENDERER_TEST_UNDEFINED}", &table("")).unwrap_err();
+        assert!(error.message().starts_with("Not a defined variable: 'MUSICRENDERER_TEST_UNDEFINED'"), "{}", error);
+    }
+
+    #[test]
+    fn expand_string_rejects_unterminated_references() {
+        let error = expand_string("${name", &table("name = 'song'\n")).unwrap_err();
+        assert!(error.message().starts_with("Not a valid variable reference"), "{}", error);
+    }
+
+    #[test]
+    fn set_value_creates_missing_tables() {
+        let mut root = toml::Value::Table(table("[synth.piano]\ngain = 1.0\n"));
+        set_value(&mut root, "synth.piano.gain", toml::Value::Float(0.5)).unwrap();
+        set_value(&mut root, "synth.drums.synthtype", toml::Value::String("test".to_string())).unwrap();
+        assert_eq!(root, toml::Value::Table(table("[synth.piano]\ngain = 0.5\n[synth.drums]\nsynthtype = 'test'\n")));
+    }
+
+    #[test]
+    fn set_value_addresses_array_elements_from_one() {
+        let mut root = toml::Value::Table(table("[[effect]]\nmix = 0.1\n[[effect]]\nmix = 0.2\n"));
+        set_value(&mut root, "effect.2.mix", toml::Value::Float(0.5)).unwrap();
+        assert_eq!(root, toml::Value::Table(table("[[effect]]\nmix = 0.1\n[[effect]]\nmix = 0.5\n")));
+    }
+
+    #[test]
+    fn set_value_rejects_invalid_paths() {
+        let mut root = toml::Value::Table(table("gain = 1.0\n[[effect]]\nmix = 0.1\n"));
+        let value = toml::Value::Integer(1);
+        assert_eq!(set_value(&mut root, "gain.left", value.clone()).unwrap_err().message(), "Setting 'gain' is not a table or array");
+        assert_eq!(set_value(&mut root, "effect.first.mix", value.clone()).unwrap_err().message(), "Not a valid array index in setting 'effect.first.mix': 'first'");
+        assert_eq!(set_value(&mut root, "effect.0.mix", value.clone()).unwrap_err().message(), "Array index in setting 'effect.0.mix' must be between 1 and 1");
+        assert_eq!(set_value(&mut root, "effect.2", value).unwrap_err().message(), "Array index in setting 'effect.2' must be between 1 and 1");
+    }
+
+    #[test]
+    fn apply_setting_parses_toml_values() {
+        let mut root = toml::Value::Table(table(""));
+        apply_setting(&mut root, "sample_rate=44100").unwrap();
+        apply_setting(&mut root, " synth.piano.gain = 0.5 ").unwrap();
+        apply_setting(&mut root, "output_file=out/song.wav").unwrap();
+        apply_setting(&mut root, "title='a = b'").unwrap();
+        apply_setting(&mut root, "flags=[1, 2]").unwrap();
+        let expected = table("sample_rate = 44100\noutput_file = 'out/song.wav'\ntitle = 'a = b'\nflags = [1, 2]\n[synth.piano]\ngain = 0.5\n");
+        assert_eq!(root, toml::Value::Table(expected));
+    }
+
+    #[test]
+    fn apply_setting_requires_a_key() {
+        let mut root = toml::Value::Table(table(""));
+        assert_eq!(apply_setting(&mut root, "gain").unwrap_err().message(), "Not a valid setting: 'gain'. Use key=value.");
+        assert_eq!(apply_setting(&mut root, " =1").unwrap_err().message(), "Not a valid setting: ' =1'. Use key=value.");
+    }
+
+    #[test]
+    fn read_project_file_applies_the_jobs_to_the_shared_settings() {
+        let jobs = project("jobs", &format!("{}{}", PROJECT_SETTINGS,
+                           "[[job]]\ninput_file = 'a.mid'\noutput_file = 'a.wav'\n\
+                            [[job]]\ninput_file = 'b.mid'\noutput_file = 'b.wav'\n[job.overrides]\nsample_rate = 48000\n'synth.piano.gain' = 0.5\n")).unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!((jobs[0].input_file.as_str(), jobs[0].output_file.as_str()), ("a.mid", "a.wav"));
+        assert_eq!((jobs[1].input_file.as_str(), jobs[1].output_file.as_str()), ("b.mid", "b.wav"));
+        assert_eq!((jobs[0].sample_rate, jobs[0].synth["piano"].gain), (44100, 1.0));
+        assert_eq!((jobs[1].sample_rate, jobs[1].synth["piano"].gain), (48000, 0.5));
+        assert_eq!(jobs[0].input_path, env::temp_dir());
+        assert!(!jobs[0].source.contains("job"));
+        assert_ne!(jobs[0].source, jobs[1].source);
+    }
+
+    #[test]
+    fn read_project_file_requires_jobs() {
+        let error = project("nojobs", PROJECT_SETTINGS).unwrap_err();
+        assert_eq!(error.message(), "Project file must contain [[job]] tables");
+        let error = project("jobvalue", &format!("job = 1\n{}", PROJECT_SETTINGS)).unwrap_err();
+        assert_eq!(error.message(), "Project file must contain [[job]] tables");
+    }
+
+    #[test]
+    fn read_project_file_rejects_invalid_jobs() {
+        let error = project("nooutput", &format!("{}[[job]]\ninput_file = 'a.mid'\n", PROJECT_SETTINGS)).unwrap_err();
+        assert_eq!(error.message(), "Job 1 must contain output_file");
+        let error = project("unknownkey", &format!("{}[[job]]\ninput_file = 'a.mid'\noutput_file = 'a.wav'\ngain = 0.5\n", PROJECT_SETTINGS)).unwrap_err();
+        assert!(error.message().starts_with("Not a valid key in job 1: 'gain'"), "{}", error);
+        let error = project("overrides", &format!("{}[[job]]\ninput_file = 'a.mid'\noutput_file = 'a.wav'\noverrides = 1\n", PROJECT_SETTINGS)).unwrap_err();
+        assert_eq!(error.message(), "Overrides of job 1 must be a table");
+    }
 }
@@ -0,0 +1,271 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+use types::*;
+
+const KEYS: usize = 128;
+
+// Cents of the twelve pitch classes above the root of each built-in temperament
+const EQUAL: [f64; 12] = [0.0, 100.0, 200.0, 300.0, 400.0, 500.0, 600.0, 700.0, 800.0, 900.0, 1000.0, 1100.0];
+const WERCKMEISTER_III: [f64; 12] = [0.0, 90.225, 192.18, 294.135, 390.225, 498.045, 588.27, 696.09, 792.18, 888.27, 996.09, 1092.18];
+const JUST: [f64; 12] = [0.0, 111.731, 203.91, 315.641, 386.314, 498.045, 590.224, 701.955, 813.686, 884.359, 1017.596, 1088.269];
+const MEANTONE: [f64; 12] = [0.0, 76.049, 193.157, 310.265, 386.314, 503.422, 579.471, 696.578, 772.627, 889.735, 1006.843, 1082.892];
+const PYTHAGOREAN: [f64; 12] = [0.0, 113.685, 203.91, 294.135, 407.82, 498.045, 611.73, 701.955, 815.64, 905.865, 996.09, 1109.775];
+
+fn read_lines(file: &PathBuf) -> Result<Vec<String>, Error> {
+    let mut contents = String::new();
+    File::open(file)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|_| Error::new(format!("Could not read tuning file '{}'", file.to_str().unwrap())))?;
+
+    // Lines starting with ! are comments in both Scala file formats
+    Ok(contents.lines()
+        .filter(|l| !l.starts_with('!'))
+        .map(|l| l.trim().to_string())
+        .collect())
+}
+
+fn parse_scala_pitch(pitch: &str) -> Result<f64, Error> {
+    let pitch = pitch.split_whitespace().next().ok_or_else(|| Error::new("Empty pitch in Scala file"))?;
+    if pitch.contains('.') {
+        pitch.parse().map_err(|_| Error::new("Invalid cents value in Scala file"))
+    } else {
+        let mut parts = pitch.splitn(2, '/');
+        let numerator: f64 = parts.next().unwrap().parse().map_err(|_| Error::new("Invalid ratio in Scala file"))?;
+        let denominator: f64 = match parts.next() {
+            Some(denominator) => denominator.parse().map_err(|_| Error::new("Invalid ratio in Scala file"))?,
+            None => 1.0,
+        };
+        ensure!(numerator > 0.0 && denominator > 0.0, "Ratios in Scala files must be positive");
+        Ok(1200.0 * (numerator / denominator).log2())
+    }
+}
+
+/// Reads a Scala scale file. Returns the pitches of all degrees after the first in cents,
+/// the last one being the period of the scale.
+pub fn load_scale(file: &PathBuf) -> Result<Vec<f64>, Error> {
+    let lines = read_lines(file)?;
+    // The first line is the description, which may be empty
+    let count: usize = lines.get(1).and_then(|l| l.split_whitespace().next())
+        .and_then(|c| c.parse().ok())
+        .ok_or_else(|| Error::new("Scala file does not contain a note count"))?;
+    let pitches = lines.iter().skip(2).filter(|l| !l.is_empty()).take(count).map(|l| parse_scala_pitch(l)).collect::<Result<Vec<f64>, Error>>()?;
+    ensure!(pitches.len() == count, "Scala file contains less pitches than announced");
+    ensure!(count > 0, "Scala file must contain at least one pitch");
+    Ok(pitches)
+}
+
+/// Reads a Scala keyboard mapping file.
+pub fn load_keyboard_map(file: &PathBuf) -> Result<ScalaKeyboardMap, Error> {
+    let lines: Vec<String> = read_lines(file)?.into_iter().filter(|l| !l.is_empty()).collect();
+    ensure!(lines.len() >= 7, "Keyboard mapping file is incomplete");
+    let field = |i: usize| lines[i].split_whitespace().next().unwrap().to_string();
+    let number = |i: usize| -> Result<i32, Error> { field(i).parse().map_err(|_| Error::new("Invalid number in keyboard mapping file")) };
+
+    let size = number(0)?;
+    ensure!(size >= 0, "Keyboard mapping size must not be negative");
+    let mut mapping = Vec::new();
+    for i in 0..size as usize {
+        let entry = lines.get(7 + i).map_or("x".to_string(), |_| field(7 + i));
+        mapping.push(if entry == "x" { None } else { Some(entry.parse().map_err(|_| Error::new("Invalid keyboard mapping entry"))?) });
+    }
+
+    Ok(ScalaKeyboardMap {
+        first_key: number(1)?,
+        last_key: number(2)?,
+        middle_key: number(3)?,
+        reference_key: number(4)?,
+        reference_frequency: field(5).parse().map_err(|_| Error::new("Invalid reference frequency in keyboard mapping file"))?,
+        octave_degree: number(6)?,
+        mapping,
+    })
+}
+
+/// The linear mapping Scala uses without a keyboard mapping file, with A4 at `reference_pitch`.
+pub fn default_keyboard_map(reference_pitch: f64) -> ScalaKeyboardMap {
+    ScalaKeyboardMap {
+        first_key: 0,
+        last_key: KEYS as i32 - 1,
+        middle_key: 60,
+        reference_key: 69,
+        reference_frequency: reference_pitch,
+        octave_degree: 0,
+        mapping: Vec::new(),
+    }
+}
+
+fn floor_div(a: i32, b: i32) -> (i32, i32) {
+    let div = (a as f64 / b as f64).floor() as i32;
+    (div, a - div * b)
+}
+
+fn degree_of(map: &ScalaKeyboardMap, scale_size: i32, key: i32) -> Option<i32> {
+    if key < map.first_key || key > map.last_key {
+        return None;
+    }
+    if map.mapping.is_empty() {
+        return Some(key - map.middle_key);
+    }
+    let octave_degree = if map.octave_degree == 0 { scale_size } else { map.octave_degree };
+    let (octave, index) = floor_div(key - map.middle_key, map.mapping.len() as i32);
+    map.mapping[index as usize].map(|entry| octave * octave_degree + entry)
+}
+
+fn cents_of(scale: &[f64], degree: i32) -> f64 {
+    let (period, index) = floor_div(degree, scale.len() as i32);
+    let cents = if index == 0 { 0.0 } else { scale[index as usize - 1] };
+    period as f64 * scale[scale.len() - 1] + cents
+}
+
+/// Computes the pitch of all MIDI keys in cents as expected by FluidSynth, where key 69 at 440 Hz is 6900.
+pub fn key_pitches(scale: &[f64], map: &ScalaKeyboardMap) -> Result<Vec<f64>, Error> {
+    let scale_size = scale.len() as i32;
+    let reference_degree = degree_of(map, scale_size, map.reference_key).ok_or_else(|| Error::new("The reference key must be mapped"))?;
+    let reference_cents = 6900.0 + 1200.0 * (map.reference_frequency / 440.0).log2();
+
+    Ok((0..KEYS as i32).map(|key| {
+        match degree_of(map, scale_size, key) {
+            Some(degree) => reference_cents + cents_of(scale, degree) - cents_of(scale, reference_degree),
+            // Unmapped keys keep their equal tempered pitch
+            None => f64::from(key) * 100.0,
+        }
+    }).collect())
+}
+
+/// Computes the key pitches of a built-in temperament starting at pitch class `root` (0 is C).
+pub fn temperament_pitches(name: &str, root: u8, reference_pitch: f64) -> Result<Vec<f64>, Error> {
+    let temperament = match name {
+        "equal" => EQUAL,
+        "well-tempered" | "werckmeister3" => WERCKMEISTER_III,
+        "just" => JUST,
+        "meantone" => MEANTONE,
+        "pythagorean" => PYTHAGOREAN,
+        _ => fail!("Not a valid temperament: '{}'. Use 'equal', 'well-tempered', 'just', 'meantone' or 'pythagorean'.", name),
+    };
+    let mut scale: Vec<f64> = temperament[1..].to_vec();
+    scale.push(1200.0);
+
+    let mut map = default_keyboard_map(reference_pitch);
+    map.middle_key = 60 + i32::from(root);
+    key_pitches(&scale, &map)
+}
+
+/// Resolves a tuning from the TOML, loading Scala files from the resource directories.
+pub fn generate_tuning(settings: &TOMLTuning, resources: &ResourcePaths) -> Result<Vec<f64>, Error> {
+    let reference_pitch = settings.reference_pitch.unwrap_or(440.0);
+    ensure!(reference_pitch > 0.0, "Tuning reference_pitch must be greater than 0.0");
+    let root = settings.root.unwrap_or(0);
+    ensure!(root < 12, "Tuning root must be between 0 and 11");
+
+    match (&settings.scale, &settings.temperament) {
+        (&Some(ref scale), &None) => {
+            let scale_file = resources.find(scale);
+            info!("Loading scale '{}'", scale_file.to_str().unwrap());
+            let scale = load_scale(&scale_file)?;
+
+            let map = match settings.keyboard_map {
+                Some(ref keyboard_map) => {
+                    let keyboard_map_file = resources.find(keyboard_map);
+                    info!("Loading keyboard mapping '{}'", keyboard_map_file.to_str().unwrap());
+                    load_keyboard_map(&keyboard_map_file)?
+                }
+                None => default_keyboard_map(reference_pitch),
+            };
+            key_pitches(&scale, &map)
+        }
+        (&None, &Some(ref temperament)) => {
+            ensure!(settings.keyboard_map.is_none(), "A keyboard_map requires a scale");
+            temperament_pitches(temperament, root, reference_pitch)
+        }
+        _ => fail!("A tuning must contain either scale or temperament"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn scala_file(name: &str, contents: &str) -> PathBuf {
+        let file = env::temp_dir().join(format!("musicrenderer_{}_{}", process::id(), name));
+        fs::write(&file, contents).unwrap();
+        file
+    }
+
+    fn assert_pitches(pitches: &[f64], expected: &[(usize, f64)]) {
+        for &(key, cents) in expected {
+            assert!((pitches[key] - cents).abs() < 1e-6, "key {} is at {} cents instead of {}", key, pitches[key], cents);
+        }
+    }
+
+    #[test]
+    fn load_scale_reads_cents_and_ratios() {
+        let file = scala_file("scale.scl", "! comment\nFifths and octaves\n 3\n!\n350.0 cents\n3/2\n2\n");
+        let scale = load_scale(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(scale.len(), 3);
+        assert_eq!(scale[0], 350.0);
+        assert!((scale[1] - 701.955).abs() < 1e-3);
+        assert_eq!(scale[2], 1200.0);
+    }
+
+    #[test]
+    fn load_scale_rejects_missing_pitches() {
+        let file = scala_file("short.scl", "Short\n3\n100.0\n200.0\n");
+        let error = load_scale(&file).unwrap_err();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(error.message(), "Scala file contains less pitches than announced");
+    }
+
+    #[test]
+    fn key_pitches_of_the_equal_scale_are_the_keys() {
+        let scale: Vec<f64> = (1..13).map(|d| f64::from(d) * 100.0).collect();
+        let pitches = key_pitches(&scale, &default_keyboard_map(440.0)).unwrap();
+        assert_eq!(pitches.len(), 128);
+        assert_pitches(&pitches, &[(0, 0.0), (60, 6000.0), (69, 6900.0), (127, 12_700.0)]);
+    }
+
+    #[test]
+    fn key_pitches_follow_the_reference_frequency() {
+        let scale: Vec<f64> = (1..13).map(|d| f64::from(d) * 100.0).collect();
+        let pitches = key_pitches(&scale, &default_keyboard_map(880.0)).unwrap();
+        assert_pitches(&pitches, &[(69, 8100.0), (57, 6900.0)]);
+    }
+
+    #[test]
+    fn key_pitches_leave_unmapped_keys_equal_tempered() {
+        // Only the white keys of C major, so the black keys are unmapped
+        let scale = vec![200.0, 400.0, 500.0, 700.0, 900.0, 1100.0, 1200.0];
+        let mut map = default_keyboard_map(440.0);
+        map.mapping = vec![Some(0), None, Some(1), None, Some(2), Some(3), None, Some(4), None, Some(5), None, Some(6)];
+        map.octave_degree = 7;
+        let pitches = key_pitches(&scale, &map).unwrap();
+        assert_pitches(&pitches, &[(60, 6000.0), (61, 6100.0), (62, 6200.0), (69, 6900.0), (71, 7100.0), (72, 7200.0)]);
+    }
+
+    #[test]
+    fn key_pitches_require_a_mapped_reference_key() {
+        let mut map = default_keyboard_map(440.0);
+        map.last_key = 60;
+        assert_eq!(key_pitches(&[1200.0], &map).unwrap_err().message(), "The reference key must be mapped");
+    }
+
+    #[test]
+    fn temperament_pitches_start_at_the_root() {
+        let pitches = temperament_pitches("just", 0, 440.0).unwrap();
+        // A is the major sixth above C
+        assert_pitches(&pitches, &[(69, 6900.0), (60, 6900.0 - 884.359), (72, 6900.0 - 884.359 + 1200.0), (67, 6900.0 - 884.359 + 701.955)]);
+
+        let pitches = temperament_pitches("pythagorean", 9, 440.0).unwrap();
+        assert_pitches(&pitches, &[(69, 6900.0), (76, 6900.0 + 701.955), (81, 8100.0)]);
+    }
+
+    #[test]
+    fn temperament_pitches_reject_unknown_temperaments() {
+        let error = temperament_pitches("mystery", 0, 440.0).unwrap_err();
+        assert!(error.message().starts_with("Not a valid temperament: 'mystery'"), "{}", error);
+    }
+}
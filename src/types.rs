@@ -1,22 +1,118 @@
 use std::path::PathBuf;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 use fluidsynth_bindgen::*;
 
+use synthesizer::Synthesizer;
+use sf2::GEN_COUNT;
+use tomlparser::toml;
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "musicrenderer_rust", about = "A simple program to render the music for OpenRCT2-OpenMusic")]
 pub struct Options {
-    #[structopt(short = "l", long = "list-instruments", help = "Lists the names of all GM instruments")]
-    pub list_instruments: bool,
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences), raw(global = "true"), help = "Logs more, repeat for debug and trace output")]
+    pub verbose: u64,
+
+    #[structopt(short = "q", long = "quiet", raw(global = "true"), help = "Only logs errors")]
+    pub quiet: bool,
+
+    #[structopt(long = "json", raw(global = "true"), help = "Writes progress, results and log messages as JSON lines to standard output")]
+    pub json: bool,
+
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    #[structopt(name = "render", about = "Renders the MIDI file of a render settings file")]
+    Render {
+        #[structopt(help = "Input file, '-' reads it from standard input")]
+        input: String,
+
+        #[structopt(help = "Resource directories, separated like PATH")]
+        resources: String,
+
+        #[structopt(short = "d", long = "debug", help = "Activate debug mode (save interstage products)")]
+        debug: bool,
+
+        #[structopt(short = "f", long = "force", help = "Renders even if the inputs have not changed since the last render")]
+        force: bool,
+
+        #[structopt(short = "w", long = "watch", help = "Renders again whenever the input file, the MIDI file or a resource file changes. SoundFonts of synthtype 'fluidsynth' are loaded again for every render, use synthtype 'sf2' to keep them loaded")]
+        watch: bool,
+
+        #[structopt(flatten)]
+        overrides: SettingOverrides,
+    },
+
+    #[structopt(name = "validate", about = "Checks a render settings file and all resources it uses without rendering")]
+    Validate {
+        #[structopt(help = "Input file, '-' reads it from standard input")]
+        input: String,
+
+        #[structopt(help = "Resource directories, separated like PATH")]
+        resources: String,
+
+        #[structopt(flatten)]
+        overrides: SettingOverrides,
+    },
+
+    #[structopt(name = "batch", about = "Renders all jobs of a project file in parallel")]
+    Batch {
+        #[structopt(help = "Project file")]
+        project: String,
 
-    #[structopt(help = "Input file")]
-    pub input: String,
+        #[structopt(help = "Resource directories, separated like PATH")]
+        resources: String,
 
-    #[structopt(help = "Resource directory")]
-    pub resources: String,
+        #[structopt(short = "j", long = "jobs", help = "Number of jobs rendered at the same time, defaults to the number of CPU cores")]
+        jobs: Option<usize>,
 
-    #[structopt(short = "d", long = "debug", help = "Activate debug mode (save interstage products)")]
-    pub debug: bool,
+        #[structopt(short = "f", long = "force", help = "Renders all jobs, even those whose inputs have not changed since the last render")]
+        force: bool,
+    },
+
+    #[structopt(name = "inspect", about = "Shows the tempo map, markers and channel usage of a MIDI file")]
+    Inspect {
+        #[structopt(help = "MIDI file")]
+        midi_file: String,
+    },
+
+    #[structopt(name = "list-instruments", about = "Lists the names of all GM instruments")]
+    ListInstruments,
+
+    #[structopt(name = "list-presets", about = "Lists the presets of a SoundFont")]
+    ListPresets {
+        #[structopt(help = "SoundFont file")]
+        soundfont: String,
+    },
+
+    #[structopt(name = "completions", about = "Writes shell completions to stdout")]
+    Completions {
+        #[structopt(help = "Shell, one of bash, fish, zsh, powershell or elvish")]
+        shell: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub struct SettingOverrides {
+    #[structopt(long = "set", raw(number_of_values = "1"), help = "Overrides a render setting, e.g. --set synth.piano.gain=0.8")]
+    pub set: Vec<String>,
+
+    #[structopt(short = "o", long = "output", help = "Output file, replacing output_file. '-' writes to standard output")]
+    pub output: Option<String>,
+
+    #[structopt(short = "m", long = "input-midi", help = "MIDI file, replacing input_file. '-' reads it from standard input")]
+    pub input_midi: Option<String>,
+}
+
+/// Directories resource files are searched in, in order.
+#[derive(Debug, Clone)]
+pub struct ResourcePaths {
+    pub directories: Vec<PathBuf>,
 }
 
 #[derive(Debug)]
@@ -25,17 +121,84 @@ pub struct MIDITempoChange {
     pub us_per_pulse: f64,
 }
 
-pub struct MIDIHandlerData {
-    pub fluid_synthesizers: Vec<FluidSynthesizer>,
+#[derive(Debug)]
+pub struct MIDITimeSignature {
+    pub pulse: u64,
+    pub numerator: u8,
+    // The note value of a beat, e.g. 8 for 6/8
+    pub denominator: u8,
+}
+
+#[derive(Debug)]
+pub struct TempoMap {
     pub pulses_per_quarter_note: u16,
     pub tempo_changes: Vec<MIDITempoChange>,
+    pub time_signatures: Vec<MIDITimeSignature>,
+}
+
+#[derive(Debug, Clone)]
+pub enum MIDIMessage {
+    NoteOn { channel: u8, key: u8, velocity: u8 },
+    NoteOff { channel: u8, key: u8 },
+    KeyPressure { channel: u8, key: u8, pressure: u8 },
+    ControlChange { channel: u8, control: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelPressure { channel: u8, pressure: u8 },
+    PitchBend { channel: u8, value: u16 },
+    // Without the leading F0 and the trailing F7
+    SysEx { data: Vec<u8> },
+    // Marker meta event, not bound to a channel
+    Marker { text: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SysExKind {
+    GMSystemOn,
+    GMSystemOff,
+    GSReset,
+    XGSystemOn,
+    MasterVolume(u16),
+    DrumPart { channel: u8, drum: bool },
+    // Any other GS or XG part parameter, addressed to a channel of the MIDI file
+    Part { channel: u8 },
+    Other,
+}
+
+#[derive(Debug)]
+pub struct MIDIEvent {
+    pub pulse: u64,
+    pub message: MIDIMessage,
+}
+
+pub struct SynthesizerInstance {
+    pub id: String,
+    pub gain: f32,
+    pub synthesizer: Box<dyn Synthesizer>,
+}
+
+pub struct MIDIHandlerData {
+    pub synthesizers: Vec<SynthesizerInstance>,
+    pub tempo_map: TempoMap,
+    pub events: Vec<MIDIEvent>,
+    pub max_pulse: u64,
+}
+
+/// State of the parse stage, owned by the handler while ghakuf reads the file.
+#[derive(Debug)]
+pub struct MIDIParseData {
+    pub tempo_map: TempoMap,
+    pub events: Vec<MIDIEvent>,
+    pub ignore_program_changes: bool,
+    // Pulse in the current track
     pub current_pulse: u64,
     pub max_pulse: u64,
+    // The first invalid event, handler callbacks cannot return errors
+    pub error: Option<Error>,
 }
 
 pub struct MIDIHandler {
-    // Yes. I know. This is not nice. But due to limitations in ghakuf I have to do this.
-    pub data: *mut MIDIHandlerData,
+    // ghakuf keeps the handler, the data is shared to get it back after reading
+    pub data: Arc<Mutex<MIDIParseData>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -43,6 +206,9 @@ pub struct TOMLOptionalRenderSettings {
     pub input_file: String,
     pub output_file: String,
     pub sample_rate: Option<u64>,
+    pub ignore_program_changes: Option<bool>,
+    pub effect: Option<Vec<TOMLEffect>>,
+    pub resource_path: Option<Vec<String>>,
 
     pub synth: HashMap<String, TOMLSynth>,
 }
@@ -54,14 +220,34 @@ pub struct TOMLRenderSettings {
     pub input_file: String,
     pub output_file: String,
     pub sample_rate: u64,
+    pub ignore_program_changes: bool,
+    // Master effect chain, applied to the mix
+    pub effect: Vec<TOMLEffect>,
+    // Searched before the resource directories of the command line
+    pub resource_path: Vec<PathBuf>,
 
     pub synth: HashMap<String, TOMLSynth>,
+
+    // The TOML the settings were read from after all overrides, in a stable format for change detection
+    pub source: String,
+    // Files merged in by include lists, directly or by other included files
+    pub included_files: Vec<PathBuf>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct TOMLMapping {
     pub condition: Vec<TOMLCondition>,
     pub destination: Vec<TOMLDestination>,
+    pub controller: Option<Vec<TOMLControllerRule>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TOMLControllerRule {
+    pub control: u8,
+    pub action: String,
+    pub min: Option<u8>,
+    pub max: Option<u8>,
+    pub value: Option<u8>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -75,7 +261,37 @@ pub struct TOMLDestination {
     pub bank: Option<u32>,
     pub program: Option<String>,
     pub program_nr: Option<u32>,
-    pub soundfont: u32,
+    pub soundfont: Option<u32>,
+    // File relative to the synth's directory instead of a SoundFont preset: an SFZ instrument or an audio clip
+    pub instrument: Option<String>,
+    pub volume: Option<f64>,
+    pub pan: Option<f64>,
+    pub transpose: Option<i32>,
+    pub detune: Option<f64>,
+    pub velocity_curve: Option<String>,
+    pub velocity_exponent: Option<f64>,
+    pub velocity_table: Option<Vec<f64>>,
+    pub tuning: Option<TOMLTuning>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TOMLTuning {
+    pub scale: Option<String>,
+    pub keyboard_map: Option<String>,
+    pub temperament: Option<String>,
+    pub root: Option<u8>,
+    pub reference_pitch: Option<f64>,
+}
+
+#[derive(Debug)]
+pub struct ScalaKeyboardMap {
+    pub first_key: i32,
+    pub last_key: i32,
+    pub middle_key: i32,
+    pub reference_key: i32,
+    pub reference_frequency: f64,
+    pub octave_degree: i32,
+    pub mapping: Vec<Option<i32>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -85,9 +301,79 @@ pub struct TOMLSynth {
     pub directory: Option<String>,
     pub soundfont: Option<Vec<TOMLSynthSoundfont>>,
     pub setting: Option<Vec<TOMLSynthSetting>>,
+    pub reverb: Option<TOMLSynthReverb>,
+    pub chorus: Option<TOMLSynthChorus>,
+    pub sysex: Option<TOMLSynthSysEx>,
+    pub tuning: Option<TOMLTuning>,
+    pub clip: Option<Vec<TOMLSynthClip>>,
+    pub effect: Option<Vec<TOMLEffect>>,
+    pub automation: Option<Vec<TOMLAutomation>>,
     pub mapping: HashMap<String, TOMLMapping>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct TOMLSynthClip {
+    pub file: String,
+    pub marker: Option<String>,
+    pub bar: Option<u32>,
+    pub beat: Option<f64>,
+    pub gain: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TOMLEffect {
+    pub effecttype: String,
+    // eq
+    pub band: Option<Vec<TOMLEQBand>>,
+    // compressor, threshold and makeup in dB, attack and release in seconds
+    pub threshold: Option<f64>,
+    pub ratio: Option<f64>,
+    pub attack: Option<f64>,
+    pub release: Option<f64>,
+    pub makeup: Option<f64>,
+    // delay, times in seconds
+    pub time: Option<f64>,
+    pub time_left: Option<f64>,
+    pub time_right: Option<f64>,
+    pub feedback: Option<f64>,
+    // reverb, impulse response relative to the resource directory
+    pub impulse: Option<String>,
+    // delay and reverb
+    pub mix: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TOMLEQBand {
+    pub shape: String,
+    pub frequency: f64,
+    pub gain: Option<f64>,
+    pub q: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TOMLAutomation {
+    // 'gain', 'pan' or a parameter of the effect
+    pub parameter: String,
+    // Effect and EQ band, starting at 1
+    pub effect: Option<usize>,
+    pub band: Option<usize>,
+    pub curve: Option<String>,
+    pub point: Option<Vec<TOMLAutomationPoint>>,
+    // Controller on a channel of the MIDI file, its values are scaled from min to max
+    pub controller: Option<u8>,
+    pub channel: Option<u8>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TOMLAutomationPoint {
+    // Either seconds or "bar:beat:tick", bars and beats starting at 1
+    pub time: Option<f64>,
+    pub position: Option<String>,
+    pub value: f64,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct TOMLSynthSoundfont {
     pub file: String,
@@ -102,37 +388,710 @@ pub struct TOMLSynthSetting {
     pub value_s: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct TOMLSynthSysEx {
+    pub reset: Option<String>,
+    pub master_volume: Option<String>,
+    pub drum_part: Option<String>,
+    pub other: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TOMLSynthReverb {
+    pub enabled: Option<bool>,
+    pub room_size: Option<f64>,
+    pub damping: Option<f64>,
+    pub width: Option<f64>,
+    pub level: Option<f64>,
+    pub change: Option<Vec<TOMLSynthReverbChange>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TOMLSynthReverbChange {
+    pub time: f64,
+    pub enabled: Option<bool>,
+    pub room_size: Option<f64>,
+    pub damping: Option<f64>,
+    pub width: Option<f64>,
+    pub level: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TOMLSynthChorus {
+    pub enabled: Option<bool>,
+    pub voices: Option<i32>,
+    pub level: Option<f64>,
+    pub speed: Option<f64>,
+    pub depth: Option<f64>,
+    #[serde(rename = "type")]
+    pub modulation: Option<String>,
+    pub change: Option<Vec<TOMLSynthChorusChange>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TOMLSynthChorusChange {
+    pub time: f64,
+    pub enabled: Option<bool>,
+    pub voices: Option<i32>,
+    pub level: Option<f64>,
+    pub speed: Option<f64>,
+    pub depth: Option<f64>,
+    #[serde(rename = "type")]
+    pub modulation: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FluidSynthesizerReverb {
+    pub enabled: bool,
+    pub room_size: f64,
+    pub damping: f64,
+    pub width: f64,
+    pub level: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FluidSynthesizerChorus {
+    pub enabled: bool,
+    pub voices: i32,
+    pub level: f64,
+    pub speed: f64,
+    pub depth: f64,
+    pub modulation: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SysExHandling {
+    Honour,
+    Ignore,
+    Translate,
+}
+
+#[derive(Debug)]
+pub struct SynthesizerSysEx {
+    pub reset: SysExHandling,
+    pub master_volume: SysExHandling,
+    pub drum_part: SysExHandling,
+    pub other: SysExHandling,
+}
+
+#[derive(Debug, Clone)]
+pub enum FluidSynthesizerParameterChange {
+    Reverb(FluidSynthesizerReverb),
+    Chorus(FluidSynthesizerChorus),
+    SysEx { data: Vec<u8>, reset: bool },
+    SystemReset,
+    MasterVolume(f32),
+    DrumChannels { channels: Vec<u8>, drum: bool },
+}
+
+#[derive(Debug, Clone)]
+pub struct FluidSynthesizerTimedChange {
+    // Time in microseconds, like TempoMap::pulse_to_time
+    pub time: f64,
+    pub change: FluidSynthesizerParameterChange,
+}
+
 #[derive(Debug)]
-pub struct FluidSynthesizerCondition {
+pub struct SynthesizerCondition {
     pub channel: Option<u8>,
     pub program: Option<u8>,
 }
 
 #[derive(Debug)]
-pub struct FluidSynthesizerMapping {
-    pub condition: FluidSynthesizerCondition,
-    pub destinations: Vec<u8>,
+pub enum VelocityCurve {
+    Linear,
+    Exponential(f64),
+    Table(Vec<f64>),
+}
+
+#[derive(Debug)]
+pub struct SynthesizerDestination {
+    pub channel: u8,
+    pub soundfont: u32,
+    pub bank: u32,
+    pub program: u8,
+    pub instrument: Option<String>,
+    pub volume: f64,
+    pub pan: f64,
+    pub transpose: i32,
+    pub detune: f64,
+    pub velocity_curve: VelocityCurve,
+    // Index into ChannelRouter::tunings
+    pub tuning: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum ControllerAction {
+    Drop,
+    Clamp(u8, u8),
+    Rescale(u8, u8),
+    Constant(u8),
+}
+
+#[derive(Debug)]
+pub struct ControllerRule {
+    pub control: u8,
+    pub action: ControllerAction,
+}
+
+#[derive(Debug)]
+pub struct SynthesizerMapping {
+    pub condition: SynthesizerCondition,
+    pub destinations: Vec<SynthesizerDestination>,
+    pub controller_rules: Vec<ControllerRule>,
+}
+
+#[derive(Debug)]
+pub struct ChannelRouter {
+    pub mapping: Vec<SynthesizerMapping>,
+    // Key pitches in cents of all tunings used by destinations
+    pub tunings: Vec<Vec<f64>>,
+    pub used_channels: usize,
+    pub channel_programs: [u8; 16],
+    // Destination channels and keys of sounding notes, so note offs reach the same destinations
+    pub active_notes: HashMap<(u8, u8), Vec<(u8, u8)>>,
+}
+
+#[derive(Debug)]
+pub enum FluidError {
+    // FluidSynth could not create the named object
+    Allocation(&'static str),
+    // Name of a setting that was rejected or not applied as given
+    Setting(String),
+    SoundFont(String),
+    // Name of the function which returned an error
+    Call(&'static str),
+}
+
+pub type FluidResult<T> = Result<T, FluidError>;
+
+/// Owns a fluid_settings_t.
+#[derive(Debug)]
+pub struct FluidSettings {
+    pub(crate) settings: *mut fluid_settings_t,
+}
+
+/// Owns a fluid_synth_t and the settings it was created with.
+#[derive(Debug)]
+pub struct FluidSynth {
+    pub(crate) synth: *mut fluid_synth_t,
+    // Only kept alive, FluidSynth uses the settings until the synth is deleted
+    pub(crate) _settings: FluidSettings,
+}
+
+/// Owns a fluid_sequencer_t sending to a FluidSynth, which must outlive it.
+#[derive(Debug)]
+pub struct FluidSequencer {
+    pub(crate) sequencer: *mut fluid_sequencer_t,
+    // Sequencer client ID of the synth
+    pub(crate) destination: i16,
 }
 
 #[derive(Debug)]
 pub struct FluidSynthesizer {
-    pub settings: *mut fluid_settings_t,
-    pub synthesizer: Option<*mut fluid_synth_t>,
-    pub sequencer: Option<*mut fluid_sequencer_t>,
-    pub synthesizer_seq_id: i16,
-    pub gain: f32,
+    // Declared before the synth it sends events to, so it is dropped first
+    pub sequencer: FluidSequencer,
+    pub synth: FluidSynth,
+    pub sample_rate: u64,
+    pub rendered_samples: u64,
     pub last_event: i32,
-    pub used_channels: u8,
-    pub mapping: Vec<FluidSynthesizerMapping>,
+    pub router: ChannelRouter,
+    pub reverb: Option<FluidSynthesizerReverb>,
+    pub chorus: Option<FluidSynthesizerChorus>,
+    // Changes from the TOML, timed_changes additionally contains the ones caused by MIDI events
+    pub configured_changes: Vec<FluidSynthesizerTimedChange>,
+    pub timed_changes: Vec<FluidSynthesizerTimedChange>,
+    pub next_timed_change: usize,
+    pub sysex: SynthesizerSysEx,
+    pub unhandled_sysex: Vec<Vec<u8>>,
+    pub initial_synth_gain: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SF2Generator {
+    pub operator: u16,
+    pub amount: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SF2Modulator {
+    pub source: u16,
+    pub destination: u16,
+    pub amount: i16,
+    pub amount_source: u16,
+    pub transform: u16,
+}
+
+#[derive(Debug)]
+pub struct SF2Zone {
+    pub generators: Vec<SF2Generator>,
+    pub modulators: Vec<SF2Modulator>,
+}
+
+#[derive(Debug)]
+pub struct SF2Preset {
+    pub name: String,
+    pub program: u16,
+    pub bank: u16,
+    pub global_zone: Option<SF2Zone>,
+    pub zones: Vec<SF2Zone>,
+}
+
+#[derive(Debug)]
+pub struct SF2Instrument {
+    pub name: String,
+    pub global_zone: Option<SF2Zone>,
+    pub zones: Vec<SF2Zone>,
+}
+
+#[derive(Debug)]
+pub struct SF2Sample {
+    pub name: String,
+    pub start: u32,
+    pub end: u32,
+    pub loop_start: u32,
+    pub loop_end: u32,
+    pub sample_rate: u32,
+    pub original_pitch: u8,
+    pub pitch_correction: i8,
+    pub sample_type: u16,
+}
+
+#[derive(Debug)]
+pub struct SoundFont {
+    pub name: String,
+    pub presets: Vec<SF2Preset>,
+    pub instruments: Vec<SF2Instrument>,
+    pub samples: Vec<SF2Sample>,
+    pub data: Vec<i16>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SF2EnvelopeStage {
+    Delay,
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+    Finished,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SF2Envelope {
+    pub stage: SF2EnvelopeStage,
+    // Seconds spent in the current stage
+    pub stage_time: f64,
+    // Between 0.0 and 1.0, linear in dB for the volume envelope after its attack
+    pub value: f64,
+}
+
+#[derive(Debug)]
+pub struct SF2Voice {
+    pub channel: u8,
+    pub key: u8,
+    pub velocity: u8,
+    pub soundfont: usize,
+    pub generators: [i32; GEN_COUNT],
+    pub modulators: Vec<SF2Modulator>,
+    pub sample_rate: f64,
+    // Cents of the played note above the root key of the sample
+    pub pitch: f64,
+    pub end: usize,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    pub loop_mode: i32,
+    pub position: f64,
+    pub age: f64,
+    pub volume_envelope: SF2Envelope,
+    pub modulation_envelope: SF2Envelope,
+    pub filter_state: [f64; 4],
+    pub released: bool,
+    pub sustained: bool,
+}
+
+#[derive(Debug)]
+pub struct SF2Channel {
+    pub preset: Option<(usize, usize)>,
+    pub drum: bool,
+    pub controllers: [u8; 128],
+    pub key_pressure: [u8; 128],
+    pub channel_pressure: u8,
+    pub pitch_bend: u16,
+    // Pitch bend range and tuning in cents, as set by RPNs
+    pub bend_range: f64,
+    pub fine_tuning: f64,
+    pub coarse_tuning: f64,
+}
+
+#[derive(Debug, Clone)]
+pub enum SamplerEvent {
+    Message(MIDIMessage),
+    SystemReset,
+    MasterVolume(f32),
+    DrumChannels { channels: Vec<u8>, drum: bool },
+    // Start of a clip placed at a bar and beat, index into AudioSynthesizer::triggers
+    Clip(usize),
+}
+
+/// Mapping and event queue shared by the sampler based backends.
+#[derive(Debug)]
+pub struct SamplerCore {
+    pub name: &'static str,
+    pub sample_rate: u64,
+    pub rendered_samples: u64,
+    pub router: ChannelRouter,
+    // Events with their time in samples, in chronological order
+    pub events: VecDeque<(u64, SamplerEvent)>,
+    pub sysex: SynthesizerSysEx,
+    pub unhandled_sysex: Vec<Vec<u8>>,
+}
+
+pub struct SF2Synthesizer {
+    pub core: SamplerCore,
+    // Shared with the SoundFont cache, with their offsets
+    pub soundfonts: Vec<(Rc<SoundFont>, i32)>,
+    pub channels: Vec<SF2Channel>,
+    pub voices: Vec<SF2Voice>,
+    pub master_volume: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SFZLoopMode {
+    NoLoop,
+    OneShot,
+    LoopContinuous,
+    LoopSustain,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SFZTrigger {
+    Attack,
+    Release,
+}
+
+#[derive(Debug)]
+pub struct AudioSample {
+    pub channels: usize,
+    pub sample_rate: f64,
+    // Interleaved frames between -1.0 and 1.0
+    pub data: Vec<f32>,
+}
+
+#[derive(Debug)]
+pub struct SFZRegion {
+    // Index into SFZSynthesizer::samples
+    pub sample: usize,
+    pub lokey: u8,
+    pub hikey: u8,
+    pub lovel: u8,
+    pub hivel: u8,
+    pub lorand: f64,
+    pub hirand: f64,
+    pub seq_length: u32,
+    pub seq_position: u32,
+    pub trigger: SFZTrigger,
+    pub pitch_keycenter: u8,
+    // In cents per key
+    pub pitch_keytrack: f64,
+    // In cents, including transpose
+    pub tune: f64,
+    // In dB
+    pub volume: f64,
+    // Between -100.0 and 100.0
+    pub pan: f64,
+    pub amp_veltrack: f64,
+    pub offset: usize,
+    pub end: Option<usize>,
+    pub loop_mode: SFZLoopMode,
+    pub loop_start: Option<usize>,
+    pub loop_end: Option<usize>,
+    // Delay, attack, hold, decay, sustain level and release in seconds, the decay ends at the sustain level
+    pub envelope: [f64; 6],
+    pub group: u32,
+    pub off_by: u32,
+}
+
+#[derive(Debug)]
+pub struct SFZInstrument {
+    pub regions: Vec<SFZRegion>,
+}
+
+#[derive(Debug)]
+pub struct SFZChannel {
+    pub instrument: usize,
+    pub controllers: [u8; 128],
+    pub pitch_bend: u16,
+    // In cents
+    pub bend_range: f64,
+    // Round robin counters and note on velocities for release triggers, per key
+    pub sequence: [u32; 128],
+    pub velocities: [u8; 128],
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SFZEnvelope {
+    pub stage: SF2EnvelopeStage,
+    // Seconds spent in the current stage
+    pub stage_time: f64,
+    // Linear amplitude between 0.0 and 1.0
+    pub value: f64,
+    // Amplitude when the release started, it falls from there to zero
+    pub release_level: f64,
+}
+
+#[derive(Debug)]
+pub struct SFZVoice {
+    pub channel: u8,
+    pub key: u8,
+    pub instrument: usize,
+    pub region: usize,
+    // Cents above the key center of the sample
+    pub pitch: f64,
+    pub amplitude: f64,
+    pub position: f64,
+    pub end: usize,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    pub envelope: SFZEnvelope,
+    pub released: bool,
+    pub sustained: bool,
+}
+
+pub struct SFZSynthesizer {
+    pub core: SamplerCore,
+    pub samples: Vec<AudioSample>,
+    pub instruments: Vec<SFZInstrument>,
+    // Index into instruments for every destination channel
+    pub channel_instruments: Vec<usize>,
+    pub channels: Vec<SFZChannel>,
+    pub voices: Vec<SFZVoice>,
+    pub master_volume: f32,
+    // State of the random generator for lorand and hirand, reset with the synthesizer so renders are reproducible
+    pub random: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestWaveform {
+    Sine,
+    Square,
+}
+
+#[derive(Debug)]
+pub struct TestNote {
+    pub channel: u8,
+    pub key: u8,
+    pub frequency: f64,
+    pub amplitude: f64,
+    pub waveform: TestWaveform,
+    // Frames rendered since the note on, so every note starts at phase 0
+    pub age: u64,
+}
+
+pub struct TestSynthesizer {
+    pub core: SamplerCore,
+    pub controllers: Vec<[u8; 128]>,
+    pub notes: Vec<TestNote>,
+}
+
+#[derive(Debug)]
+pub struct AudioClipTrigger {
+    // Index into AudioSynthesizer::clips
+    pub clip: usize,
+    pub marker: Option<String>,
+    // Bar and beat, both starting at 1
+    pub position: Option<(u32, f64)>,
+    pub gain: f64,
+}
+
+#[derive(Debug)]
+pub struct AudioVoice {
+    pub clip: usize,
+    pub position: f64,
+    pub left: f64,
+    pub right: f64,
+}
+
+pub struct AudioSynthesizer {
+    pub core: SamplerCore,
+    pub clips: Vec<AudioSample>,
+    // Index into clips for every destination channel
+    pub channel_clips: Vec<usize>,
+    pub controllers: Vec<[u8; 128]>,
+    pub triggers: Vec<AudioClipTrigger>,
+    pub voices: Vec<AudioVoice>,
+    // End of the last clip started so far in microseconds
+    pub end_time: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EQShape {
+    Peak,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+#[derive(Debug)]
+pub struct EQBand {
+    pub shape: EQShape,
+    pub frequency: f64,
+    // In dB, unused by the pass filters
+    pub gain: f64,
+    pub q: f64,
+}
+
+pub enum Effect {
+    EQ {
+        bands: Vec<EQBand>,
+    },
+    Compressor {
+        threshold: f64,
+        ratio: f64,
+        attack: f64,
+        release: f64,
+        makeup: f64,
+    },
+    Delay {
+        time_left: f64,
+        time_right: f64,
+        feedback: f64,
+        mix: f64,
+    },
+    Reverb {
+        // Resampled to the render sample rate
+        impulse: AudioSample,
+        mix: f64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AutomationCurve {
+    Step,
+    Linear,
+    Exponential,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AutomationTarget {
+    Gain,
+    Pan,
+    // Indices into the effects of the synth and the bands of an EQ, the band is 0 for other effects
+    Effect {
+        effect: usize,
+        band: usize,
+        parameter: String,
+    },
+}
+
+#[derive(Debug)]
+pub struct AutomationLane {
+    pub target: AutomationTarget,
+    pub curve: AutomationCurve,
+    // Time in samples and value, in chronological order
+    pub points: Vec<(u64, f64)>,
+}
+
+pub struct PreparedRender {
+    pub handler_data: MIDIHandlerData,
+    pub master_effects: Vec<Effect>,
+    // Effects and automation lanes of every synthesizer, in the order of handler_data.synthesizers
+    pub synth_effects: Vec<Vec<Effect>>,
+    pub synth_lanes: Vec<Vec<AutomationLane>>,
+}
+
+pub struct RenderResult {
+    // Length of the output in microseconds
+    pub length: f64,
+    pub peak: f32,
+    // Integrated loudness in LUFS, None for silence
+    pub loudness: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportMode {
+    // Only log messages
+    Log,
+    // Progress bar on standard error
+    ProgressBar,
+    // JSON lines on standard output
+    Json,
+}
+
+/// Reports the progress of one render.
+#[derive(Debug)]
+pub struct ProgressReporter {
+    pub mode: ReportMode,
+    pub output_file: String,
+    pub synths: usize,
+    // Length of the render in microseconds
+    pub length: f64,
+    // Times in seconds
+    pub start: f64,
+    pub last_report: f64,
+}
+
+/// Biquad filter in direct form I, for K-weighting.
+#[derive(Debug, Clone, Copy)]
+pub struct Biquad {
+    pub b: [f64; 3],
+    pub a: [f64; 3],
+    pub x: [f64; 2],
+    pub y: [f64; 2],
+}
+
+/// Render settings as read from TOML, with includes and presets resolved. Variables are expanded when a render is
+/// started, so they see values that were set before.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub(crate) value: toml::Value,
+    // Files in the settings are relative to it
+    pub(crate) directory: PathBuf,
+    pub(crate) included_files: Vec<PathBuf>,
+}
+
+/// Builder for a render, see the crate documentation.
+#[derive(Debug)]
+pub struct Render {
+    pub(crate) settings: TOMLRenderSettings,
+    pub(crate) resource_directories: Vec<PathBuf>,
+    // Rendered instead of the input_file of the settings
+    pub(crate) midi_data: Option<Vec<u8>>,
+}
+
+/// Why a render through the library failed, with the message the command line tool reports.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub(crate) message: String,
+}
+
+/// Stereo output of a render.
+#[derive(Debug)]
+pub struct RenderedAudio {
+    pub(crate) sample_rate: u64,
+    // Interleaved left and right samples
+    pub(crate) samples: Vec<f32>,
+}
+
+/// Deleted when dropped, also if reading it panicked.
+pub struct TemporaryFile {
+    pub path: PathBuf,
 }
 
 pub fn to_render_settings(r: TOMLOptionalRenderSettings, p: PathBuf) -> TOMLRenderSettings {
     TOMLRenderSettings {
+        resource_path: r.resource_path.unwrap_or_else(Vec::new).iter().map(|d| p.join(d)).collect(),
         input_file: r.input_file,
         input_path: p,
         output_file: r.output_file,
         sample_rate: r.sample_rate.unwrap_or(48_000),
+        ignore_program_changes: r.ignore_program_changes.unwrap_or(false),
+        effect: r.effect.unwrap_or_else(Vec::new),
 
         synth: r.synth,
+
+        source: String::new(),
+        included_files: Vec::new(),
     }
 }
@@ -0,0 +1,89 @@
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use types::*;
+use renderer;
+use tomlparser;
+use stdio;
+use progress;
+use resources;
+
+// Interval in which the watched files are checked for modifications
+const WATCH_INTERVAL: u64 = 500;
+
+/// Returns the settings file with its included files, the MIDI file and all resource files of a render.
+fn watched_files(input: &str, render_settings: Option<&TOMLRenderSettings>, resources: &ResourcePaths) -> Vec<PathBuf> {
+    let mut files = vec![PathBuf::from(input)];
+    if let Some(render_settings) = render_settings {
+        files.extend(render_settings.included_files.iter().cloned());
+        files.push(render_settings.input_path.join(&render_settings.input_file));
+        files.extend(resources::resource_files(render_settings, resources));
+    }
+    files
+}
+
+fn modification_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files.iter().map(|f| fs::metadata(f).and_then(|m| m.modified()).ok()).collect()
+}
+
+/// Renders whenever the settings file, the MIDI file or a resource file changes, until the process is stopped.
+/// Failed renders are reported and watching goes on, so the files can be fixed. SoundFonts of the sf2 synthtype stay
+/// loaded while their files are unchanged, the fluidsynth synthtype loads them again for every render. Standard output is
+/// not written, as the renders would follow each other in one stream.
+pub fn watch(input: &str, resources: &ResourcePaths, overrides: &SettingOverrides, debug: bool, report: ReportMode) -> Result<(), Error> {
+    ensure!(!stdio::is_standard_stream(input) && overrides.input_midi.as_ref().map_or(true, |m| !stdio::is_standard_stream(m)),
+            "Watch mode cannot read from standard input");
+    ensure!(overrides.output.as_ref().map_or(true, |o| !stdio::is_standard_stream(o)), "Watch mode cannot write to standard output");
+    let mut files = vec![PathBuf::from(input)];
+    loop {
+        // Taken before reading, so changes while reading or rendering lead to another render. Files which are new in
+        // the settings are taken as they are after reading.
+        let before = modification_times(&files);
+        let render_settings = tomlparser::read_input_file(input, overrides).and_then(|render_settings| {
+            ensure!(!stdio::is_standard_stream(&render_settings.output_file), "Watch mode cannot write to standard output");
+            Ok(render_settings)
+        });
+        let render_settings = match render_settings {
+            Ok(render_settings) => Some(render_settings),
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        };
+        let watched = watched_files(input, render_settings.as_ref(), &render_settings.as_ref().map_or_else(|| resources.clone(), |r| resources.for_settings(r)));
+        let times: Vec<Option<SystemTime>> = watched.iter().zip(modification_times(&watched))
+            .map(|(file, now)| files.iter().position(|f| f == file).map_or(now, |i| before[i]))
+            .collect();
+        files = watched;
+
+        if let Some(ref render_settings) = render_settings {
+            if let Err(e) = renderer::process_if_changed(render_settings, resources, debug, false, report) {
+                error!("{}", e);
+                error!("Rendering '{}' failed, waiting for changes", input);
+            }
+        } else {
+            error!("Reading '{}' failed, waiting for changes", input);
+        }
+
+        info!("Watching {} files for changes", files.len());
+        loop {
+            thread::sleep(Duration::from_millis(WATCH_INTERVAL));
+            let changed: Vec<&PathBuf> = files.iter().zip(modification_times(&files).iter().zip(times.iter()))
+                .filter(|&(_, (now, before))| now != before)
+                .map(|(f, _)| f)
+                .collect();
+            if !changed.is_empty() {
+                for file in changed {
+                    if report == ReportMode::Json {
+                        progress::emit("changed", &[("file", progress::json_string(file.to_str().unwrap()))]);
+                    } else {
+                        info!("'{}' changed, rendering again", file.to_str().unwrap());
+                    }
+                }
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,186 @@
+extern crate musicrenderer_rust;
+
+use std::env;
+
+use musicrenderer_rust::{Render, RenderedAudio, Settings};
+
+const SAMPLE_RATE: usize = 48_000;
+
+// 120 BPM at 480 pulses per quarter note, so a quarter note lasts half a second
+const PULSES_PER_QUARTER_NOTE: u16 = 480;
+const QUARTER_NOTE_SAMPLES: usize = SAMPLE_RATE / 2;
+
+// Peak of the test synthesizer at velocity 127 and the default channel volume of 100
+const FULL_VELOCITY_PEAK: f64 = 0.5 * 100.0 / 127.0;
+
+fn variable_length(mut value: u32) -> Vec<u8> {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.insert(0, (value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes
+}
+
+/// A format 0 Standard MIDI File with events given as delta time in pulses and message bytes.
+fn midi_track(events: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut track = Vec::new();
+    for &(delta, ref message) in events {
+        track.extend(variable_length(delta));
+        track.extend(message);
+    }
+    track.extend(&[0x00, 0xFF, 0x2F, 0x00]);
+
+    let mut data = b"MThd".to_vec();
+    data.extend(&[0, 0, 0, 6, 0, 0, 0, 1]);
+    data.extend(&[(PULSES_PER_QUARTER_NOTE >> 8) as u8, PULSES_PER_QUARTER_NOTE as u8]);
+    data.extend(b"MTrk");
+    data.extend(&[(track.len() >> 24) as u8, (track.len() >> 16) as u8, (track.len() >> 8) as u8, track.len() as u8]);
+    data.extend(track);
+    data
+}
+
+/// A format 0 Standard MIDI File at 120 BPM.
+fn midi_file(events: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut events = events.to_vec();
+    events.insert(0, (0, vec![0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20]));
+    midi_track(&events)
+}
+
+/// One note on channel 0 from the second to the fourth quarter note.
+fn single_note(key: u8, velocity: u8) -> Vec<u8> {
+    midi_file(&[(480, vec![0x90, key, velocity]), (960, vec![0x80, key, 0])])
+}
+
+/// Settings with one synth of synthtype test, `synth` and `destination` are added to its table and its destination.
+fn settings(gain: f64, synth: &str, destination: &str) -> String {
+    format!("input_file = 'unused.mid'
+output_file = 'unused.wav'
+sample_rate = {}
+
+[synth.lead]
+synthtype = 'test'
+gain = {}
+{}
+
+[synth.lead.mapping.all]
+condition = [{{ channel = 0 }}]
+destination = [{{ {} }}]
+", SAMPLE_RATE, gain, synth, destination)
+}
+
+fn render(settings: &str, midi_data: Vec<u8>) -> RenderedAudio {
+    Render::from_toml_str(settings, env::temp_dir())
+        .unwrap()
+        .midi_data(midi_data)
+        .render()
+        .unwrap()
+}
+
+fn channel(audio: &RenderedAudio, channel: usize) -> Vec<f32> {
+    audio.samples().chunks(2).map(|f| f[channel]).collect()
+}
+
+fn assert_close(value: f64, expected: f64, tolerance: f64) {
+    assert!((value - expected).abs() <= tolerance, "{} is not within {} of {}", value, tolerance, expected);
+}
+
+#[test]
+fn notes_start_sample_accurately() {
+    // Odd programs play squares, which are at full level from the first sample
+    let audio = render(&settings(1.0, "", "program_nr = 1"), single_note(69, 127));
+    assert_eq!(audio.sample_rate(), SAMPLE_RATE as u64);
+    let left = channel(&audio, 0);
+    assert_eq!(left.iter().position(|s| *s != 0.0), Some(QUARTER_NOTE_SAMPLES));
+    assert_eq!(left.iter().rposition(|s| *s != 0.0), Some(3 * QUARTER_NOTE_SAMPLES - 1));
+}
+
+#[test]
+fn notes_sound_at_their_frequency() {
+    let audio = render(&settings(1.0, "", "program_nr = 0"), single_note(69, 127));
+    let note = &channel(&audio, 0)[QUARTER_NOTE_SAMPLES..3 * QUARTER_NOTE_SAMPLES];
+    let rising = note.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+    // 440 Hz for one second
+    assert!(rising >= 439 && rising <= 440, "{} rising zero crossings", rising);
+}
+
+#[test]
+fn transpose_changes_the_frequency() {
+    let audio = render(&settings(1.0, "", "program_nr = 0, transpose = 12"), single_note(69, 127));
+    let note = &channel(&audio, 0)[QUARTER_NOTE_SAMPLES..3 * QUARTER_NOTE_SAMPLES];
+    let rising = note.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+    assert!(rising >= 879 && rising <= 880, "{} rising zero crossings", rising);
+}
+
+#[test]
+fn amplitude_follows_velocity() {
+    let loud = render(&settings(1.0, "", "program_nr = 1"), single_note(60, 127));
+    let soft = render(&settings(1.0, "", "program_nr = 1"), single_note(60, 64));
+    assert_close(f64::from(loud.peak()), FULL_VELOCITY_PEAK, 1e-6);
+    assert_close(f64::from(soft.peak()), FULL_VELOCITY_PEAK * 64.0 / 127.0, 1e-6);
+}
+
+#[test]
+fn gain_scales_the_mix() {
+    let audio = render(&settings(0.25, "", "program_nr = 1"), single_note(60, 127));
+    assert_close(f64::from(audio.peak()), FULL_VELOCITY_PEAK * 0.25, 1e-6);
+}
+
+#[test]
+fn settings_can_be_set_before_rendering() {
+    let settings = Settings::from_toml_str(&settings(1.0, "", "program_nr = 1"), env::temp_dir())
+        .and_then(|s| s.set("synth.lead.gain", "0.25"))
+        .unwrap();
+    let audio = Render::from_settings(&settings).unwrap().midi_data(single_note(60, 127)).render().unwrap();
+    assert_close(f64::from(audio.peak()), FULL_VELOCITY_PEAK * 0.25, 1e-6);
+}
+
+#[test]
+fn centered_notes_play_on_both_sides() {
+    let audio = render(&settings(1.0, "", "program_nr = 1"), single_note(60, 127));
+    let (left, right) = audio.channel_peaks();
+    assert_close(f64::from(left), FULL_VELOCITY_PEAK, 1e-6);
+    assert_close(f64::from(right), FULL_VELOCITY_PEAK, 1e-6);
+}
+
+#[test]
+fn destination_pan_moves_notes_to_one_side() {
+    let audio = render(&settings(1.0, "", "program_nr = 1, pan = -1.0"), single_note(60, 127));
+    let (left, right) = audio.channel_peaks();
+    assert_close(f64::from(left), FULL_VELOCITY_PEAK, 1e-6);
+    assert_eq!(right, 0.0);
+}
+
+#[test]
+fn automated_pan_moves_the_synth_in_the_mix() {
+    let automation = "[[synth.lead.automation]]\nparameter = 'pan'\npoint = [{ time = 0.0, value = 1.0 }]";
+    let audio = render(&settings(1.0, automation, "program_nr = 1"), single_note(60, 127));
+    let (left, right) = audio.channel_peaks();
+    assert_eq!(left, 0.0);
+    assert_close(f64::from(right), FULL_VELOCITY_PEAK, 1e-6);
+}
+
+#[test]
+fn invalid_settings_are_errors() {
+    let error = Render::from_toml_str("input_file = 'unused.mid'", env::temp_dir()).unwrap_err();
+    assert!(error.message().starts_with("Not valid render settings"), "{}", error);
+
+    let render = Render::from_toml_str(&settings(1.0, "", "program_nr = 1, pan = 2.0"), env::temp_dir()).unwrap();
+    let error = render.midi_data(single_note(60, 127)).render().unwrap_err();
+    assert_eq!(error.message(), "Destination pan must be between -1.0 and 1.0");
+}
+
+#[test]
+fn invalid_midi_data_is_an_error() {
+    let render = Render::from_toml_str(&settings(1.0, "", "program_nr = 1"), env::temp_dir()).unwrap();
+    let error = render.midi_data(b"not a MIDI file".to_vec()).render().unwrap_err();
+    assert!(error.message().starts_with("Not a valid MIDI file"), "{}", error);
+}
+
+#[test]
+fn midi_data_without_tempo_is_an_error() {
+    let render = Render::from_toml_str(&settings(1.0, "", "program_nr = 1"), env::temp_dir()).unwrap();
+    let error = render.midi_data(midi_track(&[(0, vec![0x90, 60, 127]), (480, vec![0x80, 60, 0])])).render().unwrap_err();
+    assert!(error.message().ends_with("contains no tempo"), "{}", error);
+}